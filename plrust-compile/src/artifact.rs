@@ -0,0 +1,217 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! The json PL/Rust stores in `pg_catalog.pg_proc.prosrc`.
+//!
+//! This must serialize exactly like `ProSrcEntry` in `plrust/src/prosrc.rs`, or the server won't
+//! accept what we produce.  How the artifacts themselves are encoded, hashed, and signed is shared
+//! with the server, see `plrust/src/user_crate/artifact_format.rs`.
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::artifact_format::{sha256_hex, BuildInputs, Encoding, SignedMessage};
+use crate::Lints;
+
+#[derive(Serialize)]
+pub(crate) struct ProSrcEntry {
+    src: String,
+    trusted_pgx_version: String,
+    /// the generated function's Rust signature, which the artifacts' signatures vouch for and a
+    /// trusted restore checks
+    function: String,
+    lib: BTreeMap<String, SharedLibrary>,
+    /// the `Cargo.lock` the function's dependencies were resolved to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) lockfile: Option<String>,
+    #[serde(skip)]
+    encoding: Encoding,
+    #[serde(skip)]
+    signing_key: Option<Vec<u8>>,
+}
+
+#[derive(Serialize)]
+struct SharedLibrary {
+    encoding: Encoding,
+    symbol: Option<String>,
+    encoded: String,
+    library_bytes: usize,
+    lints: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    hashes: ArtifactHashes,
+}
+
+#[derive(Serialize)]
+struct ArtifactHashes {
+    so_sha256: String,
+    src_sha256: String,
+    build_inputs_sha256: String,
+    build_inputs: BuildInputs,
+}
+
+impl ProSrcEntry {
+    /// Libraries will be compressed with `encoding`, and signed with `signing_key` if there is one
+    pub(crate) fn new(
+        src: &str,
+        trusted_pgx_version: &str,
+        function: String,
+        encoding: Encoding,
+        signing_key: Option<Vec<u8>>,
+    ) -> Self {
+        ProSrcEntry {
+            src: src.to_string(),
+            trusted_pgx_version: trusted_pgx_version.to_string(),
+            lib: Default::default(),
+            function,
+            lockfile: None,
+            encoding,
+            signing_key,
+        }
+    }
+
+    pub(crate) fn add_library(
+        &mut self,
+        target: &str,
+        symbol: &str,
+        lints: &Lints,
+        so_bytes: Vec<u8>,
+        build_inputs: BuildInputs,
+    ) -> eyre::Result<()> {
+        let hashes = ArtifactHashes {
+            so_sha256: sha256_hex(&so_bytes),
+            src_sha256: sha256_hex(self.src.as_bytes()),
+            build_inputs_sha256: build_inputs.sha256(),
+            build_inputs,
+        };
+        let signature = self.signing_key.as_deref().map(|key| {
+            SignedMessage {
+                target: target.to_string(),
+                symbol: symbol.to_string(),
+                lints: lints.to_string(),
+                function: self.function.clone(),
+                src_sha256: hashes.src_sha256.clone(),
+                trusted_pgx_version: self.trusted_pgx_version.clone(),
+                build_inputs_sha256: hashes.build_inputs_sha256.clone(),
+                so_sha256: hashes.so_sha256.clone(),
+            }
+            .sign(key)
+        });
+
+        self.lib.insert(
+            target.to_string(),
+            SharedLibrary {
+                encoding: self.encoding,
+                symbol: Some(symbol.to_string()),
+                encoded: self.encoding.encode(&so_bytes)?,
+                library_bytes: so_bytes.len(),
+                lints: lints.0.iter().cloned().collect(),
+                signature,
+                hashes,
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use base64::Engine;
+    use hmac::Mac;
+
+    use super::*;
+    use crate::artifact_format::BASE64_ENGINE;
+
+    const KEY: &[u8] = b"6b2f0d1e4c9a87355e21f0b7c3d94a6e18f5b20c7d3e9a41b6c8f02d5e7a9134";
+    const TARGET: &str = "x86_64-postgres-linux-gnu";
+    const SO_BYTES: &[u8] = b"not really a shared library";
+
+    fn entry(encoding: Encoding) -> eyre::Result<serde_json::Value> {
+        let mut entry = ProSrcEntry::new(
+            "Ok(Some(a + b))",
+            "1.0.0",
+            "fn (a : i32 , b : i32) -> Result < Option < i32 > , Box < dyn std :: error :: Error > >"
+                .to_string(),
+            encoding,
+            Some(KEY.to_vec()),
+        );
+        entry.add_library(
+            TARGET,
+            "plrust_fn_precompiled_test",
+            &Lints(["unsafe_code".to_string()].into_iter().collect()),
+            SO_BYTES.to_vec(),
+            BuildInputs {
+                toolchain: "plrustc 1.0.0".to_string(),
+                trusted_pgx_version: "1.0.0".to_string(),
+                lockfile_sha256: None,
+                dependency_lints: None,
+                allow_list_sha256: Some(sha256_hex(b"allow-list")),
+            },
+        )?;
+        Ok(serde_json::to_value(&entry)?)
+    }
+
+    #[test]
+    fn libraries_decode_to_what_was_compiled() -> eyre::Result<()> {
+        let gzip = entry(Encoding::GzBase64)?;
+        let library = &gzip["lib"][TARGET];
+        assert_eq!("GzBase64", library["encoding"]);
+        assert_eq!(SO_BYTES.len(), library["library_bytes"]);
+        let encoded = BASE64_ENGINE.decode(library["encoded"].as_str().unwrap())?;
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&encoded[..]).read_to_end(&mut decoded)?;
+        assert_eq!(SO_BYTES, decoded);
+
+        let zstd = entry(Encoding::ZstdBase64)?;
+        let library = &zstd["lib"][TARGET];
+        assert_eq!("ZstdBase64", library["encoding"]);
+        let encoded = BASE64_ENGINE.decode(library["encoded"].as_str().unwrap())?;
+        assert_eq!(SO_BYTES, zstd::stream::decode_all(&encoded[..])?);
+        Ok(())
+    }
+
+    #[test]
+    fn libraries_are_signed_like_the_server_verifies() -> eyre::Result<()> {
+        let entry = entry(Encoding::ZstdBase64)?;
+        let library = &entry["lib"][TARGET];
+        let hashes = &library["hashes"];
+        assert_eq!(sha256_hex(SO_BYTES), hashes["so_sha256"]);
+        assert_eq!(sha256_hex(b"Ok(Some(a + b))"), hashes["src_sha256"]);
+        let build_inputs = serde_json::from_value::<BuildInputs>(hashes["build_inputs"].clone())?;
+        assert_eq!(build_inputs.sha256(), hashes["build_inputs_sha256"]);
+        assert_eq!(
+            Some(sha256_hex(b"allow-list")),
+            build_inputs.allow_list_sha256
+        );
+
+        let message = |function: &str| SignedMessage {
+            target: TARGET.to_string(),
+            symbol: "plrust_fn_precompiled_test".to_string(),
+            lints: "unsafe_code".to_string(),
+            function: function.to_string(),
+            src_sha256: sha256_hex(b"Ok(Some(a + b))"),
+            trusted_pgx_version: "1.0.0".to_string(),
+            build_inputs_sha256: build_inputs.sha256(),
+            so_sha256: sha256_hex(SO_BYTES),
+        };
+        let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(library["signature"].as_str().unwrap())?;
+        let function = entry["function"].as_str().unwrap();
+        assert!(message(function).mac(KEY).verify_slice(&signature).is_ok());
+        // the signature is only good for the function it was compiled for
+        assert!(
+            message("fn () -> Result < Option < i32 > , Box < dyn std :: error :: Error > >")
+                .mac(KEY)
+                .verify_slice(&signature)
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Running `cargo` and `plrustc` with the same environment PL/Rust gives them inside Postgres (see
+//! `plrust/src/user_crate/cargo.rs` and `plrust/src/user_crate/build.rs`)
+use std::path::Path;
+use std::process::Command;
+
+use eyre::WrapErr;
+
+use crate::dependencies::DependencyLints;
+
+/// The `KEY = value` pairs `pg_config` reports
+pub(crate) fn pg_config_values(pg_config: &Path) -> eyre::Result<Vec<(String, String)>> {
+    let output = Command::new(pg_config)
+        .output()
+        .wrap_err_with(|| format!("unable to execute `{}`", pg_config.display()))?;
+    if !output.status.success() {
+        eyre::bail!(
+            "`{}` failed: {}",
+            pg_config.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .filter_map(|line| line.split_once(" = "))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect())
+}
+
+/// The output of `plrustc -vV`
+pub(crate) fn plrustc_version() -> eyre::Result<String> {
+    let output = Command::new("plrustc")
+        .arg("-vV")
+        .output()
+        .wrap_err("unable to execute `plrustc -vV`")?;
+    if !output.status.success() {
+        eyre::bail!(
+            "`plrustc -vV` failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The `host:` triple from `plrustc -vV`
+pub(crate) fn plrustc_host(toolchain: &str) -> eyre::Result<String> {
+    toolchain
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .map(|host| host.trim().to_string())
+        .ok_or_else(|| eyre::eyre!("`plrustc -vV` didn't report its host"))
+}
+
+/// `cargo metadata` for the crate in `crate_dir`, which resolves (and downloads) its dependencies
+pub(crate) fn metadata(crate_dir: &Path) -> eyre::Result<String> {
+    let output = Command::new("cargo")
+        .current_dir(crate_dir)
+        .args(["metadata", "--format-version", "1"])
+        .output()
+        .wrap_err("unable to execute `cargo metadata`")?;
+    if !output.status.success() {
+        eyre::bail!(
+            "`cargo metadata` failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+pub(crate) struct Build<'a> {
+    pub(crate) crate_dir: &'a Path,
+    pub(crate) work_dir: &'a Path,
+    pub(crate) crate_name: &'a str,
+    pub(crate) pg_config: &'a [(String, String)],
+    pub(crate) dependency_lints: Option<&'a DependencyLints>,
+}
+
+impl Build<'_> {
+    /// Build the crate for `target` and return the shared library
+    pub(crate) fn build(&self, target: &str) -> eyre::Result<Vec<u8>> {
+        let target_dir = self.work_dir.join("target");
+        std::fs::create_dir_all(&target_dir)?;
+        let crate_dir = self.crate_dir.canonicalize()?;
+        let target_dir = target_dir.canonicalize()?;
+        let work_dir = self.work_dir.canonicalize()?;
+
+        let mut command = Command::new("cargo");
+        command.current_dir(&crate_dir);
+        command.env("RUSTC", "plrustc");
+        command.env("CARGO_TARGET_DIR", &target_dir);
+
+        command.env("PGX_PG_CONFIG_AS_ENV", "true");
+        for (k, v) in self.pg_config {
+            command.env(format!("PGX_PG_CONFIG_{k}"), v);
+        }
+
+        for var in [
+            "DOCS_RS",
+            "PGX_BUILD_VERBOSE",
+            "PGX_PG_SYS_GENERATE_BINDINGS_FOR_RELEASE",
+            "CARGO_MANIFEST_DIR",
+            "OUT_DIR",
+            "RUSTC_WRAPPER",
+            "RUSTC_WORKSPACE_WRAPPER",
+            "RUSTFLAGS",
+        ] {
+            command.env_remove(var);
+        }
+
+        command.env("PLRUSTC_USER_CRATE_NAME", self.crate_name);
+        command.env(
+            "PLRUSTC_USER_CRATE_ALLOWED_SOURCE_PATHS",
+            std::env::join_paths([&crate_dir, &target_dir])?,
+        );
+
+        let mut rustflags = Vec::new();
+        if cfg!(target_os = "macos") {
+            rustflags.push("-Clink-args=-Wl,-undefined,dynamic_lookup".to_string());
+        }
+        rustflags.push(format!(
+            "--remap-path-prefix={}=/plrust",
+            work_dir.display()
+        ));
+        if let Ok(cargo_home) = home::cargo_home() {
+            rustflags.push(format!(
+                "--remap-path-prefix={}=/cargo",
+                cargo_home.canonicalize().unwrap_or(cargo_home).display()
+            ));
+        }
+        if let Some(flag) = self.dependency_lints.and_then(DependencyLints::rustflag) {
+            rustflags.push(flag);
+        }
+        command.env("CARGO_ENCODED_RUSTFLAGS", rustflags.join("\x1f"));
+
+        command.args(["rustc", "--release", "--target", target]);
+        let status = command.status().wrap_err("`cargo` execution failure")?;
+        if !status.success() {
+            eyre::bail!("`cargo rustc` failed for target `{target}`");
+        }
+
+        use std::env::consts::DLL_SUFFIX;
+        let so_path = target_dir
+            .join(target)
+            .join("release")
+            .join(format!("lib{}{DLL_SUFFIX}", self.crate_name));
+        std::fs::read(&so_path).wrap_err_with(|| format!("Reading `{}`", so_path.display()))
+    }
+}
@@ -0,0 +1,373 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Compile a PL/Rust function outside of Postgres.
+//!
+//! `plrust-compile` generates the same crate PL/Rust would (see `plrust/src/user_crate/codegen.rs`,
+//! which we include as-is), builds it with `plrustc` under the lints it's given, and prints the json
+//! PL/Rust stores in `pg_catalog.pg_proc.prosrc`.  Creating a function with that json as its body,
+//! with `plrust.restore_policy = trust`, has the server verify and keep the artifacts rather than
+//! compile the function itself.
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use eyre::WrapErr;
+use quote::{ToTokens, TokenStreamExt};
+
+#[path = "../../plrust/src/user_crate/advisory_db.rs"]
+mod advisory_db;
+mod artifact;
+#[path = "../../plrust/src/user_crate/artifact_format.rs"]
+mod artifact_format;
+mod cargo;
+#[path = "../../plrust/src/user_crate/codegen.rs"]
+mod codegen;
+#[path = "../../plrust/src/user_crate/dependencies.rs"]
+mod dependencies;
+// only `Lockfile::locked_dependencies` is needed here, the rest is how the server pins and verifies
+// what a function's dependencies are locked to
+#[allow(dead_code)]
+#[path = "../../plrust/src/user_crate/lockfile.rs"]
+mod lockfile;
+mod types;
+
+use crate::advisory_db::{AdvisoryDb, AdvisoryPolicy, Finding};
+use crate::artifact::ProSrcEntry;
+use crate::artifact_format::{BuildInputs, Encoding};
+use crate::codegen::UserFn;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Compile a PL/Rust function outside of PostgreSQL and print its `prosrc` json"
+)]
+struct Args {
+    /// File containing the function's body, as it would appear between the `$$`s of a
+    /// `CREATE FUNCTION` statement.  `-` reads it from stdin
+    source: PathBuf,
+
+    /// A function argument, as `name:type`, such as `a:int4` or `names:text[]`.  Repeat for each
+    /// argument, in order
+    #[arg(long = "arg", value_name = "NAME:TYPE")]
+    arguments: Vec<String>,
+
+    /// The function's return type, such as `int4`, `text[]`, or `trigger`
+    #[arg(long)]
+    returns: String,
+
+    /// The function `RETURNS SETOF` its return type
+    #[arg(long)]
+    setof: bool,
+
+    /// The function is `STRICT`
+    #[arg(long)]
+    strict: bool,
+
+    /// The server's `plrust.compile_lints`
+    #[arg(long)]
+    lints: String,
+
+    /// The server's `plrust.trusted_pgx_version`
+    #[arg(long)]
+    trusted_pgx_version: String,
+
+    /// The server's `plrust.allowed_dependencies` file, to check the function's `[dependencies]`
+    /// against
+    #[arg(long)]
+    allowed_dependencies: Option<PathBuf>,
+
+    /// A role the function's owner has the privileges of, whose section of the allow-list also
+    /// applies.  Repeat for each such role
+    #[arg(long = "role", value_name = "ROLE")]
+    roles: Vec<String>,
+
+    /// The schema the function will be created in, whose section of the allow-list also applies
+    #[arg(long)]
+    schema: Option<String>,
+
+    /// The server has `plrust.dependency_lints` on, so the function's dependencies are compiled with
+    /// the `plrust_lints` group
+    #[arg(long)]
+    lint_dependencies: bool,
+
+    /// The server's `plrust.advisory_db`, a local copy of the RustSec advisory database to check the
+    /// crates the function's dependencies resolve to against
+    #[arg(long)]
+    advisory_db: Option<PathBuf>,
+
+    /// The server's `plrust.advisory_policy`, which is whether a dependency with an advisory is
+    /// refused or only warned about
+    #[arg(long, value_parser = ["deny", "warn"], default_value = "deny")]
+    advisory_policy: String,
+
+    /// The `pg_config` of the server the function is for
+    #[arg(long, default_value = "pg_config")]
+    pg_config: PathBuf,
+
+    /// A target triple to compile for, such as `x86_64-postgres-linux-gnu`.  Repeat for each of the
+    /// server's `plrust.compilation_targets`.  Defaults to `plrustc`'s host
+    #[arg(long = "target", value_name = "TRIPLE")]
+    targets: Vec<String>,
+
+    /// Where to build.  Reusing it between runs means dependencies are only compiled once
+    #[arg(long)]
+    work_dir: Option<PathBuf>,
+
+    /// The same key file as the server's `plrust.artifact_signing_key`
+    #[arg(long)]
+    signing_key: Option<PathBuf>,
+
+    /// The server's `plrust.artifact_encoding`, which is how the compiled artifacts are compressed
+    #[arg(long, value_parser = ["zstd", "gzip"], default_value = "zstd")]
+    encoding: String,
+}
+
+/// Lints, rendered the same way as PL/Rust's `LintSet`
+pub(crate) struct Lints(pub(crate) BTreeSet<String>);
+
+impl ToTokens for Lints {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        for lint in &self.0 {
+            let lint = proc_macro2::Ident::new(lint, proc_macro2::Span::call_site());
+            tokens.append_all(quote::quote!(#![forbid(#lint)]))
+        }
+    }
+}
+
+impl std::fmt::Display for Lints {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.0.iter().cloned().collect::<Vec<_>>().join(", ")
+        )
+    }
+}
+
+/// Removes the generated crate when dropped, so nothing is left under `--work-dir` however the
+/// build ends
+struct CrateDir<'a>(&'a Path);
+
+impl Drop for CrateDir<'_> {
+    fn drop(&mut self) {
+        if self.0.exists() {
+            if let Err(e) = std::fs::remove_dir_all(self.0) {
+                eprintln!("warning: problem removing `{}`: {e}", self.0.display());
+            }
+        }
+    }
+}
+
+/// Check the crates the function's dependencies were locked to in the crate in `crate_dir` against
+/// the advisory database at `path`, as PL/Rust does with `plrust.advisory_db`
+fn check_advisories(path: &Path, policy: AdvisoryPolicy, crate_dir: &Path) -> eyre::Result<()> {
+    let db = AdvisoryDb::new(&path.to_string_lossy(), |warning| {
+        eprintln!("warning: {warning}")
+    });
+    let lockfile = lockfile::Lockfile::parse(
+        &std::fs::read_to_string(crate_dir.join("Cargo.lock"))
+            .wrap_err("Reading generated `Cargo.lock`")?,
+    )?;
+    let locked = lockfile
+        .locked_dependencies()
+        .into_iter()
+        .filter_map(|(name, version)| Some((name, semver::Version::parse(version).ok()?)))
+        .collect::<Vec<_>>();
+
+    let denied = db.denied(
+        policy,
+        locked.iter().map(|(name, version)| (*name, version)),
+    )?;
+    if !denied.is_empty() {
+        eyre::bail!(
+            "The following dependencies have advisories in `{}`:\n    {}",
+            path.display(),
+            denied
+                .iter()
+                .map(Finding::to_string)
+                .collect::<Vec<_>>()
+                .join("\n    ")
+        );
+    }
+    Ok(())
+}
+
+fn main() -> eyre::Result<()> {
+    let args = Args::parse();
+
+    let src = if args.source.as_os_str() == "-" {
+        let mut src = String::new();
+        std::io::stdin().read_to_string(&mut src)?;
+        src
+    } else {
+        std::fs::read_to_string(&args.source)
+            .wrap_err_with(|| format!("Unable to read `{}`", args.source.display()))?
+    };
+
+    let lints = Lints(
+        args.lints
+            .split(',')
+            .map(|lint| lint.trim().to_string())
+            .filter(|lint| !lint.is_empty())
+            .collect(),
+    );
+
+    let (code_block, deps_block) = codegen::split_source_and_deps(&src);
+    let user_code: syn::Block =
+        syn::parse_str(&code_block).wrap_err("Parsing the function's code")?;
+    let user_dependencies: toml::Table =
+        toml::from_str(&deps_block).wrap_err("Parsing the function's `[dependencies]`")?;
+
+    let mut arguments = Vec::with_capacity(args.arguments.len());
+    let return_type;
+    let user_fn = match args.returns.trim() {
+        "trigger" => UserFn::Trigger,
+        returns => {
+            for argument in &args.arguments {
+                let Some((name, sql_type)) = argument.split_once(':') else {
+                    eyre::bail!("`--arg {argument}` must be given as `name:type`");
+                };
+                let name = syn::parse_str::<syn::Ident>(name.trim()).map_err(|_| {
+                    eyre::eyre!("`{name}` is an invalid Rust identifier and cannot be used as an argument name")
+                })?;
+                let bare = types::rust_type(sql_type, false)?;
+                arguments.push(codegen::argument(&name, &bare, args.strict)?);
+            }
+            return_type = codegen::return_type(&types::rust_type(returns, true)?, args.setof)?;
+            UserFn::Function {
+                arguments: &arguments,
+                return_type: &return_type,
+            }
+        }
+    };
+    let function_signature = codegen::signature(&user_fn);
+    // the same name PL/Rust gives the function, so we build the very same crate
+    let symbol_name = codegen::symbol_name(&function_signature, &src);
+    let lib_rs = codegen::lib_rs(&symbol_name, user_fn, &user_code, &lints)?;
+
+    let pg_config = cargo::pg_config_values(&args.pg_config)?;
+    let major_version = pg_config
+        .iter()
+        .find(|(k, _)| k == "VERSION")
+        .and_then(|(_, v)| v.split_whitespace().nth(1))
+        .and_then(|v| v.split('.').next())
+        .ok_or_else(|| eyre::eyre!("Unable to determine the Postgres version from `pg_config`"))?
+        .to_string();
+    let mut cargo_toml = codegen::cargo_toml_template(
+        &symbol_name,
+        &format!("pgx/pg{major_version}"),
+        &args.trusted_pgx_version,
+    );
+    let parsed_dependencies = dependencies::parse_user_dependencies(&user_dependencies)?;
+    let mut allow_list = None;
+    let user_dependencies = match &args.allowed_dependencies {
+        Some(allowed_dependencies) => {
+            let allowed: toml::Table = toml::from_str(
+                &std::fs::read_to_string(allowed_dependencies).wrap_err_with(|| {
+                    format!("Unable to read `{}`", allowed_dependencies.display())
+                })?,
+            )?;
+            dependencies::parse_allow_list(&allowed).wrap_err_with(|| {
+                format!("Invalid allow-list `{}`", allowed_dependencies.display())
+            })?;
+            let allowed = dependencies::effective_allow_list(
+                &allowed,
+                |role| args.roles.iter().any(|r| r == role),
+                args.schema.as_deref(),
+            )?;
+            let applied = dependencies::apply_allow_list(&parsed_dependencies, &allowed)?
+                .into_iter()
+                .map(|dependency| (dependency.name.clone(), dependency.to_toml()))
+                .collect::<toml::Table>();
+            allow_list = Some(allowed);
+            applied
+        }
+        None => user_dependencies,
+    };
+    if let Some(toml::Value::Table(dependencies)) = cargo_toml.get_mut("dependencies") {
+        dependencies.extend(user_dependencies);
+    }
+
+    let work_dir = args
+        .work_dir
+        .unwrap_or_else(|| std::env::temp_dir().join("plrust-compile"));
+    let crate_dir = work_dir.join(&symbol_name);
+    let _crate_dir = CrateDir(&crate_dir);
+    std::fs::create_dir_all(crate_dir.join("src"))?;
+    std::fs::write(
+        crate_dir.join("src").join("lib.rs"),
+        prettyplease::unparse(&lib_rs),
+    )?;
+    std::fs::write(crate_dir.join("Cargo.toml"), toml::to_string(&cargo_toml)?)?;
+    let mut dependency_lints = None;
+    if allow_list.is_some() || args.lint_dependencies || args.advisory_db.is_some() {
+        // also writes the `Cargo.lock` the advisories are checked against
+        let metadata = cargo::metadata(&crate_dir)?;
+        if let Some(advisory_db) = &args.advisory_db {
+            check_advisories(advisory_db, args.advisory_policy.parse()?, &crate_dir)?;
+        }
+        if let Some(allow_list) = &allow_list {
+            dependencies::check_resolved_dependencies(&metadata, allow_list)?;
+        }
+        if args.lint_dependencies {
+            dependency_lints = Some(dependencies::DependencyLints::new(
+                &metadata,
+                allow_list.as_ref(),
+            )?);
+        }
+    }
+
+    let toolchain = cargo::plrustc_version()?;
+    let targets = match args.targets.is_empty() {
+        true => vec![cargo::plrustc_host(&toolchain)?],
+        false => args.targets,
+    };
+
+    let signing_key = args
+        .signing_key
+        .as_deref()
+        .map(artifact_format::read_signing_key)
+        .transpose()?;
+    let mut entry = ProSrcEntry::new(
+        &src,
+        &args.trusted_pgx_version,
+        function_signature,
+        args.encoding.parse::<Encoding>()?,
+        signing_key,
+    );
+    let build = cargo::Build {
+        crate_dir: &crate_dir,
+        work_dir: &work_dir,
+        crate_name: &symbol_name,
+        pg_config: &pg_config,
+        dependency_lints: dependency_lints.as_ref(),
+    };
+    for target in targets {
+        let so_bytes = build.build(&target)?;
+        let lockfile = std::fs::read(crate_dir.join("Cargo.lock")).ok();
+        entry.add_library(
+            &target,
+            &symbol_name,
+            &lints,
+            so_bytes,
+            BuildInputs {
+                toolchain: toolchain.clone(),
+                trusted_pgx_version: args.trusted_pgx_version.clone(),
+                lockfile_sha256: lockfile.map(|lockfile| artifact_format::sha256_hex(&lockfile)),
+                dependency_lints: dependency_lints.clone(),
+                allow_list_sha256: allow_list.as_ref().map(dependencies::allow_list_sha256),
+            },
+        )?;
+    }
+
+    entry.lockfile = std::fs::read_to_string(crate_dir.join("Cargo.lock")).ok();
+    println!("{}", serde_json::to_string(&entry)?);
+    Ok(())
+}
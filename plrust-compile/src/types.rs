@@ -0,0 +1,87 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Mapping Postgres type names to Rust types.
+//!
+//! Without a catalog to look types up in, we go by name.  This must map every type the same way
+//! `oid_to_syn_type()` in `plrust/src/user_crate/mod.rs` does, or the function we compile won't
+//! match the one the server calls.
+use quote::quote;
+
+/// The Rust type PL/Rust uses for the Postgres type named `sql_type`, such as `int4`, `integer`, or
+/// `text[]`
+pub(crate) fn rust_type(sql_type: &str, owned: bool) -> eyre::Result<syn::Type> {
+    let normalized = sql_type.trim().to_lowercase();
+    let (base_type, array) = match normalized.strip_suffix("[]") {
+        Some(base_type) => (base_type.trim(), true),
+        None => (normalized.as_str(), false),
+    };
+
+    let base_rust_type = match base_type {
+        "anyelement" => quote! { pgx::AnyElement },
+        "bool" | "boolean" => quote! { bool },
+        "box" => quote! {pgx::BOX },
+        "bytea" if owned => quote! { Vec<Option<[u8]>> },
+        "bytea" => quote! { &'a [u8] },
+        "\"char\"" => quote! { u8 },
+        "cstring" => quote! { std::ffi::CStr },
+        "float4" | "real" => quote! { f32 },
+        "float8" | "double precision" => quote! { f64 },
+        "int2" | "smallint" => quote! { i16 },
+        "int4" | "integer" | "int" => quote! { i32 },
+        "int4range" => quote! { Range<i32> },
+        "int8" | "bigint" => quote! { i64 },
+        "int8range" => quote! { Range<i64> },
+        "jsonb" => quote! { pgx::JsonB },
+        "json" => quote! { pgx::Json },
+        "point" => quote! { pgx::Point },
+        "numeric" | "decimal" => quote! { pgx::AnyNumeric },
+        "numrange" => quote! { Range<pgx::AnyNumeric> },
+        "oid" => quote! { pgx::Oid },
+        "text" if owned => quote! { String },
+        "text" => quote! { &'a str },
+        "tid" => quote! { pg_sys::ItemPointer },
+        "uuid" => quote! { pgx::Uuid },
+        "varchar" | "character varying" => quote! { String },
+        "void" => quote! { () },
+        _ => eyre::bail!("no Rust type mapping for Postgres type `{sql_type}`"),
+    };
+
+    let rust_type = if array {
+        quote! { Vec<Option<#base_rust_type>> }
+    } else {
+        base_rust_type
+    };
+
+    syn::parse2(rust_type.clone())
+        .map_err(|e| eyre::eyre!("parsing `{rust_type}`, the Rust type for `{sql_type}`: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use quote::ToTokens;
+
+    use super::rust_type;
+
+    fn rendered(sql_type: &str, owned: bool) -> String {
+        rust_type(sql_type, owned)
+            .unwrap()
+            .to_token_stream()
+            .to_string()
+    }
+
+    #[test]
+    fn types_are_mapped_by_name() {
+        assert_eq!("i32", rendered("int4", false));
+        assert_eq!("i32", rendered(" INTEGER ", false));
+        assert_eq!("& 'a str", rendered("text", false));
+        assert_eq!("String", rendered("text", true));
+        assert_eq!("Vec < Option < i64 > >", rendered("bigint[]", false));
+        assert!(rust_type("no_such_type", false).is_err());
+    }
+}
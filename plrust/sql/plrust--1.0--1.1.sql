@@ -0,0 +1,257 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+-- Creates everything a fresh 1.1 install has that a 1.0 install doesn't.  Keep this in sync with
+-- the `#[pg_extern]` functions and `extension_sql!()` blocks that make up the 1.1 schema.
+
+\echo Use "ALTER EXTENSION plrust UPDATE TO '1.1'" to load this file. \quit
+
+-- recompile.rs
+CREATE FUNCTION plrust.stale_functions() RETURNS TABLE (
+    "fn_oid" oid,
+    "signature" text,
+    "reasons" text[]
+) STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'stale_functions_wrapper';
+
+CREATE FUNCTION plrust.recompile_functions("dry_run" bool DEFAULT false) RETURNS TABLE (
+    "fn_oid" oid,
+    "signature" text,
+    "status" text,
+    "message" text
+) STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'recompile_functions_wrapper';
+
+-- introspection.rs
+CREATE FUNCTION plrust.artifact_hashes("fn_oid" oid) RETURNS TABLE (
+    "target" text,
+    "so_sha256" text,
+    "src_sha256" text,
+    "build_inputs_sha256" text,
+    "toolchain" text,
+    "trusted_pgx_version" text,
+    "lockfile_sha256" text
+) STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'artifact_hashes_wrapper';
+
+CREATE FUNCTION plrust.lockfile("fn_oid" oid) RETURNS text
+    STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'lockfile_wrapper';
+
+CREATE FUNCTION plrust.loaded_functions_stats() RETURNS TABLE (
+    "loaded" int8,
+    "max_loaded" int8,
+    "hits" int8,
+    "misses" int8,
+    "evictions" int8
+) STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'loaded_functions_stats_wrapper';
+
+CREATE FUNCTION plrust.function_info("fn" regprocedure) RETURNS TABLE (
+    "target" text,
+    "symbol" text,
+    "stored_bytes" int8,
+    "library_bytes" int8,
+    "lints" text[],
+    "missing_lints" text[],
+    "signed" bool,
+    "trusted_pgx_version" text,
+    "dependencies" text[],
+    "generation" int8,
+    "linted_dependencies" text[],
+    "audited_dependencies" text[]
+) STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'function_info_wrapper';
+
+CREATE VIEW plrust.functions AS
+SELECT p.oid AS fn_oid,
+       p.oid::regprocedure AS function,
+       i.*
+  FROM pg_catalog.pg_proc p
+  JOIN pg_catalog.pg_language l ON l.oid = p.prolang
+  LEFT JOIN LATERAL plrust.function_info(p.oid) i ON true
+ WHERE l.lanname = 'plrust';
+
+CREATE FUNCTION plrust.allowed_dependencies() RETURNS TABLE (
+    "name" text,
+    "version" text,
+    "features" text[],
+    "forced_features" text[],
+    "forbidden_features" text[],
+    "default_features" bool,
+    "build_script_ok" bool,
+    "proc_macro_ok" bool,
+    "role" text,
+    "schema" text
+) STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'allowed_dependencies_wrapper';
+
+CREATE FUNCTION plrust.rescan_advisories() RETURNS TABLE (
+    "fn_oid" oid,
+    "signature" text,
+    "name" text,
+    "version" text,
+    "advisory" text,
+    "informational" text,
+    "title" text
+) STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'rescan_advisories_wrapper';
+
+-- plrust.rs
+CREATE FUNCTION plrust.warm("functions" regprocedure[]) RETURNS TABLE ("fn_oid" oid, "signature" text, "loaded" bool, "error" text)
+    STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'warm_wrapper';
+
+-- check.rs
+CREATE FUNCTION plrust."check"(
+    "source" text,
+    "argtypes" regtype[],
+    "rettype" regtype,
+    "strict" bool,
+    "argnames" text[] DEFAULT NULL,
+    "returns_set" bool DEFAULT false
+) RETURNS TABLE (
+    "level" text,
+    "code" text,
+    "message" text,
+    "line" int,
+    "column" int,
+    "rendered" text
+) LANGUAGE c AS 'MODULE_PATHNAME', 'check_wrapper';
+
+-- export.rs
+CREATE FUNCTION plrust.export_crate(
+    "fn" regprocedure,
+    "trusted_pgx_path" text DEFAULT '../plrust-trusted-pgx'
+) RETURNS bytea LANGUAGE c AS 'MODULE_PATHNAME', 'export_crate_wrapper';
+
+CREATE FUNCTION plrust.export_crate_to(
+    "fn" regprocedure,
+    "dir" text,
+    "trusted_pgx_path" text DEFAULT '../plrust-trusted-pgx'
+) RETURNS text LANGUAGE c AS 'MODULE_PATHNAME', 'export_crate_to_wrapper';
+
+-- stats.rs
+CREATE FUNCTION plrust.function_stats() RETURNS TABLE (
+    "db_oid" oid,
+    "fn_oid" oid,
+    "calls" int8,
+    "panics" int8,
+    "errors" int8,
+    "total_time" float8,
+    "max_time" float8,
+    "loads" int8,
+    "load_time" float8,
+    "library_bytes" int8
+) STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'function_stats_wrapper';
+
+CREATE FUNCTION plrust.stat_reset("fn_oid" oid DEFAULT NULL) RETURNS void
+    LANGUAGE c AS 'MODULE_PATHNAME', 'stat_reset_wrapper';
+
+CREATE VIEW plrust.stat_functions AS
+SELECT s.fn_oid,
+       n.nspname AS schemaname,
+       p.proname AS funcname,
+       s.calls,
+       s.panics,
+       s.errors,
+       s.total_time,
+       s.max_time,
+       s.loads,
+       s.load_time,
+       s.library_bytes
+  FROM plrust.function_stats() s
+  JOIN pg_catalog.pg_proc p ON p.oid = s.fn_oid
+  JOIN pg_catalog.pg_namespace n ON n.oid = p.pronamespace
+ WHERE s.db_oid = (SELECT oid FROM pg_catalog.pg_database WHERE datname = pg_catalog.current_database());
+
+REVOKE ALL ON FUNCTION plrust.stat_reset(oid) FROM PUBLIC;
+
+CREATE FUNCTION plrust.drop_function_stats() RETURNS event_trigger
+    LANGUAGE plpgsql
+    SECURITY DEFINER
+    SET search_path = pg_catalog
+AS $$
+BEGIN
+    PERFORM plrust.stat_reset(d.objid)
+       FROM pg_catalog.pg_event_trigger_dropped_objects() d
+      WHERE d.classid = 'pg_catalog.pg_proc'::regclass;
+END;
+$$;
+REVOKE ALL ON FUNCTION plrust.drop_function_stats() FROM PUBLIC;
+
+CREATE EVENT TRIGGER plrust_drop_function_stats ON sql_drop
+    EXECUTE FUNCTION plrust.drop_function_stats();
+
+-- storage.rs
+CREATE TABLE plrust.artifacts (
+    -- the function these artifacts were compiled for
+    fn_oid oid NOT NULL,
+    -- the generation of the function's pg_proc row when it was compiled
+    generation int8 NOT NULL,
+    -- the same json document PL/Rust would otherwise have stored in pg_proc.prosrc
+    entry text NOT NULL,
+    PRIMARY KEY (fn_oid, generation)
+);
+REVOKE ALL ON plrust.artifacts FROM PUBLIC;
+
+CREATE FUNCTION plrust.drop_artifacts() RETURNS event_trigger
+    LANGUAGE plpgsql
+    SECURITY DEFINER
+    SET search_path = pg_catalog
+AS $$
+BEGIN
+    DELETE FROM plrust.artifacts a
+     USING pg_catalog.pg_event_trigger_dropped_objects() d
+     WHERE d.classid = 'pg_catalog.pg_proc'::regclass
+       AND a.fn_oid = d.objid;
+END;
+$$;
+REVOKE ALL ON FUNCTION plrust.drop_artifacts() FROM PUBLIC;
+
+CREATE EVENT TRIGGER plrust_drop_artifacts ON sql_drop
+    EXECUTE FUNCTION plrust.drop_artifacts();
+
+-- Moves the entries of functions whose pg_proc row was rewritten without changing their source
+-- code along to the row's new generation.  This must compute the same thing as
+-- `PgProc::generation_number()`
+CREATE FUNCTION plrust.rekey_artifacts() RETURNS event_trigger
+    LANGUAGE plpgsql
+    SECURITY DEFINER
+    SET search_path = pg_catalog
+AS $$
+BEGIN
+    UPDATE plrust.artifacts a
+       SET generation = (p.xmin::text::int8 << 32) | p.cmin::text::int8
+      FROM pg_catalog.pg_proc p
+     WHERE p.oid = a.fn_oid
+       AND a.generation <> ((p.xmin::text::int8 << 32) | p.cmin::text::int8)
+       AND a.entry::jsonb ->> 'src' = p.prosrc;
+END;
+$$;
+REVOKE ALL ON FUNCTION plrust.rekey_artifacts() FROM PUBLIC;
+
+CREATE EVENT TRIGGER plrust_rekey_artifacts ON ddl_command_end
+    WHEN TAG IN ('ALTER FUNCTION', 'ALTER ROUTINE', 'GRANT', 'REVOKE')
+    EXECUTE FUNCTION plrust.rekey_artifacts();
+
+-- history.rs
+CREATE TABLE plrust.compile_history (
+    id int8 NOT NULL GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    -- when the compilation started
+    compiled_at timestamptz NOT NULL,
+    fn_oid oid NOT NULL,
+    -- the function's signature at the time, as it may since have been renamed or dropped
+    signature text NOT NULL,
+    -- the user who caused the function to be compiled
+    compiled_by name NOT NULL,
+    -- the compilation targets that were built, or attempted
+    targets text[] NOT NULL,
+    duration interval NOT NULL,
+    success bool NOT NULL,
+    warnings text[] NOT NULL,
+    -- what `cargo` printed, for each target
+    stdout text,
+    stderr text,
+    -- why the compilation failed
+    error text
+);
+CREATE INDEX ON plrust.compile_history (compiled_at);
+CREATE INDEX ON plrust.compile_history (fn_oid);
+REVOKE ALL ON plrust.compile_history FROM PUBLIC;
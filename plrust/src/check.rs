@@ -0,0 +1,166 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Checking PL/Rust source code against this server's toolchain, lints and allowed dependencies
+//! without creating a function.
+//!
+//! The source goes through the same [`UserCrate`] pipeline as `CREATE FUNCTION`, up until the
+//! crate is built, where we instead ask `cargo check` for the compiler's diagnostics.  Nothing that
+//! was generated is kept.
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use pgx::prelude::*;
+use pgx::PgOid;
+
+use crate::gucs;
+use crate::user_crate::{Diagnostic, UserCrate};
+
+/// Checked crates are named with a generation number, like a function's, so that concurrent checks
+/// in a backend don't share a crate directory.  The other half of the generation number is our pid
+static CHECK_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// Check PL/Rust source code as the body of a function with the specified arguments and return
+/// type, returning the compiler's diagnostics.  An empty result means the function would compile.
+///
+/// Arguments are named `argnames`, or `arg1`, `arg2`, etc. when it's NULL, and the function returns
+/// `SETOF rettype` when `returns_set` is true.  Like `CREATE FUNCTION`, this requires `USAGE` on
+/// `LANGUAGE plrust`.
+#[pg_extern(sql = r#"
+CREATE FUNCTION "check"(
+    "source" text,
+    "argtypes" regtype[],
+    "rettype" regtype,
+    "strict" bool,
+    "argnames" text[] DEFAULT NULL,
+    "returns_set" bool DEFAULT false
+) RETURNS TABLE (
+    "level" text,
+    "code" text,
+    "message" text,
+    "line" int,
+    "column" int,
+    "rendered" text
+) LANGUAGE c AS 'MODULE_PATHNAME', '@FUNCTION_NAME@';
+"#)]
+fn check(
+    source: String,
+    argtypes: Vec<Option<pg_sys::Oid>>,
+    rettype: pg_sys::Oid,
+    strict: bool,
+    argnames: Option<Vec<Option<String>>>,
+    returns_set: bool,
+) -> TableIterator<
+    'static,
+    (
+        name!(level, String),
+        name!(code, Option<String>),
+        name!(message, String),
+        name!(line, Option<i32>),
+        name!(column, Option<i32>),
+        name!(rendered, Option<String>),
+    ),
+> {
+    if !has_language_usage() {
+        pgx::ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+            "permission denied for language plrust"
+        );
+    }
+
+    let diagnostics = match check_source(&source, argtypes, argnames, rettype, strict, returns_set)
+    {
+        Ok(diagnostics) => diagnostics,
+        // anything wrong with the user's source before it got to the compiler
+        Err(err) => vec![Diagnostic::error(format!("{err:#}"))],
+    };
+
+    TableIterator::new(
+        diagnostics
+            .into_iter()
+            .map(|d| (d.level, d.code, d.message, d.line, d.column, d.rendered)),
+    )
+}
+
+/// Can the current user create `LANGUAGE plrust` functions?
+fn has_language_usage() -> bool {
+    let plrust_lang_oid = crate::hooks::plrust_lang_oid();
+    plrust_lang_oid != pg_sys::Oid::INVALID
+        && unsafe {
+            // SAFETY:  `pg_language_aclcheck` only inspects the catalogs and the current session's
+            // user id
+            pg_sys::pg_language_aclcheck(
+                plrust_lang_oid,
+                pg_sys::GetUserId(),
+                pg_sys::ACL_USAGE as _,
+            )
+        } == pg_sys::AclResult_ACLCHECK_OK
+}
+
+#[tracing::instrument(level = "debug", skip(source))]
+fn check_source(
+    source: &str,
+    argtypes: Vec<Option<pg_sys::Oid>>,
+    argnames: Option<Vec<Option<String>>>,
+    rettype: pg_sys::Oid,
+    strict: bool,
+    returns_set: bool,
+) -> eyre::Result<Vec<Diagnostic>> {
+    let argtypes = argtypes
+        .into_iter()
+        .map(|argtype| argtype.ok_or_else(|| eyre::eyre!("`argtypes` cannot contain NULLs")))
+        .collect::<eyre::Result<Vec<_>>>()?;
+    let argnames = match argnames {
+        Some(argnames) if argnames.len() != argtypes.len() => {
+            eyre::bail!("`argnames` must have as many elements as `argtypes`")
+        }
+        Some(argnames) => argnames
+            .into_iter()
+            .map(|name| {
+                name.ok_or_else(|| eyre::eyre!("PL/Rust does not support unnamed arguments"))
+            })
+            .collect::<eyre::Result<Vec<_>>>()?,
+        None => (1..=argtypes.len()).map(|i| format!("arg{i}")).collect(),
+    };
+    let argument_oids_and_names = argtypes
+        .into_iter()
+        .map(PgOid::from)
+        .zip(argnames)
+        .map(|(oid, name)| {
+            let ident = syn::parse_str::<syn::Ident>(&name).map_err(|_| {
+                eyre::eyre!(
+                    "`{name}` is an invalid Rust identifier and cannot be used as an argument name"
+                )
+            })?;
+            Ok((oid, ident))
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    // SAFETY: Postgres globally sets these during backend initialization, so they're always
+    // read-safe and we're not racing anyone
+    let (db_oid, pid) = unsafe { (pg_sys::MyDatabaseId, pg_sys::MyProcPid) };
+    let generation_number =
+        ((pid as u64) << 32) | CHECK_COUNTER.fetch_add(1, Ordering::Relaxed) as u64;
+
+    let work_dir = gucs::work_dir();
+    let target_dir = work_dir.join("target");
+    let generated = UserCrate::try_from_source(
+        db_oid,
+        generation_number,
+        source,
+        argument_oids_and_names,
+        PgOid::from(rettype),
+        returns_set,
+        strict,
+    )?;
+    // nothing that's generated is kept, whether or not the crate makes it to the compiler
+    let _crate_dir = generated.remove_crate_dir_on_drop(&work_dir);
+    let provisioned = generated.provision(&work_dir)?;
+    let (validated, _output) = provisioned.validate(target_dir.as_path())?;
+    validated.check(target_dir.as_path())
+}
@@ -33,8 +33,26 @@ pub(crate) enum PlRustError {
     ParsingCodeBlock(syn::Error),
     #[error("Parsing error at span `{:?}`", .0.span())]
     Parse(#[from] syn::Error),
+    #[error("Function `{0}` has not been compiled")]
+    FunctionNotCompiled(pgx::pg_sys::Oid),
     #[error("Function was not compiled for this host (`{0}`)")]
     FunctionNotCompiledForTarget(CompilationTarget),
     #[error("Function not compiled with required lints: {0}")]
     MissingLints(LintSet),
+    #[error("Function artifact for `{0}` is not signed")]
+    UnsignedArtifact(CompilationTarget),
+    #[error("Function artifact for `{0}` failed signature verification")]
+    InvalidArtifactSignature(CompilationTarget),
+    #[error("Function artifact for `{0}` does not match its recorded SHA-256 hashes")]
+    ArtifactHashMismatch(CompilationTarget),
+    #[error("Function `{0}` is not a PL/Rust function")]
+    NotPlRustFunction(pgx::pg_sys::Oid),
+    #[error("Permission denied to execute function `{0}`")]
+    NoExecutePermission(pgx::pg_sys::Oid),
+    #[error("Only superusers may recompile PL/Rust functions")]
+    RecompileRequiresSuperuser,
+    #[error("Only superusers may export PL/Rust crates to the server's filesystem")]
+    ExportRequiresSuperuser,
+    #[error("`plrust.artifact_storage = 'table'` requires `plrust.artifacts`, which this database doesn't have yet.  Run `ALTER EXTENSION plrust UPDATE`")]
+    ArtifactsTableMissing,
 }
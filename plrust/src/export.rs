@@ -0,0 +1,114 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Exporting the crate PL/Rust generates for a function, so it can be opened in an IDE and iterated
+//! on locally.
+//!
+//! The exported `Cargo.toml` and `src/lib.rs` are exactly what the build sees, except that the
+//! `plrust-trusted-pgx` dependency can point at a local checkout.
+use std::path::PathBuf;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use pgx::prelude::*;
+
+use crate::error::PlRustError;
+use crate::pgproc::PgProc;
+use crate::user_crate::UserCrate;
+
+/// The generated crate for the specified function as a `.tar.gz`, with everything under a directory
+/// named after the crate.
+#[pg_extern(sql = r#"
+CREATE FUNCTION export_crate(
+    "fn" regprocedure,
+    "trusted_pgx_path" text DEFAULT '../plrust-trusted-pgx'
+) RETURNS bytea LANGUAGE c AS 'MODULE_PATHNAME', '@FUNCTION_NAME@';
+"#)]
+fn export_crate(fn_oid: pg_sys::Oid, trusted_pgx_path: Option<String>) -> Vec<u8> {
+    fn export_crate_inner(
+        fn_oid: pg_sys::Oid,
+        trusted_pgx_path: Option<String>,
+    ) -> eyre::Result<Vec<u8>> {
+        let (crate_name, files) = generated_crate(fn_oid, trusted_pgx_path.as_deref())?;
+
+        let mut tarball = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tarball.append_data(
+                &mut header,
+                format!("{crate_name}/{path}"),
+                contents.as_bytes(),
+            )?;
+        }
+        Ok(tarball.into_inner()?.finish()?)
+    }
+
+    match export_crate_inner(fn_oid, trusted_pgx_path) {
+        Ok(tarball) => tarball,
+        // Panic into the pgx guard.
+        Err(err) => panic!("{:?}", err),
+    }
+}
+
+/// Write the generated crate for the specified function into a directory named after the crate,
+/// under `dir` on the server's filesystem, and return the crate's directory.  Superuser only.
+#[pg_extern(sql = r#"
+CREATE FUNCTION export_crate_to(
+    "fn" regprocedure,
+    "dir" text,
+    "trusted_pgx_path" text DEFAULT '../plrust-trusted-pgx'
+) RETURNS text LANGUAGE c AS 'MODULE_PATHNAME', '@FUNCTION_NAME@';
+"#)]
+fn export_crate_to(fn_oid: pg_sys::Oid, dir: String, trusted_pgx_path: Option<String>) -> String {
+    fn export_crate_to_inner(
+        fn_oid: pg_sys::Oid,
+        dir: String,
+        trusted_pgx_path: Option<String>,
+    ) -> eyre::Result<String> {
+        // SAFETY:  `superuser()` only inspects the current session's user id
+        if !unsafe { pg_sys::superuser() } {
+            return Err(PlRustError::ExportRequiresSuperuser)?;
+        }
+
+        let (crate_name, files) = generated_crate(fn_oid, trusted_pgx_path.as_deref())?;
+        let crate_dir = PathBuf::from(dir).join(crate_name);
+        for (path, contents) in files {
+            let path = crate_dir.join(path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, contents)?;
+        }
+        Ok(crate_dir.display().to_string())
+    }
+
+    match export_crate_to_inner(fn_oid, dir, trusted_pgx_path) {
+        Ok(crate_dir) => crate_dir,
+        // Panic into the pgx guard.
+        Err(err) => panic!("{:?}", err),
+    }
+}
+
+/// The specified function's crate name and generated files
+fn generated_crate(
+    fn_oid: pg_sys::Oid,
+    trusted_pgx_path: Option<&str>,
+) -> eyre::Result<(String, Vec<(String, String)>)> {
+    if PgProc::new(fn_oid)?.prolang() != crate::hooks::plrust_lang_oid() {
+        return Err(PlRustError::NotPlRustFunction(fn_oid))?;
+    }
+
+    // SAFETY: Postgres globally sets this to `const InvalidOid`, so is always read-safe,
+    // then writes it only during initialization, so we should not be racing anyone.
+    let db_oid = unsafe { pg_sys::MyDatabaseId };
+    let generated = unsafe { UserCrate::try_from_fn_oid(db_oid, fn_oid)? };
+    Ok((generated.crate_name(), generated.export(trusted_pgx_path)?))
+}
@@ -7,48 +7,64 @@ All rights reserved.
 Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
 */
 
-use std::ffi::CStr;
+use std::cell::Cell;
+use std::ffi::{c_void, CStr};
+use std::os::raw::c_char;
 use std::path::PathBuf;
+use std::rc::Rc;
 use std::str::FromStr;
 
-use once_cell::sync::Lazy;
+use eyre::WrapErr;
 use pgx::guc::{GucContext, GucRegistry, GucSetting};
 use pgx::pg_sys::AsPgCStr;
-use pgx::{pg_sys, GucFlags};
+use pgx::{pg_guard, pg_sys, GucFlags};
 
+use crate::history::Retention;
+use crate::restore::RestorePolicy;
+use crate::storage::StorageMode;
 use crate::target::{CompilationTarget, CrossCompilationTarget, TargetErr};
+use crate::user_crate::advisories;
+use crate::user_crate::advisory_db::{AdvisoryDb, AdvisoryPolicy};
+use crate::user_crate::{CrateSource, DependencyPinning, Encoding};
 use crate::{target, DEFAULT_LINTS};
 
 static PLRUST_WORK_DIR: GucSetting<Option<&'static str>> = GucSetting::new(None);
 pub(crate) static PLRUST_PATH_OVERRIDE: GucSetting<Option<&'static str>> = GucSetting::new(None);
 static PLRUST_TRACING_LEVEL: GucSetting<Option<&'static str>> = GucSetting::new(None);
-pub(crate) static PLRUST_ALLOWED_DEPENDENCIES: GucSetting<Option<&'static str>> =
-    GucSetting::new(None);
+/// `plrust.allowed_dependencies` is defined directly with Postgres, rather than through
+/// `GucRegistry`, so it can have check and assign hooks
+static mut PLRUST_ALLOWED_DEPENDENCIES: *mut c_char = std::ptr::null_mut();
+static PLRUST_DEPENDENCY_PINNING: GucSetting<Option<&'static str>> =
+    GucSetting::new(Some("direct"));
+/// `plrust.crate_source` is also defined directly, so it has a check hook
+static mut PLRUST_CRATE_SOURCE: *mut c_char = std::ptr::null_mut();
+static PLRUST_DEPENDENCY_LINTS: GucSetting<bool> = GucSetting::new(false);
+static PLRUST_ADVISORY_DB: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static PLRUST_ADVISORY_POLICY: GucSetting<Option<&'static str>> = GucSetting::new(Some("deny"));
 static PLRUST_COMPILATION_TARGETS: GucSetting<Option<&'static str>> = GucSetting::new(None);
 pub(crate) static PLRUST_COMPILE_LINTS: GucSetting<Option<&'static str>> =
     GucSetting::new(Some(DEFAULT_LINTS));
 pub(crate) static PLRUST_REQUIRED_LINTS: GucSetting<Option<&'static str>> =
     GucSetting::new(Some(DEFAULT_LINTS));
+pub(crate) static PLRUST_ARTIFACT_SIGNING_KEY: GucSetting<Option<&'static str>> =
+    GucSetting::new(None);
+/// `plrust.artifact_encoding` is also defined directly, so it has a check hook
+static mut PLRUST_ARTIFACT_ENCODING: *mut c_char = std::ptr::null_mut();
+static PLRUST_ARTIFACT_STORAGE: GucSetting<Option<&'static str>> = GucSetting::new(Some("prosrc"));
+static PLRUST_RESTORE_POLICY: GucSetting<Option<&'static str>> = GucSetting::new(Some("recompile"));
+static PLRUST_MAX_LOADED_FUNCTIONS: GucSetting<i32> = GucSetting::new(0);
+static PLRUST_PRELOAD_FUNCTIONS: GucSetting<Option<&'static str>> = GucSetting::new(None);
+static PLRUST_COMPILE_HISTORY_RETENTION: GucSetting<i32> = GucSetting::new(30 * 24 * 60);
 pub(crate) static PLRUST_TRUSTED_PGX_VERSION: GucSetting<Option<&'static str>> =
     GucSetting::new(Some(env!(
         "PLRUST_TRUSTED_PGX_VERSION",
         "unknown `plrust-trusted-pgx` version.  `build.rs` must not have run successfully"
     )));
 
-pub(crate) static PLRUST_ALLOWED_DEPENDENCIES_CONTENTS: Lazy<toml::value::Table> =
-    Lazy::new(|| {
-        let path = PathBuf::from_str(
-            &PLRUST_ALLOWED_DEPENDENCIES
-                .get()
-                .expect("plrust.allowed_dependencies is not set in postgresql.conf"),
-        )
-        .expect("plrust.allowed_dependencies is not a valid path");
-
-        let contents =
-            std::fs::read_to_string(&path).expect("Unable to read allow listed dependencies");
-
-        toml::from_str(&contents).expect("Unable to format allow listed dependencies")
-    });
+thread_local! {
+    /// The parsed contents of `plrust.allowed_dependencies`, until it's next assigned
+    static PLRUST_ALLOWED_DEPENDENCIES_CONTENTS: Cell<Option<Rc<toml::value::Table>>> = Cell::new(None);
+}
 
 pub(crate) fn init() {
     GucRegistry::define_string_guc(
@@ -78,12 +94,72 @@ pub(crate) fn init() {
         GucFlags::default(),
     );
 
+    unsafe {
+        // SAFETY:  Postgres keeps the name and descriptions we give it, so they're all 'static, and
+        // it owns the string `PLRUST_ALLOWED_DEPENDENCIES` points to
+        pg_sys::DefineCustomStringVariable(
+            "plrust.allowed_dependencies\0".as_ptr().cast(),
+            "The full path of a toml file containing crates and versions allowed when creating PL/Rust functions\0".as_ptr().cast(),
+            "Reloaded on SIGHUP.  The file is checked whenever this is set, and an invalid file is refused\0".as_ptr().cast(),
+            std::ptr::addr_of_mut!(PLRUST_ALLOWED_DEPENDENCIES),
+            std::ptr::null(),
+            pg_sys::GucContext_PGC_SIGHUP,
+            0,
+            Some(check_allowed_dependencies),
+            Some(assign_allowed_dependencies),
+            None,
+        );
+    }
+
     GucRegistry::define_string_guc(
-        "plrust.allowed_dependencies",
-        "The full path of a toml file containing crates and versions allowed when creating PL/Rust functions",
-        "The full path of a toml file containing crates and versions allowed when creating PL/Rust functions",
-        &PLRUST_ALLOWED_DEPENDENCIES,
-        GucContext::Postmaster,
+        "plrust.dependency_pinning",
+        "Which dependencies of a PL/Rust function must be pinned to a version in `plrust.allowed_dependencies`.  Supported values are: direct, transitive",
+        "With `transitive`, every crate a function's dependencies depend on must also be allowed, and is updated to the newest compatible allowed version",
+        &PLRUST_DEPENDENCY_PINNING,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+
+    unsafe {
+        // SAFETY:  as for `plrust.allowed_dependencies`
+        pg_sys::DefineCustomStringVariable(
+            "plrust.crate_source\0".as_ptr().cast(),
+            "Where PL/Rust function dependencies come from instead of crates.io: a `cargo vendor` directory, a `cargo local-registry` directory, or a registry index url\0".as_ptr().cast(),
+            "Builds from a directory never use the network, and fail if a crate isn't in it\0".as_ptr().cast(),
+            std::ptr::addr_of_mut!(PLRUST_CRATE_SOURCE),
+            std::ptr::null(),
+            pg_sys::GucContext_PGC_SIGHUP,
+            0,
+            Some(check_crate_source),
+            None,
+            None,
+        );
+    }
+
+    GucRegistry::define_bool_guc(
+        "plrust.dependency_lints",
+        "Compile the crates PL/Rust functions depend on with the `plrust_lints` group too",
+        "Crates marked `audited` in `plrust.allowed_dependencies`, and those `plrust-trusted-pgx` needs, are not linted",
+        &PLRUST_DEPENDENCY_LINTS,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "plrust.advisory_db",
+        "The full path of a local copy of the RustSec advisory database to check PL/Rust function dependencies against",
+        "It's only read, and must be kept up to date by other means",
+        &PLRUST_ADVISORY_DB,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "plrust.advisory_policy",
+        "What to do with a function that depends on a crate version with an advisory in `plrust.advisory_db`.  Supported values are: deny, warn",
+        "Informational advisories, such as a crate being unmaintained, are only ever warned about",
+        &PLRUST_ADVISORY_POLICY,
+        GucContext::Sighup,
         GucFlags::default(),
     );
 
@@ -122,6 +198,196 @@ pub(crate) fn init() {
         GucContext::Sighup,
         GucFlags::default(),
     );
+
+    GucRegistry::define_string_guc(
+        "plrust.artifact_signing_key",
+        "The full path of a file containing the key used to sign and verify compiled PL/Rust artifacts",
+        "If set, compiled functions are signed with this key and unsigned or tampered artifacts are refused when loaded",
+        &PLRUST_ARTIFACT_SIGNING_KEY,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+
+    unsafe {
+        // SAFETY:  as for `plrust.allowed_dependencies`, and the boot value is 'static too
+        pg_sys::DefineCustomStringVariable(
+            "plrust.artifact_encoding\0".as_ptr().cast(),
+            "How compiled PL/Rust artifacts are compressed when stored in `pg_catalog.pg_proc`.  Supported values are: zstd, gzip\0".as_ptr().cast(),
+            "Only affects newly compiled functions.  Artifacts stored with any encoding can always be loaded\0".as_ptr().cast(),
+            std::ptr::addr_of_mut!(PLRUST_ARTIFACT_ENCODING),
+            "zstd\0".as_ptr().cast(),
+            pg_sys::GucContext_PGC_SIGHUP,
+            0,
+            Some(check_artifact_encoding),
+            None,
+            None,
+        );
+    }
+
+    GucRegistry::define_string_guc(
+        "plrust.artifact_storage",
+        "Where compiled PL/Rust artifacts are stored.  Supported values are: prosrc, table",
+        "With `table`, `pg_catalog.pg_proc.prosrc` keeps only the function's source code and compiled artifacts are stored in `plrust.artifacts`",
+        &PLRUST_ARTIFACT_STORAGE,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "plrust.restore_policy",
+        "What to do with the compiled artifacts of a function being restored.  Supported values are: recompile, trust, background",
+        "`recompile` compiles the function as it's restored, `trust` keeps restored artifacts that verify, and `background` compiles it after the restore",
+        &PLRUST_RESTORE_POLICY,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "plrust.max_loaded_functions",
+        "The maximum number of PL/Rust functions each backend keeps loaded",
+        "When exceeded, the least-recently-used functions are closed.  Zero means no limit",
+        &PLRUST_MAX_LOADED_FUNCTIONS,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "plrust.preload_functions",
+        "A comma-separated list of PL/Rust functions, such as `public.my_function(int, text)`, to load the first time a backend calls any PL/Rust function",
+        "Typically set per database with ALTER DATABASE ... SET, as the functions are looked up in the current database",
+        &PLRUST_PRELOAD_FUNCTIONS,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "plrust.compile_history_retention",
+        "How long compilations are kept in `plrust.compile_history`",
+        "Zero means compilations aren't recorded, and -1 that they're kept forever",
+        &PLRUST_COMPILE_HISTORY_RETENTION,
+        -1,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::UNIT_MIN,
+    );
+}
+
+/// Refuse a `plrust.allowed_dependencies` file that can't be read or isn't a valid allow-list
+#[pg_guard]
+unsafe extern "C" fn check_allowed_dependencies(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    // SAFETY:  Postgres gives us a valid pointer to the proposed value, which may be NULL
+    let path = unsafe { *newval };
+    if path.is_null() {
+        return true;
+    }
+
+    // SAFETY:  a non-NULL GUC string value is a valid C string
+    let path = unsafe { CStr::from_ptr(path) }.to_string_lossy();
+    match read_allowed_dependencies(&path) {
+        Ok(_) => true,
+        Err(e) => {
+            unsafe {
+                // SAFETY:  Postgres reports this palloc'd string as the ERROR's detail
+                pg_sys::GUC_check_errdetail_string = format!("{e:#}").as_pg_cstr();
+            }
+            false
+        }
+    }
+}
+
+#[pg_guard]
+unsafe extern "C" fn check_artifact_encoding(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    unsafe {
+        // SAFETY:  Postgres gives us a valid pointer to the proposed value
+        check_parses::<Encoding>(*newval)
+    }
+}
+
+/// Refuse a `plrust.crate_source` that's neither an existing directory nor a registry index url
+#[pg_guard]
+unsafe extern "C" fn check_crate_source(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    unsafe {
+        // SAFETY:  Postgres gives us a valid pointer to the proposed value
+        check_parses::<CrateSource>(*newval)
+    }
+}
+
+/// Refuse a setting that doesn't parse as a `T`, so a typo is reported when it's set rather than the
+/// next time the setting is used
+unsafe fn check_parses<T: FromStr<Err = eyre::Report>>(value: *const c_char) -> bool {
+    if value.is_null() {
+        return true;
+    }
+
+    // SAFETY:  a non-NULL GUC string value is a valid C string
+    let value = unsafe { CStr::from_ptr(value) }.to_string_lossy();
+    match value.parse::<T>() {
+        Ok(_) => true,
+        Err(e) => {
+            unsafe {
+                // SAFETY:  Postgres reports this palloc'd string as the ERROR's detail
+                pg_sys::GUC_check_errdetail_string = format!("{e:#}").as_pg_cstr();
+            }
+            false
+        }
+    }
+}
+
+/// Forget the parsed allow-list, so it's read again the next time it's needed.  Postgres calls this
+/// whenever the setting is assigned, including on every SIGHUP, even if the path hasn't changed
+#[pg_guard]
+unsafe extern "C" fn assign_allowed_dependencies(_newval: *const c_char, _extra: *mut c_void) {
+    PLRUST_ALLOWED_DEPENDENCIES_CONTENTS.with(|contents| contents.take());
+}
+
+fn read_allowed_dependencies(path: &str) -> eyre::Result<toml::value::Table> {
+    let contents = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("Unable to read allow-listed dependencies from `{path}`"))?;
+    let allowed: toml::value::Table = toml::from_str(&contents)
+        .wrap_err_with(|| format!("Unable to parse allow-listed dependencies in `{path}`"))?;
+    crate::user_crate::parse_allow_list(&allowed)
+        .wrap_err_with(|| format!("Invalid allow-listed dependencies in `{path}`"))?;
+    Ok(allowed)
+}
+
+/// The path of `plrust.allowed_dependencies`, if it's set
+pub(crate) fn allowed_dependencies_path() -> Option<String> {
+    unsafe {
+        // SAFETY:  Postgres owns this string and only changes it while we're not running
+        let path = PLRUST_ALLOWED_DEPENDENCIES;
+        (!path.is_null()).then(|| CStr::from_ptr(path).to_string_lossy().into_owned())
+    }
+}
+
+/// The contents of `plrust.allowed_dependencies`, or `None` if it isn't set and every crate is
+/// allowed.  The file is read the first time it's needed after the setting is (re)assigned
+pub(crate) fn allowed_dependencies() -> eyre::Result<Option<Rc<toml::value::Table>>> {
+    let Some(path) = allowed_dependencies_path() else {
+        return Ok(None);
+    };
+
+    PLRUST_ALLOWED_DEPENDENCIES_CONTENTS.with(|cached| {
+        let contents = match cached.take() {
+            Some(contents) => contents,
+            None => Rc::new(read_allowed_dependencies(&path)?),
+        };
+        cached.set(Some(contents.clone()));
+        Ok(Some(contents))
+    })
 }
 
 pub(crate) fn work_dir() -> PathBuf {
@@ -140,6 +406,95 @@ pub(crate) fn tracing_level() -> tracing::Level {
         .unwrap_or(tracing::Level::INFO)
 }
 
+pub(crate) fn artifact_encoding() -> eyre::Result<Encoding> {
+    unsafe {
+        // SAFETY:  Postgres owns this string and only changes it while we're not running
+        let encoding = PLRUST_ARTIFACT_ENCODING;
+        match encoding.is_null() {
+            true => Ok(Encoding::ZstdBase64),
+            false => CStr::from_ptr(encoding).to_string_lossy().parse(),
+        }
+    }
+}
+
+pub(crate) fn artifact_storage() -> eyre::Result<StorageMode> {
+    PLRUST_ARTIFACT_STORAGE.get().unwrap_or("prosrc").parse()
+}
+
+/// The function signatures listed in `plrust.preload_functions`.  Commas inside a function's argument
+/// list don't separate functions
+pub(crate) fn preload_functions() -> Vec<String> {
+    let Some(list) = PLRUST_PRELOAD_FUNCTIONS.get() else {
+        return vec![];
+    };
+
+    let mut functions = Vec::new();
+    let mut depth = 0;
+    let mut current = String::new();
+    for c in list.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                functions.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    functions.push(current);
+
+    functions
+        .into_iter()
+        .map(|f| f.trim().to_string())
+        .filter(|f| !f.is_empty())
+        .collect()
+}
+
+/// `None` if the number of loaded functions isn't limited
+pub(crate) fn max_loaded_functions() -> Option<usize> {
+    match PLRUST_MAX_LOADED_FUNCTIONS.get() {
+        0 => None,
+        max => Some(max as usize),
+    }
+}
+
+pub(crate) fn compile_history_retention() -> Retention {
+    PLRUST_COMPILE_HISTORY_RETENTION.get().into()
+}
+
+pub(crate) fn dependency_pinning() -> eyre::Result<DependencyPinning> {
+    PLRUST_DEPENDENCY_PINNING.get().unwrap_or("direct").parse()
+}
+
+/// What `plrust.crate_source` replaces crates.io with, if it's set
+pub(crate) fn crate_source() -> eyre::Result<Option<CrateSource>> {
+    unsafe {
+        // SAFETY:  Postgres owns this string and only changes it while we're not running
+        let source = PLRUST_CRATE_SOURCE;
+        (!source.is_null())
+            .then(|| CStr::from_ptr(source).to_string_lossy().parse())
+            .transpose()
+    }
+}
+
+pub(crate) fn dependency_lints() -> bool {
+    PLRUST_DEPENDENCY_LINTS.get()
+}
+
+pub(crate) fn advisory_db() -> Option<AdvisoryDb> {
+    PLRUST_ADVISORY_DB.get().map(advisories::open)
+}
+
+pub(crate) fn advisory_policy() -> eyre::Result<AdvisoryPolicy> {
+    PLRUST_ADVISORY_POLICY.get().unwrap_or("deny").parse()
+}
+
+pub(crate) fn restore_policy() -> eyre::Result<RestorePolicy> {
+    PLRUST_RESTORE_POLICY.get().unwrap_or("recompile").parse()
+}
+
 /// Returns the compilation targets a function should be compiled for.
 ///
 /// The return format is `( <This Host's Target Triple>, <Other Configured Target Triples> )`
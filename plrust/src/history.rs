@@ -0,0 +1,364 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! A history of every compilation, in the extension-owned `plrust.compile_history` table.
+//!
+//! A successful compilation is recorded in the same transaction that stores its artifacts.  A failed
+//! one can't be, as the ERROR we're about to raise rolls that transaction back, so it's instead
+//! written to a spool file under `plrust.work_dir` and the database's background worker, running in
+//! its own transactions, moves it into the table.
+//!
+//! Rows older than `plrust.compile_history_retention` are removed as new ones are recorded.
+//!
+//! Recording a compilation never fails it.  Nothing is recorded in a database whose extension hasn't
+//! been updated to have the table yet, and any other problem is only warned about.
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::path::PathBuf;
+use std::process::Output;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use pgx::bgworkers::{BackgroundWorker, SignalWakeFlags};
+use pgx::prelude::*;
+use pgx::IntoDatum;
+use serde::{Deserialize, Serialize};
+
+use crate::workers::{self, WorkerKind};
+use crate::{gucs, recompile, storage};
+
+/// How often the background worker checks whether it has been told about new spool files
+const WORKER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+extension_sql!(
+    r#"
+CREATE TABLE plrust.compile_history (
+    id int8 NOT NULL GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    -- when the compilation started
+    compiled_at timestamptz NOT NULL,
+    fn_oid oid NOT NULL,
+    -- the function's signature at the time, as it may since have been renamed or dropped
+    signature text NOT NULL,
+    -- the user who caused the function to be compiled
+    compiled_by name NOT NULL,
+    -- the compilation targets that were built, or attempted
+    targets text[] NOT NULL,
+    duration interval NOT NULL,
+    success bool NOT NULL,
+    warnings text[] NOT NULL,
+    -- what `cargo` printed, for each target
+    stdout text,
+    stderr text,
+    -- why the compilation failed
+    error text
+);
+CREATE INDEX ON plrust.compile_history (compiled_at);
+CREATE INDEX ON plrust.compile_history (fn_oid);
+REVOKE ALL ON plrust.compile_history FROM PUBLIC;
+"#,
+    name = "compile_history_table",
+);
+
+/// How long `plrust.compile_history` keeps what it records
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Retention {
+    /// compilations aren't recorded
+    Disabled,
+    /// rows are never removed
+    Forever,
+    /// rows are removed once they're this many minutes old
+    Minutes(i32),
+}
+
+impl From<i32> for Retention {
+    fn from(minutes: i32) -> Self {
+        match minutes {
+            0 => Retention::Disabled,
+            minutes if minutes < 0 => Retention::Forever,
+            minutes => Retention::Minutes(minutes),
+        }
+    }
+}
+
+thread_local! {
+    /// What `cargo` printed for each target of the compilation in progress
+    static CARGO_OUTPUT: RefCell<Vec<(String, Output)>> = Default::default();
+}
+
+/// Makes spool file names unique within this backend
+static SPOOL_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// A single compilation, as recorded in `plrust.compile_history`
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CompileRecord {
+    /// seconds since the Unix epoch
+    compiled_at: f64,
+    fn_oid: u32,
+    signature: String,
+    compiled_by: String,
+    targets: Vec<String>,
+    duration_secs: f64,
+    success: bool,
+    warnings: Vec<String>,
+    stdout: Option<String>,
+    stderr: Option<String>,
+    error: Option<String>,
+}
+
+/// A compilation in progress
+pub(crate) struct Compilation {
+    fn_oid: pg_sys::Oid,
+    started_at: SystemTime,
+    timer: Instant,
+}
+
+impl Compilation {
+    /// Start recording the compilation of the specified function
+    pub(crate) fn start(fn_oid: pg_sys::Oid) -> Self {
+        CARGO_OUTPUT.with(|output| output.borrow_mut().clear());
+        Compilation {
+            fn_oid,
+            started_at: SystemTime::now(),
+            timer: Instant::now(),
+        }
+    }
+
+    /// Record the compilation's `result` in `plrust.compile_history`, if the database has it.
+    /// Failing to record a failed compilation is only logged, so it doesn't replace the error the
+    /// user needs to see
+    pub(crate) fn finish<T>(self, result: &eyre::Result<T>) {
+        if gucs::compile_history_retention() == Retention::Disabled {
+            CARGO_OUTPUT.with(|output| output.borrow_mut().clear());
+            return;
+        }
+
+        let record = self.into_record(result.as_ref().err());
+        let recorded = match storage::table_exists("plrust.compile_history") {
+            Ok(false) => Ok(()),
+            Ok(true) if result.is_ok() => insert(&record),
+            Ok(true) => spool(&record),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = recorded {
+            match result {
+                Ok(_) => pgx::warning!("unable to record PL/Rust compilation: {e}"),
+                Err(_) => pgx::log!("unable to record failed PL/Rust compilation: {e}"),
+            }
+        }
+    }
+
+    fn into_record(self, error: Option<&eyre::Report>) -> CompileRecord {
+        let outputs = CARGO_OUTPUT.with(|output| std::mem::take(&mut *output.borrow_mut()));
+        let mut warnings = Vec::new();
+        for (_, output) in &outputs {
+            for stream in [&output.stdout, &output.stderr] {
+                for warning in String::from_utf8_lossy(stream).lines().filter_map(warning) {
+                    if !warnings.contains(&warning) {
+                        warnings.push(warning);
+                    }
+                }
+            }
+        }
+
+        let per_target = |stream: fn(&Output) -> &Vec<u8>| match outputs.as_slice() {
+            [] => None,
+            [(_, output)] => Some(String::from_utf8_lossy(stream(output)).into_owned()),
+            outputs => Some(
+                outputs
+                    .iter()
+                    .map(|(target, output)| {
+                        format!("# {target}\n{}", String::from_utf8_lossy(stream(output)))
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        };
+
+        CompileRecord {
+            compiled_at: self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            fn_oid: self.fn_oid.as_u32(),
+            signature: recompile::function_signature(self.fn_oid),
+            compiled_by: current_user(),
+            targets: outputs.iter().map(|(target, _)| target.clone()).collect(),
+            duration_secs: self.timer.elapsed().as_secs_f64(),
+            success: error.is_none(),
+            warnings,
+            stdout: per_target(|output| &output.stdout),
+            stderr: per_target(|output| &output.stderr),
+            error: error.map(|e| format!("{e:#}")),
+        }
+    }
+}
+
+/// Remember what `cargo` printed while building the specified target, for the compilation in
+/// progress
+pub(crate) fn capture_cargo_output(target: &str, output: &Output) {
+    CARGO_OUTPUT.with(|captured| {
+        captured
+            .borrow_mut()
+            .push((target.to_string(), output.clone()))
+    });
+}
+
+/// The message of a compiler warning's first line, but not `cargo`'s summary of how many there were
+fn warning(line: &str) -> Option<String> {
+    let message = line.strip_prefix("warning: ")?;
+    match message.contains(") generated ") {
+        true => None,
+        false => Some(message.to_string()),
+    }
+}
+
+fn current_user() -> String {
+    unsafe {
+        // SAFETY:  with `noerr`, `GetUserNameFromId` returns NULL rather than raising an ERROR for
+        // a role that doesn't exist, and otherwise a palloc'd copy of the role's name
+        let name = pg_sys::GetUserNameFromId(pg_sys::GetUserId(), true);
+        if name.is_null() {
+            String::from("?")
+        } else {
+            CStr::from_ptr(name).to_string_lossy().into_owned()
+        }
+    }
+}
+
+/// Insert `record` into `plrust.compile_history`, then remove whatever's older than
+/// `plrust.compile_history_retention`
+fn insert(record: &CompileRecord) -> eyre::Result<()> {
+    storage::as_table_owner("plrust.compile_history", || {
+        Spi::run_with_args(
+            "INSERT INTO plrust.compile_history
+                    (compiled_at, fn_oid, signature, compiled_by, targets, duration, success, warnings, stdout, stderr, error)
+             VALUES (to_timestamp($1), $2, $3, $4, $5, make_interval(secs => $6), $7, $8, $9, $10, $11)",
+            Some(vec![
+                (PgBuiltInOids::FLOAT8OID.oid(), record.compiled_at.into_datum()),
+                (PgBuiltInOids::OIDOID.oid(), pg_sys::Oid::from(record.fn_oid).into_datum()),
+                (PgBuiltInOids::TEXTOID.oid(), record.signature.clone().into_datum()),
+                (PgBuiltInOids::TEXTOID.oid(), record.compiled_by.clone().into_datum()),
+                (PgBuiltInOids::TEXTARRAYOID.oid(), record.targets.clone().into_datum()),
+                (PgBuiltInOids::FLOAT8OID.oid(), record.duration_secs.into_datum()),
+                (PgBuiltInOids::BOOLOID.oid(), record.success.into_datum()),
+                (PgBuiltInOids::TEXTARRAYOID.oid(), record.warnings.clone().into_datum()),
+                (PgBuiltInOids::TEXTOID.oid(), record.stdout.clone().into_datum()),
+                (PgBuiltInOids::TEXTOID.oid(), record.stderr.clone().into_datum()),
+                (PgBuiltInOids::TEXTOID.oid(), record.error.clone().into_datum()),
+            ]),
+        )?;
+
+        if let Retention::Minutes(minutes) = gucs::compile_history_retention() {
+            Spi::run_with_args(
+                "DELETE FROM plrust.compile_history WHERE compiled_at < now() - make_interval(mins => $1)",
+                Some(vec![(PgBuiltInOids::INT4OID.oid(), minutes.into_datum())]),
+            )?;
+        }
+        Ok(())
+    })
+}
+
+/// Where failed compilations in this database wait for the background worker
+fn spool_dir() -> PathBuf {
+    // SAFETY: Postgres globally sets this to `const InvalidOid`, so is always read-safe,
+    // then writes it only during initialization, so we should not be racing anyone.
+    let db_oid = unsafe { pg_sys::MyDatabaseId };
+    gucs::work_dir()
+        .join("compile_history")
+        .join(db_oid.as_u32().to_string())
+}
+
+/// Write `record` to a spool file and tell the database's background worker to insert it
+fn spool(record: &CompileRecord) -> eyre::Result<()> {
+    let spool_dir = spool_dir();
+    std::fs::create_dir_all(&spool_dir)?;
+
+    // write it under a temporary name first so the worker never sees a partial file
+    let name = format!(
+        "{}-{}",
+        std::process::id(),
+        SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let tmp_path = spool_dir.join(format!("{name}.tmp"));
+    std::fs::write(&tmp_path, serde_json::to_vec(record)?)?;
+    std::fs::rename(&tmp_path, spool_dir.join(format!("{name}.json")))?;
+
+    workers::notify(WorkerKind::History)
+}
+
+/// Moves the spooled failed compilations of its database into `plrust.compile_history`.  It keeps
+/// going while it's told about new spool files, and exits once there are none left to look at.
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn plrust_history_worker_main(_arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+    let dbname = BackgroundWorker::get_extra();
+    BackgroundWorker::connect_worker_to_spi(Some(dbname), None);
+    workers::attach(WorkerKind::History);
+
+    // whatever notified us may have done so before we attached
+    let mut look = true;
+    while BackgroundWorker::wait_latch(Some(WORKER_POLL_INTERVAL)) {
+        look |= workers::take_pending(WorkerKind::History);
+        if look {
+            insert_spooled();
+            look = false;
+        }
+
+        if workers::finish(WorkerKind::History) {
+            break;
+        }
+    }
+}
+
+/// Insert every spool file in this database's spool directory.  Should a second worker ever run
+/// for the same database, each spool file is claimed by renaming it before it's inserted.  A spool
+/// file that can't be inserted is left in place for the next time the worker looks.
+fn insert_spooled() {
+    let spool_dir = spool_dir();
+    let Ok(entries) = std::fs::read_dir(&spool_dir) else {
+        return;
+    };
+    // should the table have gone away since, the spool files wait for it to come back
+    let Ok(true) =
+        BackgroundWorker::transaction(|| storage::table_exists("plrust.compile_history"))
+    else {
+        return;
+    };
+    let claim = format!("claimed-{}", std::process::id());
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let claimed = path.with_extension(&claim);
+        if std::fs::rename(&path, &claimed).is_err() {
+            // another worker got to it first
+            continue;
+        }
+
+        let inserted = std::fs::read(&claimed)
+            .map_err(eyre::Report::from)
+            .and_then(|json| Ok(serde_json::from_slice::<CompileRecord>(&json)?))
+            .and_then(|record| BackgroundWorker::transaction(|| insert(&record)));
+        match inserted {
+            Ok(()) => {
+                if let Err(e) = std::fs::remove_file(&claimed) {
+                    pgx::warning!("unable to remove `{}`: {e}", claimed.display());
+                }
+            }
+            Err(e) => {
+                pgx::warning!(
+                    "unable to record failed PL/Rust compilation from `{}`: {e}",
+                    claimed.display()
+                );
+                let _ = std::fs::rename(&claimed, &path);
+            }
+        }
+    }
+}
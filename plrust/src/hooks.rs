@@ -233,7 +233,7 @@ fn lookup_func_lang(pg_proc_oid: pg_sys::Oid) -> eyre::Result<pg_sys::Oid> {
 }
 
 /// Returns [`pg_sys::Oid::INVALID`] if the `plrust` language isn't installed in the current database
-fn plrust_lang_oid() -> pg_sys::Oid {
+pub(crate) fn plrust_lang_oid() -> pg_sys::Oid {
     static PLRUST_LANG_NAME: &[u8] = b"plrust\0"; // want this to look like a c string
 
     unsafe {
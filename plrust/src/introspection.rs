@@ -0,0 +1,308 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! SQL functions for inspecting what PL/Rust has stored about `LANGUAGE plrust` functions
+use pgx::prelude::*;
+
+use crate::recompile::{function_signature, plrust_functions};
+use crate::user_crate::{advisories, Scope};
+use crate::{gucs, plrust, prosrc, user_crate};
+
+/// The content hashes recorded for each of the specified function's compiled artifacts.
+///
+/// Comparing these between servers tells you whether they're executing the same code, compiled the
+/// same way.  The hash columns are NULL for artifacts compiled before PL/Rust recorded them.
+#[pg_extern]
+fn artifact_hashes(
+    fn_oid: pg_sys::Oid,
+) -> TableIterator<
+    'static,
+    (
+        name!(target, String),
+        name!(so_sha256, Option<String>),
+        name!(src_sha256, Option<String>),
+        name!(build_inputs_sha256, Option<String>),
+        name!(toolchain, Option<String>),
+        name!(trusted_pgx_version, Option<String>),
+        name!(lockfile_sha256, Option<String>),
+    ),
+> {
+    match prosrc::artifact_hashes(fn_oid) {
+        Ok(hashes) => TableIterator::new(hashes.into_iter().map(|(target, hashes)| match hashes {
+            Some(hashes) => (
+                target.to_string(),
+                Some(hashes.so_sha256),
+                Some(hashes.src_sha256),
+                Some(hashes.build_inputs_sha256),
+                Some(hashes.build_inputs.toolchain),
+                Some(hashes.build_inputs.trusted_pgx_version),
+                hashes.build_inputs.lockfile_sha256,
+            ),
+            None => (target.to_string(), None, None, None, None, None, None),
+        })),
+        // Panic into the pgx guard.
+        Err(err) => panic!("{:?}", err),
+    }
+}
+
+/// The `Cargo.lock` the specified function was last compiled with, which it will be compiled with
+/// again.  NULL if it has never been compiled, or was compiled before PL/Rust kept lockfiles.
+#[pg_extern]
+fn lockfile(fn_oid: pg_sys::Oid) -> Option<String> {
+    match prosrc::lockfile(fn_oid) {
+        Ok(lockfile) => lockfile,
+        // Panic into the pgx guard.
+        Err(err) => panic!("{:?}", err),
+    }
+}
+
+/// How this backend's cache of loaded functions, bounded by `plrust.max_loaded_functions`, is doing
+#[pg_extern]
+fn loaded_functions_stats() -> TableIterator<
+    'static,
+    (
+        name!(loaded, i64),
+        name!(max_loaded, Option<i64>),
+        name!(hits, i64),
+        name!(misses, i64),
+        name!(evictions, i64),
+    ),
+> {
+    let (loaded, stats) = plrust::cache_stats();
+    TableIterator::new(std::iter::once((
+        loaded as i64,
+        gucs::max_loaded_functions().map(|max| max as i64),
+        stats.hits as i64,
+        stats.misses as i64,
+        stats.evictions as i64,
+    )))
+}
+
+/// What PL/Rust has stored about the specified function, one row per compilation target it was
+/// compiled for.  Returns no rows if the function has never been compiled.
+///
+/// `missing_lints` lists the lints in the current `plrust.required_lints` that the artifact wasn't
+/// compiled with.  PL/Rust refuses to load such an artifact.  `linted_dependencies` and
+/// `audited_dependencies` are NULL unless it was compiled with `plrust.dependency_lints`.
+#[pg_extern(sql = r#"
+CREATE FUNCTION function_info("fn" regprocedure) RETURNS TABLE (
+    "target" text,
+    "symbol" text,
+    "stored_bytes" int8,
+    "library_bytes" int8,
+    "lints" text[],
+    "missing_lints" text[],
+    "signed" bool,
+    "trusted_pgx_version" text,
+    "dependencies" text[],
+    "generation" int8,
+    "linted_dependencies" text[],
+    "audited_dependencies" text[]
+) STRICT LANGUAGE c AS 'MODULE_PATHNAME', '@FUNCTION_NAME@';
+"#)]
+fn function_info(
+    fn_oid: pg_sys::Oid,
+) -> TableIterator<
+    'static,
+    (
+        name!(target, String),
+        name!(symbol, Option<String>),
+        name!(stored_bytes, i64),
+        name!(library_bytes, i64),
+        name!(lints, Vec<String>),
+        name!(missing_lints, Vec<String>),
+        name!(signed, bool),
+        name!(trusted_pgx_version, String),
+        name!(dependencies, Vec<String>),
+        name!(generation, i64),
+        name!(linted_dependencies, Option<Vec<String>>),
+        name!(audited_dependencies, Option<Vec<String>>),
+    ),
+> {
+    let info = match prosrc::function_info(fn_oid) {
+        Ok(Some(info)) => info,
+        Ok(None) => return TableIterator::new(Vec::new().into_iter()),
+        // Panic into the pgx guard.
+        Err(err) => panic!("{:?}", err),
+    };
+
+    let dependencies = info
+        .dependencies
+        .iter()
+        .map(|(name, version)| format!("{name} = {version}"))
+        .collect::<Vec<_>>();
+    let rows = info
+        .artifacts
+        .into_iter()
+        .map(|artifact| {
+            let (linted, audited) = match artifact.dependency_lints {
+                Some(lints) => (Some(lints.linted), Some(lints.audited)),
+                None => (None, None),
+            };
+            (
+                artifact.target.to_string(),
+                artifact.symbol,
+                artifact.stored_bytes as i64,
+                artifact.library_bytes as i64,
+                artifact.lints.iter().collect(),
+                artifact.missing_lints.iter().collect(),
+                artifact.signed,
+                info.trusted_pgx_version.clone(),
+                dependencies.clone(),
+                // the bits are what matter, not the sign
+                info.generation as i64,
+                linted,
+                audited,
+            )
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(rows.into_iter())
+}
+
+extension_sql!(
+    r#"
+CREATE VIEW plrust.functions AS
+SELECT p.oid AS fn_oid,
+       p.oid::regprocedure AS function,
+       i.*
+  FROM pg_catalog.pg_proc p
+  JOIN pg_catalog.pg_language l ON l.oid = p.prolang
+  LEFT JOIN LATERAL plrust.function_info(p.oid) i ON true
+ WHERE l.lanname = 'plrust';
+"#,
+    name = "functions_view",
+    requires = [function_info]
+);
+
+/// The `plrust.allowed_dependencies` allow-list, with a row for each allowed version of each crate.
+/// `role` and `schema` are NULL for crates allowed to everyone, or name the role or schema the crate
+/// is allowed to.  No rows means there's no allow-list and every crate is allowed.
+#[pg_extern]
+fn allowed_dependencies() -> TableIterator<
+    'static,
+    (
+        name!(name, String),
+        name!(version, String),
+        name!(features, Vec<String>),
+        name!(forced_features, Vec<String>),
+        name!(forbidden_features, Vec<String>),
+        name!(default_features, Option<bool>),
+        name!(build_script_ok, bool),
+        name!(proc_macro_ok, bool),
+        name!(role, Option<String>),
+        name!(schema, Option<String>),
+    ),
+> {
+    type AllowedDependencyRow = (
+        String,
+        String,
+        Vec<String>,
+        Vec<String>,
+        Vec<String>,
+        Option<bool>,
+        bool,
+        bool,
+        Option<String>,
+        Option<String>,
+    );
+
+    fn allowed_dependencies_inner() -> eyre::Result<Vec<AllowedDependencyRow>> {
+        let Some(allowed) = gucs::allowed_dependencies()? else {
+            return Ok(vec![]);
+        };
+
+        let strings = |strs: &[&str]| strs.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut rows = Vec::new();
+        for (scope, crates) in user_crate::parse_allow_list(&allowed)? {
+            let (role, schema) = match scope {
+                Scope::Everyone => (None, None),
+                Scope::Role(role) => (Some(role.to_string()), None),
+                Scope::Schema(schema) => (None, Some(schema.to_string())),
+            };
+            for (name, versions) in crates {
+                for allowed in versions {
+                    rows.push((
+                        name.to_string(),
+                        allowed.version.to_string(),
+                        strings(&allowed.features),
+                        strings(&allowed.forced_features),
+                        strings(&allowed.forbidden_features),
+                        allowed.default_features,
+                        allowed.build_script_ok,
+                        allowed.proc_macro_ok,
+                        role.clone(),
+                        schema.clone(),
+                    ));
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    match allowed_dependencies_inner() {
+        Ok(rows) => TableIterator::new(rows.into_iter()),
+        // Panic into the pgx guard.
+        Err(err) => panic!("{:?}", err),
+    }
+}
+
+/// Check the dependencies recorded for every `LANGUAGE plrust` function in the current database
+/// against the current `plrust.advisory_db`, returning a row for each advisory that applies.  A
+/// function's recorded dependencies are those in the `Cargo.lock` it was last compiled with, or for
+/// a function compiled before PL/Rust kept lockfiles, those it pins to an exact version.
+#[pg_extern]
+fn rescan_advisories() -> TableIterator<
+    'static,
+    (
+        name!(fn_oid, pg_sys::Oid),
+        name!(signature, String),
+        name!(name, String),
+        name!(version, String),
+        name!(advisory, String),
+        name!(informational, Option<String>),
+        name!(title, Option<String>),
+    ),
+> {
+    type AdvisoryRow = (
+        pg_sys::Oid,
+        String,
+        String,
+        String,
+        String,
+        Option<String>,
+        Option<String>,
+    );
+
+    fn rescan_advisories_inner() -> eyre::Result<Vec<AdvisoryRow>> {
+        let Some(db) = gucs::advisory_db() else {
+            eyre::bail!("`plrust.advisory_db` is not set");
+        };
+
+        let mut rows = Vec::new();
+        for fn_oid in plrust_functions()? {
+            for finding in advisories::scan_function(&db, fn_oid)? {
+                rows.push((
+                    fn_oid,
+                    function_signature(fn_oid),
+                    finding.name,
+                    finding.version.to_string(),
+                    finding.id,
+                    finding.informational,
+                    finding.title,
+                ));
+            }
+        }
+        Ok(rows)
+    }
+
+    match rescan_advisories_inner() {
+        Ok(rows) => TableIterator::new(rows.into_iter()),
+        // Panic into the pgx guard.
+        Err(err) => panic!("{:?}", err),
+    }
+}
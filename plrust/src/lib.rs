@@ -38,10 +38,20 @@ mod plrust;
 
 mod user_crate;
 
+mod check;
+mod export;
+mod history;
 mod hooks;
+mod introspection;
 mod pgproc;
 mod prosrc;
+mod recompile;
+mod restore;
+mod signing;
+mod stats;
+mod storage;
 pub(crate) mod target;
+mod workers;
 
 #[cfg(any(test, feature = "pg_test"))]
 pub mod tests;
@@ -112,6 +122,8 @@ fn _PG_init() {
 
     gucs::init();
     hooks::init();
+    stats::init();
+    workers::init();
 
     use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
@@ -194,6 +206,12 @@ unsafe fn plrust_validator(fn_oid: pg_sys::Oid, fcinfo: pg_sys::FunctionCallInfo
         }
 
         unsafe { plrust::unload_function(fn_oid) };
+
+        // a function being restored might not need to be compiled right now
+        if restore::handled_by_restore_policy(fn_oid)? {
+            return Ok(());
+        }
+
         // NOTE:  We purposely ignore the `check_function_bodies` GUC for compilation as we need to
         // compile the function when it's created to avoid locking during function execution
         let output = plrust::compile_function(fn_oid)?;
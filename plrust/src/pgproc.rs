@@ -8,6 +8,7 @@ Use of this source code is governed by the PostgreSQL license that can be found
 
 use crate::error::PlRustError;
 use pgx::{pg_sys, FromDatum, IntoDatum, PgLogLevel, PgRelation, PgSqlErrorCode};
+use std::ffi::CStr;
 use std::ptr::NonNull;
 
 /// Provides a safe wrapper around a Postgres "SysCache" entry from `pg_catalog.pg_proc`.
@@ -106,6 +107,26 @@ impl PgProc {
         }
     }
 
+    pub(crate) fn pronamespace(&self) -> pg_sys::Oid {
+        // SAFETY:  `pronamespace` has a NOT NULL constraint
+        self.get_attr(pg_sys::Anum_pg_proc_pronamespace).unwrap()
+    }
+
+    /// The name of the function's schema, or `None` if it has since been dropped
+    pub(crate) fn namespace_name(&self) -> Option<String> {
+        // SAFETY:  `get_namespace_name` returns a palloc'd copy of the name, or NULL if the schema
+        // doesn't exist
+        unsafe {
+            let name = pg_sys::get_namespace_name(self.pronamespace());
+            (!name.is_null()).then(|| CStr::from_ptr(name).to_string_lossy().into_owned())
+        }
+    }
+
+    pub(crate) fn proowner(&self) -> pg_sys::Oid {
+        // SAFETY:  `proowner` has a NOT NULL constraint
+        self.get_attr(pg_sys::Anum_pg_proc_proowner).unwrap()
+    }
+
     pub(crate) fn prolang(&self) -> pg_sys::Oid {
         // SAFETY:  `prolang` has a NOT NULL constraint
         self.get_attr(pg_sys::Anum_pg_proc_prolang).unwrap()
@@ -7,24 +7,182 @@ All rights reserved.
 Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
 */
 
+use std::panic::AssertUnwindSafe;
 use std::rc::Rc;
-use std::{cell::RefCell, collections::HashMap, process::Output};
+use std::time::Instant;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    process::Output,
+};
 
-use eyre::WrapErr;
-use pgx::{pg_sys::FunctionCallInfo, pg_sys::MyDatabaseId, prelude::*};
+use pgx::{pg_sys::FunctionCallInfo, pg_sys::MyDatabaseId, prelude::*, IntoDatum, PgTryBuilder};
 
+use crate::error::PlRustError;
 use crate::pgproc::PgProc;
 use crate::{
-    gucs, prosrc,
+    gucs, history, prosrc, stats,
     user_crate::{FnReady, UserCrate},
 };
 
+/// A user function this backend has loaded
+pub(crate) struct LoadedFunction {
+    user_crate: Rc<UserCrate<FnReady>>,
+    /// The `PROCOID` syscache hash value of the function's Oid, which is all a syscache invalidation
+    /// tells us about which function changed
+    hash_value: u32,
+    /// Its `pg_proc` row might have changed since we loaded it
+    stale: bool,
+    /// The [`USE_CLOCK`] tick when the function was last called, for least-recently-used eviction
+    last_used: u64,
+}
+
+/// How well this backend's cache of loaded functions is working
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct CacheStats {
+    /// calls to an already loaded function
+    pub(crate) hits: u64,
+    /// calls that had to load (or reload) the function
+    pub(crate) misses: u64,
+    /// functions closed to stay within `plrust.max_loaded_functions`
+    pub(crate) evictions: u64,
+}
+
 thread_local! {
-    pub(crate) static LOADED_SYMBOLS: RefCell<HashMap<pg_sys::Oid, Rc<UserCrate<FnReady>>>> = Default::default();
+    pub(crate) static LOADED_SYMBOLS: RefCell<HashMap<pg_sys::Oid, LoadedFunction>> = Default::default();
+
+    /// Set when we couldn't tell which loaded functions an invalidation applies to, so all of them are suspect
+    static INVALIDATE_ALL: Cell<bool> = Cell::new(false);
+
+    /// Set when at least one loaded function was marked stale, and may have been dropped
+    static NEEDS_SWEEP: Cell<bool> = Cell::new(false);
+
+    /// Ticks once per function call
+    static USE_CLOCK: Cell<u64> = Cell::new(0);
+
+    static CACHE_STATS: Cell<CacheStats> = Cell::new(CacheStats::default());
+
+    /// Have we loaded `plrust.preload_functions` yet?
+    static PRELOADED: Cell<bool> = Cell::new(false);
 }
 
 pub(crate) fn init() {
-    ()
+    unsafe {
+        // SAFETY:  We're called from `_PG_init()` while Postgres is loading shared_preload_libraries,
+        // so the callback is registered before any backend could have loaded a function
+        pg_sys::CacheRegisterSyscacheCallback(
+            pg_sys::SysCacheIdentifier_PROCOID as _,
+            Some(invalidate_pg_proc),
+            pg_sys::Datum::from(0usize),
+        );
+    }
+}
+
+/// Called by Postgres whenever a `pg_catalog.pg_proc` row might have changed, or been deleted, in
+/// this or another session.  We can't look at the catalogs from here, so we only note which loaded
+/// functions are affected and leave the rest to [`evaluate_function`]
+#[pg_guard]
+unsafe extern "C" fn invalidate_pg_proc(
+    _arg: pg_sys::Datum,
+    _cache_id: std::os::raw::c_int,
+    hash_value: u32,
+) {
+    LOADED_SYMBOLS.with(|loaded_symbols| match loaded_symbols.try_borrow_mut() {
+        Ok(mut loaded_symbols) => {
+            for loaded in loaded_symbols.values_mut() {
+                // a zero hash value means the entire cache was reset
+                if hash_value == 0 || loaded.hash_value == hash_value {
+                    loaded.stale = true;
+                    NEEDS_SWEEP.with(|needs_sweep| needs_sweep.set(true));
+                }
+            }
+        }
+        // we're in the middle of (re)loading a function, and it's the catalog lookups for that which
+        // brought us here
+        Err(_) => INVALIDATE_ALL.with(|invalidate_all| invalidate_all.set(true)),
+    })
+}
+
+/// The `PROCOID` syscache hash value for the specified function
+fn pg_proc_hash_value(fn_oid: pg_sys::Oid) -> u32 {
+    unsafe {
+        // SAFETY:  computing the hash doesn't look at the cache's contents, and PROCOID has a single key
+        pg_sys::GetSysCacheHashValue(
+            pg_sys::SysCacheIdentifier_PROCOID as _,
+            fn_oid.into_datum().unwrap(),
+            pg_sys::Datum::from(0usize),
+            pg_sys::Datum::from(0usize),
+            pg_sys::Datum::from(0usize),
+        )
+    }
+}
+
+/// Best effort to close a function we're done with.  If it's still in use, or `dlclose()` fails,
+/// there's nothing we can do but carry on
+fn close_function(fn_oid: pg_sys::Oid, user_crate: Rc<UserCrate<FnReady>>) {
+    if let Ok(user_crate) = Rc::try_unwrap(user_crate) {
+        if let Err(e) = user_crate.close() {
+            tracing::warn!("Failed to close function {fn_oid}.  Ignoring: {e}");
+        }
+    }
+}
+
+/// Close every stale function whose `pg_proc` row no longer exists
+fn sweep_dropped_functions(loaded_symbols: &mut HashMap<pg_sys::Oid, LoadedFunction>) {
+    let dropped = loaded_symbols
+        .iter()
+        .filter(|(fn_oid, loaded)| loaded.stale && PgProc::new(**fn_oid).is_err())
+        .map(|(fn_oid, _)| *fn_oid)
+        .collect::<Vec<_>>();
+
+    for fn_oid in dropped {
+        if let Some(loaded) = loaded_symbols.remove(&fn_oid) {
+            tracing::trace!("Closing function {fn_oid} as it was dropped");
+            close_function(fn_oid, loaded.user_crate);
+        }
+    }
+}
+
+fn record_cache_stat(f: impl FnOnce(&mut CacheStats)) {
+    CACHE_STATS.with(|stats| {
+        let mut current = stats.get();
+        f(&mut current);
+        stats.set(current);
+    })
+}
+
+/// The number of functions this backend currently has loaded, and how well its cache of them is
+/// working
+pub(crate) fn cache_stats() -> (usize, CacheStats) {
+    let loaded = LOADED_SYMBOLS.with(|loaded_symbols| loaded_symbols.borrow().len());
+    (loaded, CACHE_STATS.with(Cell::get))
+}
+
+/// Close least-recently-used functions until there's room for one more under
+/// `plrust.max_loaded_functions`.  Functions that are currently executing (recursively, or further
+/// up the stack via SPI) are never closed, so we may end up over the limit
+fn evict_to_make_room(loaded_symbols: &mut HashMap<pg_sys::Oid, LoadedFunction>) {
+    let Some(max_loaded) = gucs::max_loaded_functions() else {
+        return;
+    };
+
+    while loaded_symbols.len() >= max_loaded {
+        let lru = loaded_symbols
+            .iter()
+            .filter(|(_, loaded)| Rc::strong_count(&loaded.user_crate) == 1)
+            .min_by_key(|(_, loaded)| loaded.last_used)
+            .map(|(fn_oid, _)| *fn_oid);
+
+        let Some(fn_oid) = lru else {
+            // everything is in use
+            return;
+        };
+        if let Some(loaded) = loaded_symbols.remove(&fn_oid) {
+            tracing::trace!("Evicting least-recently-used function {fn_oid}");
+            close_function(fn_oid, loaded.user_crate);
+            record_cache_stat(|stats| stats.evictions += 1);
+        }
+    }
 }
 
 #[tracing::instrument(level = "debug")]
@@ -32,61 +190,190 @@ pub(crate) unsafe fn unload_function(fn_oid: pg_sys::Oid) {
     LOADED_SYMBOLS.with(|loaded_symbols| {
         let mut loaded_symbols_handle = loaded_symbols.borrow_mut();
         let removed = loaded_symbols_handle.remove(&fn_oid);
-        if let Some(user_crate) = removed {
-            if let Ok(user_crate) = Rc::try_unwrap(user_crate) {
+        if let Some(loaded) = removed {
+            if let Ok(user_crate) = Rc::try_unwrap(loaded.user_crate) {
                 user_crate.close().unwrap();
             }
         }
     })
 }
 
-#[tracing::instrument(level = "debug")]
-pub(crate) unsafe fn evaluate_function(
-    fn_oid: pg_sys::Oid,
-    fcinfo: FunctionCallInfo,
-) -> eyre::Result<pg_sys::Datum> {
-    let user_crate_loaded = LOADED_SYMBOLS.with(|loaded_symbols| {
+/// The loaded version of the specified function, loading it if this backend hasn't already, or if
+/// it has changed since we did
+fn lookup_function(fn_oid: pg_sys::Oid) -> eyre::Result<Rc<UserCrate<FnReady>>> {
+    LOADED_SYMBOLS.with(|loaded_symbols| {
         let mut loaded_symbols_handle = loaded_symbols.borrow_mut();
 
+        if INVALIDATE_ALL.with(|invalidate_all| invalidate_all.replace(false)) {
+            for loaded in loaded_symbols_handle.values_mut() {
+                loaded.stale = true;
+            }
+            NEEDS_SWEEP.with(|needs_sweep| needs_sweep.set(true));
+        }
+        if NEEDS_SWEEP.with(|needs_sweep| needs_sweep.replace(false)) {
+            sweep_dropped_functions(&mut loaded_symbols_handle);
+        }
+
+        let now = USE_CLOCK.with(|clock| {
+            let now = clock.get() + 1;
+            clock.set(now);
+            now
+        });
+
         let user_crate_loaded = if let Some(current) = loaded_symbols_handle.get_mut(&fn_oid) {
-            let current_generation_number = PgProc::new(fn_oid)?.generation_number();
-
-            // `generation_number`` represents the transaction id and command id that inserted this 
-            // row (in this case into pg_catalog.pg_proc).  So if it's changed from the last time we 
-            // loaded the function then we have more work to do...
-            if current.generation_number() != current_generation_number {
-                // the function, which we've previously loaded, was changed by a concurrent session.
-                // This could be caused by (at least) the "OR REPLACE" bit of CREATE OR REPLACE or
-                // by an ALTER FUNCTION that changed one of the attributes of the function.
-                tracing::trace!(
-                    "Reloading function {fn_oid} due to change from concurrent session"
-                );
-
-                // load the new function
-                let new = prosrc::load(fn_oid)?;
-
-                // swap out the currently loaded function for the new one
-                let old = std::mem::replace(current, new);
-
-                // make a best effort to try and close the old loaded function.  If dlclose() fails,
-                // there's nothing we can do but carry on with the newly loaded version
-                if let Ok(old) = Rc::try_unwrap(old) {
-                    if let Err(e) = old.close() {
-                        tracing::warn!("Failed to close the old version of function {fn_oid}.  Ignoring, and continuing with new version: {e}");
-                    }
+            // the hot path is a function we've already loaded and whose `pg_proc` row hasn't been
+            // invalidated since
+            let mut reloaded = false;
+            if current.stale {
+                let current_generation_number = PgProc::new(fn_oid)?.generation_number();
+
+                // `generation_number`` represents the transaction id and command id that inserted this
+                // row (in this case into pg_catalog.pg_proc).  So if it's changed from the last time we
+                // loaded the function then we have more work to do...
+                if current.user_crate.generation_number() != current_generation_number {
+                    // the function, which we've previously loaded, was changed by a concurrent session.
+                    // This could be caused by (at least) the "OR REPLACE" bit of CREATE OR REPLACE or
+                    // by an ALTER FUNCTION that changed one of the attributes of the function.
+                    tracing::trace!(
+                        "Reloading function {fn_oid} due to change from concurrent session"
+                    );
+
+                    // load the new function
+                    let new = prosrc::load(fn_oid)?;
+
+                    // swap out the currently loaded function for the new one, and make a best effort
+                    // to close the old one
+                    let old = std::mem::replace(&mut current.user_crate, new);
+                    close_function(fn_oid, old);
+                    reloaded = true;
                 }
+                current.stale = false;
             }
 
+            record_cache_stat(|stats| {
+                if reloaded {
+                    stats.misses += 1
+                } else {
+                    stats.hits += 1
+                }
+            });
+            current.last_used = now;
             current
         } else {
             // loading the function for the first time
+            record_cache_stat(|stats| stats.misses += 1);
+            let user_crate = prosrc::load(fn_oid)?;
+            evict_to_make_room(&mut loaded_symbols_handle);
             loaded_symbols_handle
                 .entry(fn_oid)
-                .or_insert(prosrc::load(fn_oid)?)
+                .or_insert(LoadedFunction {
+                    user_crate,
+                    hash_value: pg_proc_hash_value(fn_oid),
+                    stale: false,
+                    last_used: now,
+                })
         };
 
-        Ok::<_, eyre::Error>(user_crate_loaded.clone())
-    })?;
+        Ok(user_crate_loaded.user_crate.clone())
+    })
+}
+
+/// Load the specified function ahead of it being called, so the first call doesn't pay for it
+pub(crate) fn warm_function(fn_oid: pg_sys::Oid) -> eyre::Result<()> {
+    let pg_proc = PgProc::new(fn_oid)?;
+    if pg_proc.prolang() != crate::hooks::plrust_lang_oid() {
+        return Err(PlRustError::NotPlRustFunction(fn_oid))?;
+    }
+
+    unsafe {
+        // SAFETY:  `pg_proc_aclcheck` only inspects the catalogs and the current session's user id
+        if pg_sys::pg_proc_aclcheck(fn_oid, pg_sys::GetUserId(), pg_sys::ACL_EXECUTE as _)
+            != pg_sys::AclResult_ACLCHECK_OK
+        {
+            return Err(PlRustError::NoExecutePermission(fn_oid))?;
+        }
+    }
+
+    lookup_function(fn_oid)?;
+    Ok(())
+}
+
+/// Load the specified functions into this backend ahead of them being called.  Functions that can't
+/// be loaded are reported in the returned rows rather than raised.
+#[pg_extern(sql = r#"
+CREATE FUNCTION warm("functions" regprocedure[]) RETURNS TABLE ("fn_oid" oid, "signature" text, "loaded" bool, "error" text)
+    STRICT LANGUAGE c AS 'MODULE_PATHNAME', '@FUNCTION_NAME@';
+"#)]
+fn warm(
+    functions: Vec<Option<pg_sys::Oid>>,
+) -> TableIterator<
+    'static,
+    (
+        name!(fn_oid, pg_sys::Oid),
+        name!(signature, String),
+        name!(loaded, bool),
+        name!(error, Option<String>),
+    ),
+> {
+    let results = functions
+        .into_iter()
+        .flatten()
+        .map(|fn_oid| {
+            let signature = crate::recompile::function_signature(fn_oid);
+            match warm_function(fn_oid) {
+                Ok(()) => (fn_oid, signature, true, None),
+                Err(e) => (fn_oid, signature, false, Some(e.to_string())),
+            }
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(results.into_iter())
+}
+
+/// Load the functions named by `plrust.preload_functions`, the first time this backend calls any
+/// PL/Rust function.  Functions that can't be loaded are reported and skipped
+fn preload_functions() {
+    if PRELOADED.with(|preloaded| preloaded.replace(true)) {
+        return;
+    }
+
+    for signature in gucs::preload_functions() {
+        let fn_oid = match Spi::get_one_with_args::<pg_sys::Oid>(
+            "SELECT pg_catalog.to_regprocedure($1)::oid",
+            vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                signature.as_str().into_datum(),
+            )],
+        ) {
+            Ok(Some(fn_oid)) => fn_oid,
+            Ok(None) => {
+                pgx::warning!("unable to preload `{signature}`: function does not exist");
+                continue;
+            }
+            Err(e) => {
+                pgx::warning!("unable to preload `{signature}`: {e}");
+                continue;
+            }
+        };
+
+        if let Err(e) = warm_function(fn_oid) {
+            pgx::warning!("unable to preload `{signature}`: {e}");
+        }
+    }
+}
+
+#[tracing::instrument(level = "debug")]
+pub(crate) unsafe fn evaluate_function(
+    fn_oid: pg_sys::Oid,
+    fcinfo: FunctionCallInfo,
+) -> eyre::Result<pg_sys::Datum> {
+    preload_functions();
+    let user_crate_loaded = match lookup_function(fn_oid) {
+        Ok(user_crate_loaded) => user_crate_loaded,
+        Err(e) => {
+            stats::record_error(fn_oid);
+            return Err(e);
+        }
+    };
 
     tracing::trace!(
         "Evaluating symbol {:?} for function {}",
@@ -94,11 +381,28 @@ pub(crate) unsafe fn evaluate_function(
         fn_oid
     );
 
-    Ok(unsafe { user_crate_loaded.evaluate(fcinfo) })
+    let start = Instant::now();
+    let datum = PgTryBuilder::new(AssertUnwindSafe(|| unsafe {
+        user_crate_loaded.evaluate(fcinfo)
+    }))
+    .catch_others(|e| {
+        stats::record_call(fn_oid, start.elapsed(), true);
+        e.rethrow()
+    })
+    .execute();
+    stats::record_call(fn_oid, start.elapsed(), false);
+    Ok(datum)
 }
 
 #[tracing::instrument(level = "debug")]
 pub(crate) fn compile_function(fn_oid: pg_sys::Oid) -> eyre::Result<Output> {
+    let compilation = history::Compilation::start(fn_oid);
+    let result = compile_function_inner(fn_oid);
+    compilation.finish(&result);
+    result
+}
+
+fn compile_function_inner(fn_oid: pg_sys::Oid) -> eyre::Result<Output> {
     let work_dir = gucs::work_dir();
     let target_dir = work_dir.join("target");
     // SAFETY: Postgres globally sets this to `const InvalidOid`, so is always read-safe,
@@ -106,6 +410,8 @@ pub(crate) fn compile_function(fn_oid: pg_sys::Oid) -> eyre::Result<Output> {
     let db_oid = unsafe { MyDatabaseId };
 
     let generated = unsafe { UserCrate::try_from_fn_oid(db_oid, fn_oid)? };
+    // cleanup after ourselves, however far the crate gets
+    let _crate_dir = generated.remove_crate_dir_on_drop(&work_dir);
     let provisioned = generated.provision(&work_dir)?;
     // We want to introduce validation here.
     let crate_dir = provisioned.crate_dir().to_path_buf();
@@ -116,44 +422,38 @@ pub(crate) fn compile_function(fn_oid: pg_sys::Oid) -> eyre::Result<Output> {
     assert!(target_builds.len() >= 1);
 
     let mut this_output = None;
+    let mut libraries = Vec::with_capacity(target_builds.len());
     for (built, output) in target_builds {
         if this_output.is_none() {
             this_output = Some(output)
         }
-        let (target_triple, shared_object, lints) = built.into_inner();
-
-        // store the shared objects in our table
-        prosrc::create_or_replace_function(db_oid, fn_oid, target_triple, shared_object, lints)?;
+        libraries.push(built.into_inner());
     }
 
-    // cleanup after ourselves
-    tracing::trace!("removing {}", crate_dir.display());
-    std::fs::remove_dir_all(&crate_dir).wrap_err(format!(
-        "Problem deleting temporary crate directory at '{}'",
-        crate_dir.display()
-    ))?;
+    // store the shared objects in our table, along with the lockfile they were built with
+    let lockfile = std::fs::read_to_string(crate_dir.join("Cargo.lock")).ok();
+    prosrc::create_or_replace_function(fn_oid, libraries, lockfile)?;
 
     Ok(this_output.unwrap())
 }
 
-/// Represents the generated name PL/Rust gives to the user's function (at least the one to which
-/// we apply a `#[pg_extern]` annotation).  When the user function shared library is loaded, this
-/// is the only symbol we access from the library.
-///
-/// The name itself doesn't matter, but we keep it closely tied to the Postgres `pg_proc` catalog
-/// entry by including the that Oid value along with the database's Oid value.
-pub(crate) fn symbol_name(db_oid: pg_sys::Oid, fn_oid: pg_sys::Oid) -> String {
+/// The name PL/Rust gave the user's function (at least the one to which we apply a `#[pg_extern]`
+/// annotation) before it named functions after their content (see `codegen::symbol_name`).
+/// Artifacts compiled back then don't record their symbol, so this is the one we look for in them.
+pub(crate) fn legacy_symbol_name(db_oid: pg_sys::Oid, fn_oid: pg_sys::Oid) -> String {
     format!("plrust_fn_oid_{}_{}", db_oid.as_u32(), fn_oid.as_u32())
 }
 
-/// Represents the name PL/Rust gives the crate we generate to hold the user's function.  For any
-/// given function we want this to be unique every time we generate a new crate for the function.
-/// This is coax platforms like MacOS into properly dlopen-ing the final .so.
+/// Represents the name of the directory, under `plrust.work_dir`, PL/Rust generates the crate
+/// holding the user's function in.  For any given function we want this to be unique every time
+/// we generate a new crate for the function, so that concurrent compilations don't trip over each
+/// other.
 ///
-/// The value here is based on the [`symbol_name`] name plus the specified `generation_number` for
-/// uniqueness.  It's up to the caller to make a generation number that is sufficiently unique
-/// for the specified (`db_oid`, `fn_oid`) pair.
-pub(crate) fn crate_name(
+/// The crate itself is named after the function's content, so that it compiles to the same bytes
+/// no matter which function, or which generation of it, it was generated for.  Only its directory
+/// is named after the (`db_oid`, `fn_oid`) pair and the specified `generation_number`.  It's up to
+/// the caller to make a generation number that is sufficiently unique for that pair.
+pub(crate) fn crate_dir_name(
     db_oid: pg_sys::Oid,
     fn_oid: pg_sys::Oid,
     generation_number: u64,
@@ -166,5 +466,9 @@ pub(crate) fn crate_name(
     //
     // This also drastically un-complicates what we'd otherwise have to do when cross-compiling for
     // multiple targets.
-    format!("{}_{}", symbol_name(db_oid, fn_oid), generation_number)
+    format!(
+        "{}_{}",
+        legacy_symbol_name(db_oid, fn_oid),
+        generation_number
+    )
 }
@@ -11,34 +11,57 @@ use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::io::prelude::*;
 use std::rc::Rc;
+use std::time::Instant;
 
 use base64::Engine;
-use flate2::read::{GzDecoder, GzEncoder};
-use flate2::Compression;
+use flate2::read::GzDecoder;
 use pgx::pg_sys;
 use pgx::pg_sys::MyDatabaseId;
 use pgx::prelude::PgHeapTuple;
 use serde::{Deserialize, Serialize};
 
 use crate::error::PlRustError;
-use crate::gucs::get_trusted_pgx_version;
+use crate::gucs::{artifact_encoding, artifact_storage, get_trusted_pgx_version};
 use crate::pgproc::PgProc;
+use crate::stats;
+use crate::storage;
+use crate::storage::StorageMode;
 use crate::target;
 use crate::target::CompilationTarget;
-use crate::user_crate::lint::LintSet;
-use crate::user_crate::{FnReady, UserCrate};
-
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
-enum Encoding {
-    GzBase64,
-}
+use crate::user_crate::lint::{compile_lints, required_lints, LintSet};
+use crate::user_crate::{
+    sha256_hex, BuildInputs, CompiledArtifact, DependencyLints, Encoding, FnReady, UserCrate,
+    BASE64_ENGINE,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SharedLibrary {
     encoding: Encoding,
     symbol: Option<String>,
     encoded: String,
+    /// size of the decoded shared library.  Missing for artifacts compiled before we started
+    /// recording it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    library_bytes: Option<usize>,
     lints: LintSet,
+    /// see [`crate::signing`].  Missing when compiled without `plrust.artifact_signing_key`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    /// Missing for artifacts compiled before we started recording them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hashes: Option<ArtifactHashes>,
+}
+
+/// Content hashes that identify exactly what went into, and came out of, compiling an artifact
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ArtifactHashes {
+    /// SHA-256 of the uncompressed shared library.  Checked every time the artifact is decoded
+    pub(crate) so_sha256: String,
+    /// SHA-256 of the user-provided `LANGUAGE plrust` source code
+    pub(crate) src_sha256: String,
+    /// SHA-256 of `build_inputs`
+    pub(crate) build_inputs_sha256: String,
+    pub(crate) build_inputs: BuildInputs,
 }
 
 struct CompiledSharedLibrary {
@@ -47,20 +70,24 @@ struct CompiledSharedLibrary {
 }
 
 impl SharedLibrary {
-    const CUSTOM_ENGINE: base64::engine::GeneralPurpose = base64::engine::GeneralPurpose::new(
-        &base64::alphabet::URL_SAFE,
-        base64::engine::general_purpose::NO_PAD,
-    );
-
-    fn new(symbol: String, so_bytes: Vec<u8>, lints: LintSet) -> eyre::Result<Self> {
-        let mut gz = GzEncoder::new(&so_bytes[..], Compression::best());
-        let mut compressed_bytes = Vec::new();
-        gz.read_to_end(&mut compressed_bytes)?;
+    fn new(src: &str, artifact: CompiledArtifact) -> eyre::Result<Self> {
+        let so_bytes = artifact.shared_object;
+        let hashes = artifact.build_inputs.map(|build_inputs| ArtifactHashes {
+            so_sha256: sha256_hex(&so_bytes),
+            src_sha256: sha256_hex(src.as_bytes()),
+            build_inputs_sha256: build_inputs.sha256(),
+            build_inputs,
+        });
+
+        let encoding = artifact_encoding()?;
         Ok(SharedLibrary {
-            encoding: Encoding::GzBase64,
-            symbol: Some(symbol),
-            encoded: Self::CUSTOM_ENGINE.encode(compressed_bytes),
-            lints,
+            encoding,
+            symbol: artifact.symbol,
+            encoded: encoding.encode(&so_bytes)?,
+            library_bytes: Some(so_bytes.len()),
+            lints: artifact.lints,
+            signature: artifact.signature,
+            hashes,
         })
     }
 
@@ -68,10 +95,14 @@ impl SharedLibrary {
         match self.encoding {
             Encoding::GzBase64 => {
                 let mut bytes = Vec::new();
-                let b64_decoded = Self::CUSTOM_ENGINE.decode(&self.encoded)?;
+                let b64_decoded = BASE64_ENGINE.decode(&self.encoded)?;
                 GzDecoder::new(&b64_decoded[..]).read_to_end(&mut bytes)?;
                 Ok(bytes)
             }
+            Encoding::ZstdBase64 => {
+                let b64_decoded = BASE64_ENGINE.decode(&self.encoded)?;
+                Ok(zstd::stream::decode_all(&b64_decoded[..])?)
+            }
         }
     }
 }
@@ -84,16 +115,18 @@ struct ProSrcEntry {
     /// the `plrust-trusted-pgx` crate used to compile this function
     trusted_pgx_version: String,
 
+    /// the Rust signature of the generated function, see [`crate::user_crate::function_signature`].
+    /// Missing for functions compiled before we started recording it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    function: Option<String>,
+
     /// A map of compiled artifacts per compilation target (ie, x86_64, aarch64)
     lib: BTreeMap<CompilationTarget, SharedLibrary>,
-}
-
-impl TryFrom<&PgProc> for ProSrcEntry {
-    type Error = serde_json::Error;
 
-    fn try_from(pg_proc: &PgProc) -> Result<Self, Self::Error> {
-        serde_json::from_str::<ProSrcEntry>(&pg_proc.prosrc())
-    }
+    /// the `Cargo.lock` the function's dependencies were resolved to, which it's compiled with
+    /// again next time.  Missing for functions compiled before we started keeping it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lockfile: Option<String>,
 }
 
 impl TryFrom<&str> for ProSrcEntry {
@@ -111,6 +144,27 @@ impl Into<String> for ProSrcEntry {
 }
 
 impl ProSrcEntry {
+    /// The stored entry for the specified function, wherever `plrust.artifact_storage` put it, or
+    /// `None` if the function has never been compiled.
+    ///
+    /// An entry in `plrust.artifacts` is only used if it was stored for the current generation of
+    /// the function's `pg_proc` row and compiled from its current source code.  A stale entry means
+    /// the function needs to be compiled again.
+    fn lookup(fn_oid: pg_sys::Oid, pg_proc: &PgProc) -> eyre::Result<Option<ProSrcEntry>> {
+        let prosrc = pg_proc.prosrc();
+        if let Ok(entry) = ProSrcEntry::try_from(prosrc.as_str()) {
+            return Ok(Some(entry));
+        }
+
+        match storage::read_entry(fn_oid, pg_proc.generation_number())? {
+            Some(stored) => {
+                let entry = ProSrcEntry::try_from(stored.as_str())?;
+                Ok((entry.src == prosrc).then_some(entry))
+            }
+            None => Ok(None),
+        }
+    }
+
     fn decode_shared_library(
         &mut self,
         compilation_target: &CompilationTarget,
@@ -120,13 +174,51 @@ impl ProSrcEntry {
             .remove(compilation_target)
             .ok_or_else(|| PlRustError::FunctionNotCompiledForTarget(compilation_target.clone()))?;
 
+        let bytes = shared_library.decode()?;
+        if let Some(hashes) = &shared_library.hashes {
+            // the recorded input hashes are what's compared across servers, so they must describe
+            // what's actually recorded alongside them
+            if sha256_hex(&bytes) != hashes.so_sha256
+                || sha256_hex(self.src.as_bytes()) != hashes.src_sha256
+                || hashes.build_inputs.sha256() != hashes.build_inputs_sha256
+            {
+                return Err(PlRustError::ArtifactHashMismatch(
+                    compilation_target.clone(),
+                ))?;
+            }
+        }
+
         Ok(CompiledSharedLibrary {
-            bytes: shared_library.decode()?,
+            bytes,
             metadata: shared_library,
         })
     }
 }
 
+/// The `Cargo.lock` from the specified function's last compilation, if we kept one.  It's used even
+/// if the function's source has since changed, as `cargo` only updates what it has to
+pub(crate) fn stored_lockfile(
+    fn_oid: pg_sys::Oid,
+    pg_proc: &PgProc,
+) -> eyre::Result<Option<String>> {
+    if let Ok(entry) = ProSrcEntry::try_from(pg_proc.prosrc().as_str()) {
+        return Ok(entry.lockfile);
+    }
+
+    match storage::read_latest_entry(fn_oid)? {
+        Some(stored) => Ok(ProSrcEntry::try_from(stored.as_str())?.lockfile),
+        None => Ok(None),
+    }
+}
+
+/// The `Cargo.lock` the specified function was last compiled with, or `None` if it's never been
+/// compiled or was compiled before PL/Rust started keeping lockfiles
+#[tracing::instrument(level = "debug")]
+pub(crate) fn lockfile(fn_oid: pg_sys::Oid) -> eyre::Result<Option<String>> {
+    let pg_proc = PgProc::new(fn_oid)?;
+    Ok(ProSrcEntry::lookup(fn_oid, &pg_proc)?.and_then(|entry| entry.lockfile))
+}
+
 /// Given an arbitrary string, suss out how to treat it as source code.  If it's JSON that matches
 /// the structure of our [`ProSrcEntry`] struct, then we return its `src` property, throwing everything
 /// else away.
@@ -143,35 +235,302 @@ pub(crate) fn maybe_extract_source_from_json(code: &str) -> Cow<str> {
     }
 }
 
-/// Update the entry for the specified function in `pg_catalog.pg_proc.prosrc` to include the compiled
-/// `so_bytes` mapped to the specified `target_triple`
+/// Why a function's stored artifacts no longer match what this server would compile today
+#[derive(Debug, Clone)]
+pub(crate) enum StaleReason {
+    /// `pg_proc.prosrc` isn't our json, so the function has never been (successfully) compiled
+    NotCompiled,
+    /// compiled with a different `plrust-trusted-pgx` version than is currently configured
+    TrustedPgxVersion { compiled: String, current: String },
+    /// the artifact for `target` was not compiled with all of the currently configured lints
+    MissingLints {
+        target: CompilationTarget,
+        lints: LintSet,
+    },
+    /// there's no artifact for one of the configured compilation targets
+    MissingTarget(CompilationTarget),
+    /// `plrust.artifact_signing_key` is configured, but the artifact for `target` isn't signed
+    Unsigned(CompilationTarget),
+}
+
+impl std::fmt::Display for StaleReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StaleReason::NotCompiled => write!(f, "not compiled"),
+            StaleReason::TrustedPgxVersion { compiled, current } => write!(
+                f,
+                "compiled with plrust-trusted-pgx `{compiled}`, current is `{current}`"
+            ),
+            StaleReason::MissingLints { target, lints } => {
+                write!(f, "`{target}` artifact is missing lints: {lints}")
+            }
+            StaleReason::MissingTarget(target) => write!(f, "no artifact for target `{target}`"),
+            StaleReason::Unsigned(target) => write!(f, "`{target}` artifact is not signed"),
+        }
+    }
+}
+
+/// Compare the artifacts stored for the specified function against the current configuration and
+/// return every reason they're out of date.  An empty result means the function is up-to-date.
+#[tracing::instrument(level = "debug")]
+pub(crate) fn stale_reasons(fn_oid: pg_sys::Oid) -> eyre::Result<Vec<StaleReason>> {
+    let pg_proc = PgProc::new(fn_oid)?;
+    let entry = match ProSrcEntry::lookup(fn_oid, &pg_proc)? {
+        Some(entry) => entry,
+        None => return Ok(vec![StaleReason::NotCompiled]),
+    };
+
+    let mut reasons = Vec::new();
+    let current_pgx_version = get_trusted_pgx_version();
+    if entry.trusted_pgx_version != current_pgx_version {
+        reasons.push(StaleReason::TrustedPgxVersion {
+            compiled: entry.trusted_pgx_version.clone(),
+            current: current_pgx_version,
+        });
+    }
+
+    let mut wanted_lints = compile_lints();
+    wanted_lints.append(&mut required_lints());
+    for (target, library) in &entry.lib {
+        let missing = wanted_lints
+            .difference(&library.lints)
+            .cloned()
+            .collect::<LintSet>();
+        if !missing.is_empty() {
+            reasons.push(StaleReason::MissingLints {
+                target: target.clone(),
+                lints: missing,
+            });
+        }
+
+        if crate::signing::signing_enabled() && library.signature.is_none() {
+            reasons.push(StaleReason::Unsigned(target.clone()));
+        }
+    }
+
+    let (this_target, other_targets) = crate::gucs::compilation_targets()?;
+    let targets = std::iter::once(this_target.clone()).chain(other_targets.map(|t| t.target()));
+    for target in targets {
+        if !entry.lib.contains_key(&target) {
+            reasons.push(StaleReason::MissingTarget(target));
+        }
+    }
+
+    Ok(reasons)
+}
+
+/// The recorded [`ArtifactHashes`] of each of the specified function's compiled artifacts.  An
+/// artifact's hashes are `None` if it was compiled before PL/Rust started recording them.
 #[tracing::instrument(level = "debug")]
+pub(crate) fn artifact_hashes(
+    fn_oid: pg_sys::Oid,
+) -> eyre::Result<Vec<(CompilationTarget, Option<ArtifactHashes>)>> {
+    let pg_proc = PgProc::new(fn_oid)?;
+    let entry =
+        ProSrcEntry::lookup(fn_oid, &pg_proc)?.ok_or(PlRustError::FunctionNotCompiled(fn_oid))?;
+    Ok(entry
+        .lib
+        .into_iter()
+        .map(|(target, library)| (target, library.hashes))
+        .collect())
+}
+
+/// What's stored about one of a function's compiled artifacts
+pub(crate) struct ArtifactInfo {
+    pub(crate) target: CompilationTarget,
+    pub(crate) symbol: Option<String>,
+    /// size of the artifact as stored, compressed and encoded
+    pub(crate) stored_bytes: usize,
+    /// size of the artifact's shared library
+    pub(crate) library_bytes: usize,
+    pub(crate) lints: LintSet,
+    /// lints in the current `plrust.required_lints` that the artifact wasn't compiled with
+    pub(crate) missing_lints: LintSet,
+    pub(crate) signed: bool,
+    /// `None` unless compiled with `plrust.dependency_lints`
+    pub(crate) dependency_lints: Option<DependencyLints>,
+}
+
+/// What's stored about a compiled function
+pub(crate) struct FunctionInfo {
+    pub(crate) generation: u64,
+    pub(crate) trusted_pgx_version: String,
+    pub(crate) dependencies: toml::value::Table,
+    pub(crate) artifacts: Vec<ArtifactInfo>,
+}
+
+/// Everything stored about the specified function's compiled artifacts, or `None` if it's never
+/// been compiled
+#[tracing::instrument(level = "debug")]
+pub(crate) fn function_info(fn_oid: pg_sys::Oid) -> eyre::Result<Option<FunctionInfo>> {
+    let pg_proc = PgProc::new(fn_oid)?;
+    let Some(entry) = ProSrcEntry::lookup(fn_oid, &pg_proc)? else {
+        return Ok(None);
+    };
+
+    let required_lints = required_lints();
+    let mut artifacts = Vec::with_capacity(entry.lib.len());
+    for (target, library) in entry.lib {
+        artifacts.push(ArtifactInfo {
+            target,
+            stored_bytes: library.encoded.len(),
+            library_bytes: match library.library_bytes {
+                Some(library_bytes) => library_bytes,
+                None => library.decode()?.len(),
+            },
+            missing_lints: required_lints.difference(&library.lints).cloned().collect(),
+            signed: library.signature.is_some(),
+            dependency_lints: library
+                .hashes
+                .and_then(|hashes| hashes.build_inputs.dependency_lints),
+            symbol: library.symbol,
+            lints: library.lints,
+        });
+    }
+
+    Ok(Some(FunctionInfo {
+        generation: pg_proc.generation_number(),
+        trusted_pgx_version: entry.trusted_pgx_version,
+        dependencies: crate::user_crate::declared_dependencies(&entry.src)?,
+        artifacts,
+    }))
+}
+
+/// Is the specified function's `prosrc` our json rather than plain source code?
+pub(crate) fn prosrc_is_entry(fn_oid: pg_sys::Oid) -> eyre::Result<bool> {
+    let pg_proc = PgProc::new(fn_oid)?;
+    Ok(ProSrcEntry::try_from(pg_proc.prosrc().as_str()).is_ok())
+}
+
+/// Can the artifacts in the specified function's `prosrc` json, presumably fed back to us by a
+/// restore, be loaded as-is?
+///
+/// Only a superuser's restore is trusted.  The entry must have been compiled for the function as
+/// it's being restored -- the same argument and return types, `STRICT`ness and `SETOF` -- against
+/// the currently configured `plrust-trusted-pgx` version, with every required lint, and its
+/// dependencies checked against the `plrust.allowed_dependencies` that apply to it here.  The
+/// artifact for this host must match its recorded hashes.  With `plrust.artifact_signing_key`
+/// configured, the artifact must also carry a valid signature over all of that.  Without one, its
+/// recorded hashes only prove the artifact is intact, not where it came from.
+#[tracing::instrument(level = "debug")]
+pub(crate) fn restored_artifacts_verify(fn_oid: pg_sys::Oid) -> eyre::Result<bool> {
+    // SAFETY:  `superuser()` only inspects the current session's user id
+    if !unsafe { pg_sys::superuser() } {
+        return Ok(false);
+    }
+
+    let pg_proc = PgProc::new(fn_oid)?;
+    let Ok(mut entry) = ProSrcEntry::try_from(pg_proc.prosrc().as_str()) else {
+        // not our json, so there's nothing to trust
+        return Ok(false);
+    };
+    if entry.trusted_pgx_version != get_trusted_pgx_version()
+        || entry.function != Some(crate::user_crate::function_signature(&pg_proc)?)
+    {
+        return Ok(false);
+    }
+
+    let this_target = target::tuple()?;
+    let Ok(so) = entry.decode_shared_library(this_target) else {
+        return Ok(false);
+    };
+    let Some(hashes) = &so.metadata.hashes else {
+        return Ok(false);
+    };
+    if hashes.build_inputs.trusted_pgx_version != entry.trusted_pgx_version
+        || !required_lints().is_subset(&so.metadata.lints)
+    {
+        return Ok(false);
+    }
+    // the function's dependencies must have been checked against the allow-list that applies to it
+    // here, which the signature vouches for
+    if let Some(allow_list_sha256) = crate::user_crate::allow_list_sha256(&pg_proc)? {
+        if hashes.build_inputs.allow_list_sha256.as_ref() != Some(&allow_list_sha256) {
+            return Ok(false);
+        }
+    }
+
+    if crate::signing::signing_enabled() {
+        let claims = crate::user_crate::claims(&pg_proc, &entry.src, entry.trusted_pgx_version)?;
+        Ok(crate::signing::verify(
+            this_target,
+            so.metadata.symbol.as_deref(),
+            &so.metadata.lints,
+            &claims,
+            Some(&hashes.build_inputs),
+            &so.bytes,
+            so.metadata.signature.as_deref(),
+        )
+        .is_ok())
+    } else {
+        Ok(true)
+    }
+}
+
+/// Throw away whatever artifacts are stored in the specified function's `prosrc`, leaving only its
+/// source code.  The function can't be executed until it's compiled again.
+#[tracing::instrument(level = "debug")]
+pub(crate) fn reset_to_source(fn_oid: pg_sys::Oid) -> eyre::Result<()> {
+    let pg_proc = PgProc::new(fn_oid)?;
+    let src = maybe_extract_source_from_json(&pg_proc.prosrc()).into_owned();
+    update_prosrc(&pg_proc, src)
+}
+
+/// Store the compiled artifact for each of the specified compilation targets, along with the
+/// `Cargo.lock` they were built with, as the specified function's entry, either in
+/// `pg_catalog.pg_proc.prosrc` or in `plrust.artifacts`, depending on `plrust.artifact_storage`.
+///
+/// Any previously compiled artifacts are thrown away -- the new entry only contains what was given
+/// to us, compiled with the current `plrust-trusted-pgx` version.
+#[tracing::instrument(level = "debug", skip(libraries, lockfile))]
 pub(crate) fn create_or_replace_function(
-    db_oid: pg_sys::Oid,
     fn_oid: pg_sys::Oid,
-    target_triple: CompilationTarget,
-    so_bytes: Vec<u8>,
-    lints: LintSet,
+    libraries: Vec<CompiledArtifact>,
+    lockfile: Option<String>,
 ) -> eyre::Result<()> {
     let pg_proc = PgProc::new(fn_oid)?;
-    let mut entry = ProSrcEntry::try_from(&pg_proc).unwrap_or_else(|_| {
-        // the pg_proc.prosrc didn't parse as json, so assume it's just the raw function source code
-        // likely means it's the first time this function is being CREATEd
-        ProSrcEntry {
-            src: pg_proc.prosrc(),
-            lib: Default::default(),
-            trusted_pgx_version: get_trusted_pgx_version(),
+    // with `prosrc` storage, `prosrc` is our json.  Otherwise it's either the function's source code
+    // from its `CREATE FUNCTION`, or it was compiled with `plrust.artifact_storage = 'table'`
+    let stored_in_prosrc = ProSrcEntry::try_from(pg_proc.prosrc().as_str()).is_ok();
+    let mut entry = ProSrcEntry {
+        // the pg_proc.prosrc might be our json from a previous compilation, or it could be the raw
+        // function source code if this is the first time the function is being CREATEd
+        src: maybe_extract_source_from_json(&pg_proc.prosrc()).into_owned(),
+        lib: Default::default(),
+        trusted_pgx_version: get_trusted_pgx_version(),
+        function: Some(crate::user_crate::function_signature(&pg_proc)?),
+        lockfile,
+    };
+
+    for artifact in libraries {
+        entry.lib.insert(
+            artifact.target.clone(),
+            SharedLibrary::new(&entry.src, artifact)?,
+        );
+    }
+
+    match artifact_storage()? {
+        StorageMode::Prosrc => {
+            update_prosrc(&pg_proc, entry.into())?;
+            if !stored_in_prosrc {
+                // in case the function was previously compiled with `plrust.artifact_storage = 'table'`
+                storage::delete_entry(fn_oid)?;
+            }
         }
-    });
-
-    // always replace any existing bytes for the specified target_triple.  we only trust
-    // what was given to us
-    let symbol_name = crate::plrust::symbol_name(db_oid, fn_oid);
-    entry.lib.insert(
-        target_triple,
-        SharedLibrary::new(symbol_name, so_bytes, lints)?,
-    );
+        StorageMode::Table => {
+            // even if `prosrc` is already the plain source code, rewriting the row gives the
+            // function a new generation number, which is how other backends learn they need to
+            // reload it
+            update_prosrc(&pg_proc, entry.src.clone())?;
+            let generation_number = PgProc::new(fn_oid)?.generation_number();
+            storage::write_entry(fn_oid, generation_number, entry.into())?;
+        }
+    }
+    Ok(())
+}
 
+/// Replace the specified function's `pg_catalog.pg_proc.prosrc` with `prosrc_value`
+fn update_prosrc(pg_proc: &PgProc, prosrc_value: String) -> eyre::Result<()> {
     let mut ctid = pg_proc.ctid();
     let relation = PgProc::relation();
     let tupdesc = relation.tuple_desc();
@@ -182,7 +541,6 @@ pub(crate) fn create_or_replace_function(
         PgHeapTuple::from_heap_tuple(tupdesc, pg_proc.heap_tuple())
     }
     .into_owned();
-    let prosrc_value: String = entry.into();
     heap_tuple.set_by_name("prosrc", prosrc_value)?;
 
     // TODO:  [`pgx::PgHeapTuple`] really needs a `.into_pg() -> *mut pg_sys::HeapTupleData` function.
@@ -204,15 +562,19 @@ pub(crate) fn create_or_replace_function(
     Ok(())
 }
 
-/// Dynamically load the shared library stored in `pg_catalog.pg_proc.prosrc` for the specified `pg_proc_oid`
+/// Dynamically load the shared library stored for the specified `pg_proc_oid`
 /// procedure object id and the `target_triple` of the host.
 #[tracing::instrument(level = "debug")]
 pub(crate) fn load(pg_proc_oid: pg_sys::Oid) -> eyre::Result<Rc<UserCrate<FnReady>>> {
     tracing::debug!("loading function oid `{pg_proc_oid}`");
+    let start = Instant::now();
     let pg_proc = PgProc::new(pg_proc_oid)?;
-    let mut entry = ProSrcEntry::try_from(&pg_proc)?;
+    let mut entry = ProSrcEntry::lookup(pg_proc_oid, &pg_proc)?
+        .ok_or(PlRustError::FunctionNotCompiled(pg_proc_oid))?;
     let this_target = target::tuple()?;
     let so = entry.decode_shared_library(this_target)?;
+    let library_bytes = so.bytes.len();
+    let claims = crate::user_crate::claims(&pg_proc, &entry.src, entry.trusted_pgx_version)?;
 
     // SAFETY: Postgres globally sets this to `const InvalidOid`, so is always read-safe,
     // then writes it only during initialization, so we should not be racing anyone.
@@ -228,9 +590,13 @@ pub(crate) fn load(pg_proc_oid: pg_sys::Oid) -> eyre::Result<Rc<UserCrate<FnRead
         so.metadata.symbol,
         so.bytes,
         so.metadata.lints,
+        claims,
+        so.metadata.signature,
+        so.metadata.hashes.map(|hashes| hashes.build_inputs),
     );
     let validated = unsafe { built.validate()? };
     let loaded = unsafe { validated.load()? };
+    stats::record_load(pg_proc_oid, start.elapsed(), library_bytes);
 
     // all good
     Ok(Rc::new(loaded))
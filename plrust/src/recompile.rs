@@ -0,0 +1,195 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Finding, and recompiling, `LANGUAGE plrust` functions whose stored artifacts are out of date
+//! with respect to the current PL/Rust configuration.
+//!
+//! This is mainly useful after upgrading PL/Rust, changing `plrust.trusted_pgx_version`, adding
+//! lints to `plrust.compile_lints`/`plrust.required_lints`, or adding `plrust.compilation_targets`.
+use std::ffi::CStr;
+use std::panic::AssertUnwindSafe;
+
+use pgx::prelude::*;
+use pgx::PgTryBuilder;
+
+use crate::error::PlRustError;
+use crate::{plrust, prosrc};
+
+/// Returns every `LANGUAGE plrust` function in the current database whose stored artifacts are out
+/// of date, along with the reasons why.
+#[pg_extern]
+fn stale_functions() -> TableIterator<
+    'static,
+    (
+        name!(fn_oid, pg_sys::Oid),
+        name!(signature, String),
+        name!(reasons, Vec<String>),
+    ),
+> {
+    fn stale_functions_inner() -> eyre::Result<Vec<(pg_sys::Oid, String, Vec<String>)>> {
+        let mut stale = Vec::new();
+        for fn_oid in plrust_functions()? {
+            let reasons = prosrc::stale_reasons(fn_oid)?;
+            if !reasons.is_empty() {
+                stale.push((
+                    fn_oid,
+                    function_signature(fn_oid),
+                    reasons.iter().map(|r| r.to_string()).collect(),
+                ));
+            }
+        }
+        Ok(stale)
+    }
+
+    match stale_functions_inner() {
+        Ok(stale) => TableIterator::new(stale.into_iter()),
+        // Panic into the pgx guard.
+        Err(err) => panic!("{:?}", err),
+    }
+}
+
+/// Recompile, in place, every `LANGUAGE plrust` function in the current database that
+/// [`stale_functions`] considers out of date.
+///
+/// Each function is recompiled in its own subtransaction so that one failure doesn't prevent the
+/// others from being recompiled.  Failures are reported in the returned rows rather than raised.
+///
+/// With `dry_run`, nothing is compiled and the returned rows describe what would have been done.
+#[pg_extern]
+fn recompile_functions(
+    dry_run: default!(bool, false),
+) -> TableIterator<
+    'static,
+    (
+        name!(fn_oid, pg_sys::Oid),
+        name!(signature, String),
+        name!(status, String),
+        name!(message, Option<String>),
+    ),
+> {
+    fn recompile_functions_inner(
+        dry_run: bool,
+    ) -> eyre::Result<Vec<(pg_sys::Oid, String, String, Option<String>)>> {
+        // SAFETY:  `superuser()` only inspects the current session's user id
+        if !unsafe { pg_sys::superuser() } {
+            return Err(PlRustError::RecompileRequiresSuperuser)?;
+        }
+
+        let mut stale = Vec::new();
+        for fn_oid in plrust_functions()? {
+            let reasons = prosrc::stale_reasons(fn_oid)?;
+            if !reasons.is_empty() {
+                stale.push((fn_oid, reasons));
+            }
+        }
+
+        let total = stale.len();
+        let mut results = Vec::with_capacity(total);
+        for (idx, (fn_oid, reasons)) in stale.into_iter().enumerate() {
+            let signature = function_signature(fn_oid);
+            let reasons = reasons
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>()
+                .join("; ");
+
+            if dry_run {
+                results.push((fn_oid, signature, "stale".into(), Some(reasons)));
+                continue;
+            }
+
+            pgx::notice!("recompiling {}/{total}: {signature} ({reasons})", idx + 1);
+            match recompile_function(fn_oid) {
+                Ok(()) => results.push((fn_oid, signature, "recompiled".into(), None)),
+                Err(e) => {
+                    pgx::warning!("failed to recompile {signature}: {e}");
+                    results.push((fn_oid, signature, "failed".into(), Some(format!("{e:?}"))))
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
+    match recompile_functions_inner(dry_run) {
+        Ok(results) => TableIterator::new(results.into_iter()),
+        // Panic into the pgx guard.
+        Err(err) => panic!("{:?}", err),
+    }
+}
+
+/// Recompile the specified function in its own subtransaction, replacing its stored artifacts
+pub(crate) fn recompile_function(fn_oid: pg_sys::Oid) -> eyre::Result<()> {
+    in_subtransaction(|| {
+        unsafe {
+            // SAFETY:  we're about to replace this function's artifacts, so whatever this backend
+            // might have loaded is no longer what we want to execute
+            plrust::unload_function(fn_oid);
+        }
+        plrust::compile_function(fn_oid)?;
+        Ok(())
+    })
+}
+
+/// Run `f` inside an internal subtransaction.  If `f` returns an error, or raises a Postgres ERROR,
+/// the subtransaction is rolled back and the error is returned.  Otherwise the subtransaction is
+/// committed into the current transaction.
+pub(crate) fn in_subtransaction<R>(f: impl FnOnce() -> eyre::Result<R>) -> eyre::Result<R> {
+    unsafe {
+        // SAFETY:  These are the same steps plpgsql takes to run an `EXCEPTION` block.  We must
+        // be in a transaction (we're called from SQL), and we restore the memory context and
+        // resource owner that were current when we started, regardless of the outcome
+        let memory_context = pg_sys::CurrentMemoryContext;
+        let resource_owner = pg_sys::CurrentResourceOwner;
+
+        pg_sys::BeginInternalSubTransaction(std::ptr::null());
+        let result = PgTryBuilder::new(AssertUnwindSafe(f))
+            .catch_others(|e| Err(eyre::eyre!("{:?}", e)))
+            .execute();
+
+        if result.is_ok() {
+            pg_sys::ReleaseCurrentSubTransaction();
+        } else {
+            pg_sys::RollbackAndReleaseCurrentSubTransaction();
+        }
+
+        pg_sys::MemoryContextSwitchTo(memory_context);
+        pg_sys::CurrentResourceOwner = resource_owner;
+        result
+    }
+}
+
+/// All the `LANGUAGE plrust` functions in the current database
+pub(crate) fn plrust_functions() -> eyre::Result<Vec<pg_sys::Oid>> {
+    let oids = Spi::connect(|client| {
+        client
+            .select(
+                "SELECT p.oid
+                   FROM pg_catalog.pg_proc p
+                   JOIN pg_catalog.pg_language l ON l.oid = p.prolang
+                  WHERE l.lanname = 'plrust'
+               ORDER BY p.oid",
+                None,
+                None,
+            )?
+            .map(|row| row.get::<pg_sys::Oid>(1))
+            .collect::<spi::Result<Vec<_>>>()
+    })?;
+    Ok(oids.into_iter().flatten().collect())
+}
+
+/// The human-readable `name(argtypes)` signature of a function, as `regprocedure` would display it
+pub(crate) fn function_signature(fn_oid: pg_sys::Oid) -> String {
+    unsafe {
+        // SAFETY:  `format_procedure` always returns a palloc'd, null-terminated string, even for
+        // an Oid that doesn't exist
+        CStr::from_ptr(pg_sys::format_procedure(fn_oid))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
@@ -0,0 +1,180 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! What to do when a function is created with our `prosrc` json, which is what `pg_dump` emits for
+//! a `LANGUAGE plrust` function.
+//!
+//! By default the validator recompiles every such function as it's restored, which, for a database
+//! with many functions, can make `pg_restore` take hours.  `plrust.restore_policy` can instead have
+//! the validator keep the restored artifacts, when they verify, or defer compiling them to the
+//! database's background worker, which is started by the restore and compiles them once it has
+//! committed.  See [`crate::workers`].
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::time::Duration;
+
+use pgx::bgworkers::{BackgroundWorker, SignalWakeFlags};
+use pgx::prelude::*;
+use pgx::{register_xact_callback, PgXactCallbackEvent};
+
+use crate::gucs;
+use crate::prosrc::{self, StaleReason};
+use crate::recompile;
+use crate::workers::{self, WorkerKind};
+
+/// How often the background worker checks whether it has been told about newly restored functions
+const WORKER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The top-level transaction that's holding this database's background worker, so it only does so
+/// once however many functions it restores
+static mut WORKER_HELD_BY: Option<pg_sys::TransactionId> = None;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum RestorePolicy {
+    /// throw away the restored artifacts and compile the function as it's restored
+    Recompile,
+    /// keep the restored artifacts if they verify, otherwise compile the function as it's restored
+    Trust,
+    /// throw away the restored artifacts and have a background worker compile the function once
+    /// the restore has committed
+    Background,
+}
+
+impl FromStr for RestorePolicy {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "recompile" => Ok(RestorePolicy::Recompile),
+            "trust" => Ok(RestorePolicy::Trust),
+            "background" => Ok(RestorePolicy::Background),
+            other => eyre::bail!(
+                "unrecognized `plrust.restore_policy` value `{other}`.  Expected `recompile`, `trust`, or `background`"
+            ),
+        }
+    }
+}
+
+/// Called by the validator before it compiles the specified function.  If the function is being
+/// restored, apply `plrust.restore_policy` and return `true` if the validator should *not* compile
+/// it.
+pub(crate) fn handled_by_restore_policy(fn_oid: pg_sys::Oid) -> eyre::Result<bool> {
+    if !prosrc::prosrc_is_entry(fn_oid)? {
+        // just source code, so this is a regular CREATE FUNCTION
+        return Ok(false);
+    }
+
+    match gucs::restore_policy()? {
+        RestorePolicy::Recompile => Ok(false),
+        RestorePolicy::Trust => Ok(prosrc::restored_artifacts_verify(fn_oid)?),
+        RestorePolicy::Background => {
+            // the worker compiles as a superuser and only logs what fails, so only a superuser's
+            // restore may skip having the function compiled and checked here
+            // SAFETY:  `superuser()` only inspects the current session's user id
+            if !unsafe { pg_sys::superuser() } {
+                return Ok(false);
+            }
+            prosrc::reset_to_source(fn_oid)?;
+            hold_worker_until_commit()?;
+            Ok(true)
+        }
+    }
+}
+
+/// Make sure this database's background worker is running, and stays running until the current
+/// transaction ends.  Once it commits, the worker compiles the functions it restored
+fn hold_worker_until_commit() -> eyre::Result<()> {
+    // SAFETY:  we're restoring a function, so we're in a transaction
+    let xid = unsafe { pg_sys::GetTopTransactionId() };
+    unsafe {
+        // SAFETY:  backends are single-threaded, so nothing else is accessing this static
+        if WORKER_HELD_BY == Some(xid) {
+            return Ok(());
+        }
+        WORKER_HELD_BY = Some(xid);
+    }
+
+    // registered first so that the hold is released even if taking it fails part way
+    register_xact_callback(PgXactCallbackEvent::Commit, || {
+        workers::release(WorkerKind::Restore, true)
+    });
+    register_xact_callback(PgXactCallbackEvent::Abort, || {
+        workers::release(WorkerKind::Restore, false)
+    });
+    workers::hold(WorkerKind::Restore)
+}
+
+/// Compiles the functions in its database that were restored with `plrust.restore_policy = background`.
+/// It keeps going while any restore that's holding it is still in progress, and exits once it has
+/// compiled everything it can.  Functions that fail to compile are reported and skipped.
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn plrust_restore_worker_main(_arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+    let dbname = BackgroundWorker::get_extra();
+    BackgroundWorker::connect_worker_to_spi(Some(dbname), None);
+    workers::attach(WorkerKind::Restore);
+
+    let mut failed = BTreeSet::new();
+    // whatever held us may have committed before we attached
+    let mut look = true;
+    while BackgroundWorker::wait_latch(Some(WORKER_POLL_INTERVAL)) {
+        look |= workers::take_pending(WorkerKind::Restore);
+        if look {
+            let pending = BackgroundWorker::transaction(uncompiled_functions);
+            let pending = match pending {
+                Ok(pending) => pending,
+                Err(e) => {
+                    pgx::warning!("unable to find PL/Rust functions to compile in `{dbname}`: {e}");
+                    break;
+                }
+            };
+
+            let mut compiled_any = false;
+            for fn_oid in pending
+                .into_iter()
+                .filter(|fn_oid| !failed.contains(fn_oid))
+            {
+                BackgroundWorker::transaction(|| {
+                    let signature = recompile::function_signature(fn_oid);
+                    match recompile::recompile_function(fn_oid) {
+                        Ok(()) => pgx::log!("compiled restored PL/Rust function {signature}"),
+                        Err(e) => {
+                            pgx::warning!(
+                                "failed to compile restored PL/Rust function {signature}: {e}"
+                            );
+                            failed.insert(fn_oid);
+                        }
+                    }
+                });
+                compiled_any = true;
+            }
+
+            // look again in case more were restored while we were compiling
+            look = compiled_any;
+        }
+
+        if !look && workers::finish(WorkerKind::Restore) {
+            break;
+        }
+    }
+}
+
+/// The `LANGUAGE plrust` functions in this database that have never been compiled
+fn uncompiled_functions() -> eyre::Result<Vec<pg_sys::Oid>> {
+    let mut uncompiled = Vec::new();
+    for fn_oid in recompile::plrust_functions()? {
+        if prosrc::stale_reasons(fn_oid)?
+            .iter()
+            .any(|reason| matches!(reason, StaleReason::NotCompiled))
+        {
+            uncompiled.push(fn_oid);
+        }
+    }
+    Ok(uncompiled)
+}
@@ -0,0 +1,138 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Signing and verification of compiled PL/Rust artifacts.
+//!
+//! When the `plrust.artifact_signing_key` GUC names a key file, every artifact PL/Rust compiles is
+//! signed with an HMAC-SHA256 over the artifact's bytes and the metadata we trust when loading it:
+//! its compilation target, entry symbol, the lints it was compiled with, and its recorded
+//! [`BuildInputs`], plus the [`Claims`] it makes about the function it was compiled for.  Before an
+//! artifact is loaded the signature is verified against the function as it's currently defined,
+//! and artifacts that are unsigned, or whose signature doesn't match, are refused.  The message
+//! format is shared with `plrust-compile`, see [`SignedMessage`].
+//!
+//! The entry symbol is derived from the function's signature and source code, not its Oids, so a
+//! restored function's artifact still verifies as long as the restored cluster is configured with
+//! the same key, and the function still has the same signature and source code.  Artifacts compiled
+//! before PL/Rust named functions after their content embed the Oids they were compiled for in
+//! their symbol, and are loaded by their recorded symbol all the same.
+use std::path::Path;
+
+use base64::Engine;
+use eyre::WrapErr;
+use hmac::Mac;
+
+use crate::error::PlRustError;
+use crate::gucs::PLRUST_ARTIFACT_SIGNING_KEY;
+use crate::target::CompilationTarget;
+use crate::user_crate::artifact_format::{read_signing_key, SignedMessage};
+use crate::user_crate::lint::LintSet;
+use crate::user_crate::{sha256_hex, BuildInputs};
+
+/// What an artifact's signature vouches for about the function it was compiled for.  When loading
+/// an artifact these come from the function as it's currently defined, so an artifact can't be
+/// loaded into a function it wasn't compiled for
+#[derive(Debug, Clone)]
+pub(crate) struct Claims {
+    /// the generated function's Rust signature, which reflects its argument and return types, and
+    /// whether it's `STRICT` or returns a `SETOF`
+    function: String,
+    /// SHA-256 of the user-provided `LANGUAGE plrust` source code
+    src_sha256: String,
+    /// the `plrust-trusted-pgx` version the function was compiled against
+    trusted_pgx_version: String,
+}
+
+impl Claims {
+    pub(crate) fn new(function: String, src: &str, trusted_pgx_version: String) -> Self {
+        Claims {
+            function,
+            src_sha256: sha256_hex(src.as_bytes()),
+            trusted_pgx_version,
+        }
+    }
+}
+
+/// Is `plrust.artifact_signing_key` configured?
+pub(crate) fn signing_enabled() -> bool {
+    PLRUST_ARTIFACT_SIGNING_KEY.get().is_some()
+}
+
+/// Sign the artifact with the configured key.  Returns `None` if no key is configured.
+pub(crate) fn sign(
+    target: &CompilationTarget,
+    symbol: Option<&str>,
+    lints: &LintSet,
+    claims: &Claims,
+    build_inputs: Option<&BuildInputs>,
+    so_bytes: &[u8],
+) -> eyre::Result<Option<String>> {
+    let Some(key) = signing_key()? else {
+        return Ok(None);
+    };
+    let message = message(target, symbol, lints, claims, build_inputs, so_bytes);
+    Ok(Some(message.sign(&key)))
+}
+
+/// Verify the artifact's `signature` against the configured key.  If no key is configured, every
+/// artifact is accepted.
+pub(crate) fn verify(
+    target: &CompilationTarget,
+    symbol: Option<&str>,
+    lints: &LintSet,
+    claims: &Claims,
+    build_inputs: Option<&BuildInputs>,
+    so_bytes: &[u8],
+    signature: Option<&str>,
+) -> eyre::Result<()> {
+    let Some(key) = signing_key()? else {
+        return Ok(());
+    };
+    let Some(signature) = signature else {
+        return Err(PlRustError::UnsignedArtifact(target.clone()))?;
+    };
+    let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| PlRustError::InvalidArtifactSignature(target.clone()))?;
+
+    message(target, symbol, lints, claims, build_inputs, so_bytes)
+        .mac(&key)
+        .verify_slice(&signature)
+        .map_err(|_| PlRustError::InvalidArtifactSignature(target.clone()))?;
+    Ok(())
+}
+
+fn signing_key() -> eyre::Result<Option<Vec<u8>>> {
+    let Some(path) = PLRUST_ARTIFACT_SIGNING_KEY.get() else {
+        return Ok(None);
+    };
+    read_signing_key(Path::new(path))
+        .wrap_err("Invalid `plrust.artifact_signing_key`")
+        .map(Some)
+}
+
+/// What we sign for the artifact.  The artifact itself is represented by its digest
+fn message(
+    target: &CompilationTarget,
+    symbol: Option<&str>,
+    lints: &LintSet,
+    claims: &Claims,
+    build_inputs: Option<&BuildInputs>,
+    so_bytes: &[u8],
+) -> SignedMessage {
+    SignedMessage {
+        target: target.to_string(),
+        symbol: symbol.unwrap_or_default().to_string(),
+        lints: lints.to_string(),
+        function: claims.function.clone(),
+        src_sha256: claims.src_sha256.clone(),
+        trusted_pgx_version: claims.trusted_pgx_version.clone(),
+        build_inputs_sha256: build_inputs.map(BuildInputs::sha256).unwrap_or_default(),
+        so_sha256: sha256_hex(so_bytes),
+    }
+}
@@ -0,0 +1,238 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Per-function execution statistics, kept in shared memory so they're visible from every backend
+//! through the `plrust.stat_functions` view.
+//!
+//! The statistics live in a fixed-capacity map sized when the postmaster starts.  Each function's
+//! counters are atomics, so recording a call only needs the map's shared lock, and the exclusive
+//! lock is only taken to start tracking a function.  Once the map is full, the least-called
+//! function is forgotten to make room, and a dropped function is forgotten along with it.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use pgx::prelude::*;
+use pgx::{pg_shmem_init, PGXSharedMemory, PgLwLock};
+
+/// How many functions, across every database, we can keep statistics for
+const MAX_TRACKED_FUNCTIONS: usize = 1024;
+
+static STATS: PgLwLock<heapless::FnvIndexMap<StatsKey, FunctionStats, MAX_TRACKED_FUNCTIONS>> =
+    PgLwLock::new();
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct StatsKey {
+    db_oid: u32,
+    fn_oid: u32,
+}
+
+impl StatsKey {
+    fn new(fn_oid: pg_sys::Oid) -> Self {
+        // SAFETY: Postgres globally sets this to `const InvalidOid`, so is always read-safe,
+        // then writes it only during initialization, so we should not be racing anyone.
+        let db_oid = unsafe { pg_sys::MyDatabaseId };
+        StatsKey {
+            db_oid: db_oid.as_u32(),
+            fn_oid: fn_oid.as_u32(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct FunctionStats {
+    /// times the function has been called
+    calls: AtomicU64,
+    /// calls that raised an ERROR from the user's code, including panics
+    panics: AtomicU64,
+    /// calls that failed because PL/Rust couldn't load the function
+    errors: AtomicU64,
+    total_time_us: AtomicU64,
+    max_time_us: AtomicU64,
+    /// times a backend has loaded the function's shared library
+    loads: AtomicU64,
+    total_load_time_us: AtomicU64,
+    /// size of the most recently loaded shared library
+    library_bytes: AtomicU64,
+}
+
+unsafe impl PGXSharedMemory for FunctionStats {}
+
+pub(crate) fn init() {
+    pg_shmem_init!(STATS);
+}
+
+/// Record a call to the specified function that took `elapsed`.  `raised` is whether the user's
+/// code raised an ERROR
+pub(crate) fn record_call(fn_oid: pg_sys::Oid, elapsed: Duration, raised: bool) {
+    let elapsed = elapsed.as_micros() as u64;
+    update(fn_oid, |stats| {
+        stats.calls.fetch_add(1, Ordering::Relaxed);
+        stats.panics.fetch_add(raised as u64, Ordering::Relaxed);
+        stats.total_time_us.fetch_add(elapsed, Ordering::Relaxed);
+        stats.max_time_us.fetch_max(elapsed, Ordering::Relaxed);
+    })
+}
+
+/// Record a call to the specified function that failed before reaching the user's code
+pub(crate) fn record_error(fn_oid: pg_sys::Oid) {
+    update(fn_oid, |stats| {
+        stats.calls.fetch_add(1, Ordering::Relaxed);
+        stats.errors.fetch_add(1, Ordering::Relaxed);
+    })
+}
+
+/// Record that loading the specified function's shared library, of `library_bytes`, took `elapsed`
+pub(crate) fn record_load(fn_oid: pg_sys::Oid, elapsed: Duration, library_bytes: usize) {
+    update(fn_oid, |stats| {
+        stats.loads.fetch_add(1, Ordering::Relaxed);
+        stats
+            .total_load_time_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        stats
+            .library_bytes
+            .store(library_bytes as u64, Ordering::Relaxed);
+    })
+}
+
+fn update(fn_oid: pg_sys::Oid, f: impl FnOnce(&FunctionStats)) {
+    let key = StatsKey::new(fn_oid);
+    {
+        let map = STATS.share();
+        if let Some(stats) = map.get(&key) {
+            f(stats);
+            return;
+        }
+    }
+
+    let mut map = STATS.exclusive();
+    // another backend may have started tracking the function while we were waiting for the lock
+    if let Some(stats) = map.get(&key) {
+        f(stats);
+        return;
+    }
+
+    if map.len() == map.capacity() {
+        let least_called = map
+            .iter()
+            .min_by_key(|(_, stats)| stats.calls.load(Ordering::Relaxed))
+            .map(|(key, _)| *key);
+        if let Some(least_called) = least_called {
+            map.remove(&least_called);
+        }
+    }
+    let stats = FunctionStats::default();
+    f(&stats);
+    let _ = map.insert(key, stats);
+}
+
+fn micros_to_millis(us: u64) -> f64 {
+    us as f64 / 1000.0
+}
+
+/// The raw statistics for every tracked function, in every database.  Use the
+/// `plrust.stat_functions` view instead.
+#[pg_extern]
+fn function_stats() -> TableIterator<
+    'static,
+    (
+        name!(db_oid, pg_sys::Oid),
+        name!(fn_oid, pg_sys::Oid),
+        name!(calls, i64),
+        name!(panics, i64),
+        name!(errors, i64),
+        name!(total_time, f64),
+        name!(max_time, f64),
+        name!(loads, i64),
+        name!(load_time, f64),
+        name!(library_bytes, i64),
+    ),
+> {
+    let rows = STATS
+        .share()
+        .iter()
+        .map(|(key, stats)| {
+            (
+                pg_sys::Oid::from(key.db_oid),
+                pg_sys::Oid::from(key.fn_oid),
+                stats.calls.load(Ordering::Relaxed) as i64,
+                stats.panics.load(Ordering::Relaxed) as i64,
+                stats.errors.load(Ordering::Relaxed) as i64,
+                micros_to_millis(stats.total_time_us.load(Ordering::Relaxed)),
+                micros_to_millis(stats.max_time_us.load(Ordering::Relaxed)),
+                stats.loads.load(Ordering::Relaxed) as i64,
+                micros_to_millis(stats.total_load_time_us.load(Ordering::Relaxed)),
+                stats.library_bytes.load(Ordering::Relaxed) as i64,
+            )
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(rows.into_iter())
+}
+
+/// Forget the statistics for the specified function, or for every function in the current database
+/// when it's NULL
+#[pg_extern]
+fn stat_reset(fn_oid: default!(Option<pg_sys::Oid>, "NULL")) {
+    let mut map = STATS.exclusive();
+    match fn_oid {
+        Some(fn_oid) => {
+            map.remove(&StatsKey::new(fn_oid));
+        }
+        None => {
+            let db_oid = StatsKey::new(pg_sys::Oid::INVALID).db_oid;
+            let keys = map
+                .keys()
+                .filter(|key| key.db_oid == db_oid)
+                .copied()
+                .collect::<Vec<_>>();
+            for key in keys {
+                map.remove(&key);
+            }
+        }
+    }
+}
+
+extension_sql!(
+    r#"
+CREATE VIEW plrust.stat_functions AS
+SELECT s.fn_oid,
+       n.nspname AS schemaname,
+       p.proname AS funcname,
+       s.calls,
+       s.panics,
+       s.errors,
+       s.total_time,
+       s.max_time,
+       s.loads,
+       s.load_time,
+       s.library_bytes
+  FROM plrust.function_stats() s
+  JOIN pg_catalog.pg_proc p ON p.oid = s.fn_oid
+  JOIN pg_catalog.pg_namespace n ON n.oid = p.pronamespace
+ WHERE s.db_oid = (SELECT oid FROM pg_catalog.pg_database WHERE datname = pg_catalog.current_database());
+
+REVOKE ALL ON FUNCTION plrust.stat_reset(oid) FROM PUBLIC;
+
+CREATE FUNCTION plrust.drop_function_stats() RETURNS event_trigger
+    LANGUAGE plpgsql
+    SECURITY DEFINER
+    SET search_path = pg_catalog
+AS $$
+BEGIN
+    PERFORM plrust.stat_reset(d.objid)
+       FROM pg_catalog.pg_event_trigger_dropped_objects() d
+      WHERE d.classid = 'pg_catalog.pg_proc'::regclass;
+END;
+$$;
+REVOKE ALL ON FUNCTION plrust.drop_function_stats() FROM PUBLIC;
+
+CREATE EVENT TRIGGER plrust_drop_function_stats ON sql_drop
+    EXECUTE FUNCTION plrust.drop_function_stats();
+"#,
+    name = "stat_functions_view",
+    requires = [function_stats, stat_reset]
+);
@@ -0,0 +1,238 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Where compiled artifacts are stored.
+//!
+//! By default PL/Rust replaces a function's `pg_catalog.pg_proc.prosrc` with a json document holding
+//! both the user's source code and the compiled artifacts (see [`crate::prosrc`]).  With
+//! `plrust.artifact_storage = 'table'`, `prosrc` keeps only the user's source code and that same json
+//! document is instead stored in the extension-owned `plrust.artifacts` table.
+//!
+//! `plrust.artifacts` isn't accessible to regular users -- otherwise anyone could replace a
+//! function's artifact with their own -- so we read and write it as the table's owner.  Its contents
+//! aren't included in `pg_dump` output as they're keyed by function Oid, which doesn't survive a
+//! restore.  Restored functions are recompiled by the validator, just like they are with `prosrc`
+//! storage.
+//!
+//! An entry is keyed by the function's Oid and the generation of its `pg_proc` row when it was
+//! compiled, and is only used while the function is still at that generation.  Statements that
+//! rewrite the row without touching its source code (`ALTER FUNCTION ... RENAME`, `OWNER TO`,
+//! `GRANT`, etc) move the entry along to the new generation, so they don't leave the function
+//! uncompiled.
+use std::str::FromStr;
+
+use pgx::prelude::*;
+use pgx::IntoDatum;
+
+use crate::error::PlRustError;
+
+extension_sql!(
+    r#"
+CREATE TABLE plrust.artifacts (
+    -- the function these artifacts were compiled for
+    fn_oid oid NOT NULL,
+    -- the generation of the function's pg_proc row when it was compiled
+    generation int8 NOT NULL,
+    -- the same json document PL/Rust would otherwise have stored in pg_proc.prosrc
+    entry text NOT NULL,
+    PRIMARY KEY (fn_oid, generation)
+);
+REVOKE ALL ON plrust.artifacts FROM PUBLIC;
+
+CREATE FUNCTION plrust.drop_artifacts() RETURNS event_trigger
+    LANGUAGE plpgsql
+    SECURITY DEFINER
+    SET search_path = pg_catalog
+AS $$
+BEGIN
+    DELETE FROM plrust.artifacts a
+     USING pg_catalog.pg_event_trigger_dropped_objects() d
+     WHERE d.classid = 'pg_catalog.pg_proc'::regclass
+       AND a.fn_oid = d.objid;
+END;
+$$;
+REVOKE ALL ON FUNCTION plrust.drop_artifacts() FROM PUBLIC;
+
+CREATE EVENT TRIGGER plrust_drop_artifacts ON sql_drop
+    EXECUTE FUNCTION plrust.drop_artifacts();
+
+-- Moves the entries of functions whose pg_proc row was rewritten without changing their source
+-- code along to the row's new generation.  This must compute the same thing as
+-- `PgProc::generation_number()`
+CREATE FUNCTION plrust.rekey_artifacts() RETURNS event_trigger
+    LANGUAGE plpgsql
+    SECURITY DEFINER
+    SET search_path = pg_catalog
+AS $$
+BEGIN
+    UPDATE plrust.artifacts a
+       SET generation = (p.xmin::text::int8 << 32) | p.cmin::text::int8
+      FROM pg_catalog.pg_proc p
+     WHERE p.oid = a.fn_oid
+       AND a.generation <> ((p.xmin::text::int8 << 32) | p.cmin::text::int8)
+       AND a.entry::jsonb ->> 'src' = p.prosrc;
+END;
+$$;
+REVOKE ALL ON FUNCTION plrust.rekey_artifacts() FROM PUBLIC;
+
+CREATE EVENT TRIGGER plrust_rekey_artifacts ON ddl_command_end
+    WHEN TAG IN ('ALTER FUNCTION', 'ALTER ROUTINE', 'GRANT', 'REVOKE')
+    EXECUTE FUNCTION plrust.rekey_artifacts();
+"#,
+    name = "artifacts_table",
+);
+
+/// Where newly compiled artifacts are stored
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum StorageMode {
+    /// in `pg_catalog.pg_proc.prosrc`, alongside the user's source code
+    Prosrc,
+    /// in the `plrust.artifacts` table
+    Table,
+}
+
+impl FromStr for StorageMode {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "prosrc" => Ok(StorageMode::Prosrc),
+            "table" => Ok(StorageMode::Table),
+            other => eyre::bail!(
+                "unrecognized `plrust.artifact_storage` value `{other}`.  Expected `prosrc` or `table`"
+            ),
+        }
+    }
+}
+
+/// The stored json entry for the specified function at the specified generation of its `pg_proc`
+/// row, if it has one in `plrust.artifacts`.  An entry from any other generation is stale and isn't
+/// returned
+pub(crate) fn read_entry(fn_oid: pg_sys::Oid, generation: u64) -> eyre::Result<Option<String>> {
+    if !table_exists("plrust.artifacts")? {
+        return Ok(None);
+    }
+    as_table_owner("plrust.artifacts", || {
+        Ok(Spi::get_one_with_args::<String>(
+            "SELECT entry FROM plrust.artifacts WHERE fn_oid = $1 AND generation = $2",
+            vec![
+                (PgBuiltInOids::OIDOID.oid(), fn_oid.into_datum()),
+                // the bits are what matter, not the sign
+                (
+                    PgBuiltInOids::INT8OID.oid(),
+                    (generation as i64).into_datum(),
+                ),
+            ],
+        )?)
+    })
+}
+
+/// The most recently stored json entry for the specified function, whatever generation it was
+/// compiled at.  Only for what carries over from one compilation to the next, like its `Cargo.lock`
+pub(crate) fn read_latest_entry(fn_oid: pg_sys::Oid) -> eyre::Result<Option<String>> {
+    if !table_exists("plrust.artifacts")? {
+        return Ok(None);
+    }
+    as_table_owner("plrust.artifacts", || {
+        Ok(Spi::get_one_with_args::<String>(
+            "SELECT entry FROM plrust.artifacts WHERE fn_oid = $1 ORDER BY generation DESC LIMIT 1",
+            vec![(PgBuiltInOids::OIDOID.oid(), fn_oid.into_datum())],
+        )?)
+    })
+}
+
+/// Store `entry` as the specified function's artifacts at the specified generation, replacing
+/// whatever was there for any generation
+pub(crate) fn write_entry(fn_oid: pg_sys::Oid, generation: u64, entry: String) -> eyre::Result<()> {
+    if !table_exists("plrust.artifacts")? {
+        return Err(PlRustError::ArtifactsTableMissing)?;
+    }
+    delete_entry(fn_oid)?;
+    as_table_owner("plrust.artifacts", || {
+        Spi::run_with_args(
+            "INSERT INTO plrust.artifacts (fn_oid, generation, entry) VALUES ($1, $2, $3)",
+            Some(vec![
+                (PgBuiltInOids::OIDOID.oid(), fn_oid.into_datum()),
+                // the bits are what matter, not the sign
+                (
+                    PgBuiltInOids::INT8OID.oid(),
+                    (generation as i64).into_datum(),
+                ),
+                (PgBuiltInOids::TEXTOID.oid(), entry.into_datum()),
+            ]),
+        )?;
+        Ok(())
+    })
+}
+
+/// Forget any artifacts stored in `plrust.artifacts` for the specified function
+pub(crate) fn delete_entry(fn_oid: pg_sys::Oid) -> eyre::Result<()> {
+    if !table_exists("plrust.artifacts")? {
+        return Ok(());
+    }
+    as_table_owner("plrust.artifacts", || {
+        Spi::run_with_args(
+            "DELETE FROM plrust.artifacts WHERE fn_oid = $1",
+            Some(vec![(PgBuiltInOids::OIDOID.oid(), fn_oid.into_datum())]),
+        )?;
+        Ok(())
+    })
+}
+
+/// Does the extension-owned, schema-qualified `table` exist?  A table added by a later version of
+/// the extension doesn't until `ALTER EXTENSION plrust UPDATE` is run in the database, and until then
+/// whatever PL/Rust keeps in it is simply skipped
+pub(crate) fn table_exists(table: &str) -> eyre::Result<bool> {
+    let (schema, name) = table
+        .split_once('.')
+        .ok_or_else(|| eyre::eyre!("`{table}` isn't schema-qualified"))?;
+    Ok(Spi::get_one_with_args::<bool>(
+        "SELECT EXISTS (
+             SELECT 1
+               FROM pg_catalog.pg_class c
+               JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+              WHERE n.nspname = $1 AND c.relname = $2
+         )",
+        vec![
+            (PgBuiltInOids::TEXTOID.oid(), schema.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), name.into_datum()),
+        ],
+    )?
+    .unwrap_or_default())
+}
+
+/// Run `f` with the owner of the extension-owned `table` as the current user
+pub(crate) fn as_table_owner<R>(
+    table: &str,
+    f: impl FnOnce() -> eyre::Result<R>,
+) -> eyre::Result<R> {
+    let owner = Spi::get_one_with_args::<pg_sys::Oid>(
+        "SELECT relowner FROM pg_catalog.pg_class WHERE oid = $1::regclass",
+        vec![(PgBuiltInOids::TEXTOID.oid(), table.into_datum())],
+    )?
+    .ok_or_else(|| eyre::eyre!("`{table}` has no owner"))?;
+
+    let mut saved_user = pg_sys::Oid::INVALID;
+    let mut saved_sec_context = 0;
+    unsafe {
+        // SAFETY:  This is how Postgres itself runs, for example, a SECURITY DEFINER function.  If
+        // `f` raises an ERROR the (sub)transaction abort restores the user id and security context
+        // for us
+        pg_sys::GetUserIdAndSecContext(&mut saved_user, &mut saved_sec_context);
+        pg_sys::SetUserIdAndSecContext(
+            owner,
+            saved_sec_context | pg_sys::SECURITY_LOCAL_USERID_CHANGE as i32,
+        );
+    }
+    let result = f();
+    unsafe {
+        // SAFETY:  restoring what `GetUserIdAndSecContext` gave us above
+        pg_sys::SetUserIdAndSecContext(saved_user, saved_sec_context);
+    }
+    result
+}
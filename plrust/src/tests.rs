@@ -263,6 +263,85 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[pg_test]
+    #[cfg(not(feature = "sandboxed"))]
+    #[search_path(@extschema@)]
+    fn plrust_deps_table_with_features() -> spi::Result<()> {
+        let definition = r#"
+                CREATE FUNCTION say_goodbye() RETURNS TEXT
+                IMMUTABLE STRICT
+                LANGUAGE PLRUST AS
+            $$
+            [dependencies]
+                tokio = { version = "1", features = ["rt"], default-features = false }
+            [code]
+                Ok(Some("goodbye".to_string()))
+            $$;
+        "#;
+        Spi::run(definition)?;
+
+        let retval = Spi::get_one::<String>("SELECT say_goodbye()");
+        assert_eq!(retval, Ok(Some("goodbye".to_string())));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[cfg(not(feature = "sandboxed"))]
+    #[search_path(@extschema@)]
+    fn plrust_deps_feature_not_allowed() {
+        let definition = r#"
+                CREATE FUNCTION say_goodbye() RETURNS TEXT
+                IMMUTABLE STRICT
+                LANGUAGE PLRUST AS
+            $$
+            [dependencies]
+                tokio = { version = "1", features = ["fs"] }
+            [code]
+                Ok(Some("goodbye".to_string()))
+            $$;
+        "#;
+        let res = std::panic::catch_unwind(|| {
+            Spi::run(definition).expect("SQL for plrust_deps_feature_not_allowed() failed")
+        });
+        assert!(res.is_err());
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn plrust_deps_path_not_allowed() {
+        let definition = r#"
+                CREATE FUNCTION say_goodbye() RETURNS TEXT
+                IMMUTABLE STRICT
+                LANGUAGE PLRUST AS
+            $$
+            [dependencies]
+                owo-colors = { version = "3", path = "/tmp/owo-colors" }
+            [code]
+                Ok(Some("goodbye".to_string()))
+            $$;
+        "#;
+        let res = std::panic::catch_unwind(|| {
+            Spi::run(definition).expect("SQL for plrust_deps_path_not_allowed() failed")
+        });
+        assert!(res.is_err());
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn allowed_dependencies_are_listed() -> spi::Result<()> {
+        let tokio = Spi::get_two::<String, Vec<String>>(
+            "SELECT version, features FROM plrust.allowed_dependencies() WHERE name = 'tokio'",
+        )?;
+        assert_eq!(
+            (
+                Some("1.19.2".to_string()),
+                Some(vec!["rt".to_string(), "net".to_string()])
+            ),
+            tokio
+        );
+        Ok(())
+    }
+
     #[pg_test]
     #[search_path(@extschema@)]
     fn plrust_returns_setof() -> spi::Result<()> {
@@ -1003,6 +1082,345 @@ mod tests {
         Ok(())
     }
 
+    #[pg_test]
+    #[search_path(@extschema@)]
+    #[should_panic(expected = "failed signature verification")]
+    fn tampered_artifact_is_refused() -> spi::Result<()> {
+        Spi::run("CREATE FUNCTION tampered() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$")?;
+
+        // claim the artifact was compiled with one fewer lint than it actually was
+        Spi::run(
+            r#"UPDATE pg_catalog.pg_proc
+                  SET prosrc = jsonb_set(
+                          prosrc::jsonb,
+                          '{lib}',
+                          (SELECT jsonb_object_agg(target, lib || jsonb_build_object('lints', (lib->'lints') - 0))
+                             FROM jsonb_each(prosrc::jsonb->'lib') AS libs(target, lib))
+                      )::text
+                WHERE oid = 'tampered'::regproc"#,
+        )?;
+        Spi::get_one::<i32>("SELECT tampered()").map(|_| ())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn recompile_stale_function() -> spi::Result<()> {
+        Spi::run("CREATE FUNCTION stale_me() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$")?;
+        let stale_count =
+            "SELECT count(*) FROM plrust.stale_functions() WHERE fn_oid = 'stale_me'::regproc";
+        assert_eq!(Ok(Some(0)), Spi::get_one::<i64>(stale_count));
+
+        // pretend the function was compiled by a different plrust-trusted-pgx version
+        Spi::run(
+            r#"UPDATE pg_catalog.pg_proc
+                  SET prosrc = jsonb_set(prosrc::jsonb, '{trusted_pgx_version}', '"=0.0.0"')::text
+                WHERE oid = 'stale_me'::regproc"#,
+        )?;
+        assert_eq!(Ok(Some(1)), Spi::get_one::<i64>(stale_count));
+
+        let dry_run = Spi::get_one::<String>(
+            "SELECT status FROM plrust.recompile_functions(dry_run => true) WHERE fn_oid = 'stale_me'::regproc",
+        );
+        assert_eq!(Ok(Some("stale".to_string())), dry_run);
+        assert_eq!(Ok(Some(1)), Spi::get_one::<i64>(stale_count));
+
+        let recompiled = Spi::get_one::<String>(
+            "SELECT status FROM plrust.recompile_functions() WHERE fn_oid = 'stale_me'::regproc",
+        );
+        assert_eq!(Ok(Some("recompiled".to_string())), recompiled);
+        assert_eq!(Ok(Some(0)), Spi::get_one::<i64>(stale_count));
+        assert_eq!(Ok(Some(1)), Spi::get_one("SELECT stale_me()"));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn artifact_hashes_are_recorded() -> spi::Result<()> {
+        Spi::run("CREATE FUNCTION hash_me() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$")?;
+        let src_matches = Spi::get_one::<bool>(
+            r#"SELECT h.src_sha256 = encode(sha256(convert_to(p.prosrc::jsonb->>'src', 'UTF8')), 'hex')
+                 FROM pg_catalog.pg_proc p, plrust.artifact_hashes(p.oid) h
+                WHERE p.oid = 'hash_me'::regproc"#,
+        );
+        assert_eq!(Ok(Some(true)), src_matches);
+
+        let so_sha256 = Spi::get_one::<String>(
+            "SELECT so_sha256 FROM plrust.artifact_hashes('hash_me'::regproc)",
+        )?;
+        assert_eq!(Some(64), so_sha256.map(|hash| hash.len()));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn same_function_compiles_to_same_bytes() -> spi::Result<()> {
+        let body = "$$ Ok(Some(a * 2)) $$";
+        Spi::run(&format!(
+            "CREATE FUNCTION twice_a(a int) RETURNS int STRICT LANGUAGE plrust AS {body}"
+        ))?;
+        Spi::run(&format!(
+            "CREATE FUNCTION twice_b(a int) RETURNS int STRICT LANGUAGE plrust AS {body}"
+        ))?;
+
+        let same = Spi::get_two::<bool, bool>(
+            r#"SELECT a.so_sha256 = b.so_sha256, ia.symbol = ib.symbol
+                 FROM plrust.artifact_hashes('twice_a'::regproc) a
+                 JOIN plrust.artifact_hashes('twice_b'::regproc) b USING (target)
+                 JOIN plrust.function_info('twice_a(int)') ia USING (target)
+                 JOIN plrust.function_info('twice_b(int)') ib USING (target)"#,
+        )?;
+        assert_eq!((Some(true), Some(true)), same);
+        assert_eq!(Ok(Some(2)), Spi::get_one::<i32>("SELECT twice_b(1)"));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn lockfile_is_kept_across_recompiles() -> spi::Result<()> {
+        Spi::run("CREATE FUNCTION lock_me() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$")?;
+        let lockfile = Spi::get_one::<String>("SELECT plrust.lockfile('lock_me'::regproc)")?
+            .expect("no lockfile was stored");
+        assert!(lockfile.contains("plrust-trusted-pgx"));
+
+        let hash_matches = Spi::get_one::<bool>(
+            r#"SELECT lockfile_sha256 = encode(sha256(convert_to(plrust.lockfile('lock_me'::regproc), 'UTF8')), 'hex')
+                 FROM plrust.artifact_hashes('lock_me'::regproc)"#,
+        );
+        assert_eq!(Ok(Some(true)), hash_matches);
+
+        // pretend the function is stale so it's compiled again, starting from its lockfile
+        Spi::run(
+            r#"UPDATE pg_catalog.pg_proc
+                  SET prosrc = jsonb_set(prosrc::jsonb, '{trusted_pgx_version}', '"=0.0.0"')::text
+                WHERE oid = 'lock_me'::regproc"#,
+        )?;
+        Spi::run("SELECT * FROM plrust.recompile_functions()")?;
+        let recompiled = Spi::get_one::<String>("SELECT plrust.lockfile('lock_me'::regproc)")?;
+        let versions = |lockfile: &str| {
+            lockfile
+                .lines()
+                .filter(|line| line.starts_with("version = "))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(versions(&lockfile), versions(&recompiled.unwrap()));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn function_info_is_reported() -> spi::Result<()> {
+        Spi::run("CREATE FUNCTION describe_me() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$")?;
+        let info = Spi::get_three::<bool, Vec<String>, Vec<String>>(
+            r#"SELECT stored_bytes > 0 AND library_bytes > 0 AND symbol IS NOT NULL, lints, missing_lints
+                 FROM plrust.function_info('describe_me()')"#,
+        )?;
+        assert_eq!(Some(true), info.0);
+        assert!(!info.1.unwrap().is_empty());
+        assert_eq!(Some(vec![]), info.2);
+
+        assert_eq!(
+            Ok(Some(1)),
+            Spi::get_one::<i64>("SELECT count(target) FROM plrust.functions WHERE function = 'describe_me()'::regprocedure")
+        );
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn compilation_is_recorded() -> spi::Result<()> {
+        Spi::run(
+            "CREATE FUNCTION record_me() RETURNS int LANGUAGE plrust AS $$ let unused = 1; Ok(Some(1)) $$",
+        )?;
+        let (success, compiled_by, warnings) = Spi::get_three::<bool, bool, Vec<String>>(
+            r#"SELECT success, compiled_by = current_user, warnings
+                 FROM plrust.compile_history
+                WHERE fn_oid = 'record_me()'::regprocedure"#,
+        )?;
+        assert_eq!(Some(true), success);
+        assert_eq!(Some(true), compiled_by);
+        assert!(warnings
+            .unwrap()
+            .iter()
+            .any(|warning| warning.contains("unused variable")));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn failed_compilation_is_recorded() -> spi::Result<()> {
+        // the failure rolls back its subtransaction, and the background worker records it in its own
+        Spi::run(
+            r#"DO $do$ BEGIN
+                   CREATE FUNCTION fail_me() RETURNS int LANGUAGE plrust AS $$ Ok(Some(not_a_variable)) $$;
+               EXCEPTION WHEN others THEN NULL;
+               END $do$"#,
+        )?;
+        // and we can only see what it commits from outside our own transaction's snapshot
+        let database = Spi::get_one::<String>("SELECT current_database()::text")?.unwrap();
+        let recorded_query = "SELECT count(*) > 0 FROM plrust.compile_history WHERE NOT success AND error IS NOT NULL AND signature LIKE '%fail_me()'";
+        let recorded = (0..300).any(|_| {
+            let recorded = pg_client("psql", &["-At", "-d", &database, "-c", recorded_query], &[]);
+            if recorded.trim() != "t" {
+                std::thread::sleep(std::time::Duration::from_secs(1));
+            }
+            recorded.trim() == "t"
+        });
+        assert!(
+            recorded,
+            "the failed compilation of `fail_me` was never recorded"
+        );
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn artifacts_are_zstd_encoded() -> spi::Result<()> {
+        Spi::run("CREATE FUNCTION squeeze_me() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$")?;
+        let encodings = Spi::get_one::<Vec<String>>(
+            r#"SELECT array_agg(DISTINCT lib->>'encoding')
+                 FROM pg_catalog.pg_proc, jsonb_each(prosrc::jsonb->'lib') AS libs(target, lib)
+                WHERE oid = 'squeeze_me'::regproc"#,
+        )?;
+        assert_eq!(Some(vec!["ZstdBase64".to_string()]), encodings);
+        assert_eq!(Ok(Some(1)), Spi::get_one("SELECT squeeze_me()"));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn gzip_artifacts_still_load() -> spi::Result<()> {
+        use base64::Engine;
+        use std::io::Read;
+
+        Spi::run("CREATE FUNCTION gzipped() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$")?;
+        let prosrc = Spi::get_one::<String>(
+            "SELECT prosrc FROM pg_catalog.pg_proc WHERE oid = 'gzipped'::regproc",
+        )?
+        .expect("no prosrc");
+
+        // rewrite the entry as it would have been stored before `zstd` was the default
+        let engine = base64::engine::GeneralPurpose::new(
+            &base64::alphabet::URL_SAFE,
+            base64::engine::general_purpose::NO_PAD,
+        );
+        let mut entry = serde_json::from_str::<serde_json::Value>(&prosrc).expect("invalid json");
+        for lib in entry["lib"].as_object_mut().unwrap().values_mut() {
+            let compressed = engine.decode(lib["encoded"].as_str().unwrap()).unwrap();
+            let so_bytes = zstd::stream::decode_all(&compressed[..]).unwrap();
+            let mut gzipped = Vec::new();
+            flate2::read::GzEncoder::new(&so_bytes[..], flate2::Compression::best())
+                .read_to_end(&mut gzipped)
+                .unwrap();
+            lib["encoding"] = "GzBase64".into();
+            lib["encoded"] = engine.encode(gzipped).into();
+        }
+        Spi::get_one_with_args::<bool>(
+            "UPDATE pg_catalog.pg_proc SET prosrc = $1 WHERE oid = 'gzipped'::regproc RETURNING true",
+            vec![(PgBuiltInOids::TEXTOID.oid(), entry.to_string().into_datum())],
+        )?;
+
+        assert_eq!(Ok(Some(1)), Spi::get_one("SELECT gzipped()"));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn artifacts_stored_in_table() -> spi::Result<()> {
+        Spi::run("SET plrust.artifact_storage = 'table'")?;
+        Spi::run(
+            "CREATE FUNCTION stored_elsewhere() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$",
+        )?;
+        let prosrc = Spi::get_one::<String>(
+            "SELECT prosrc FROM pg_catalog.pg_proc WHERE oid = 'stored_elsewhere'::regproc",
+        );
+        assert_eq!(Ok(Some(" Ok(Some(1)) ".to_string())), prosrc);
+        assert_eq!(
+            Ok(Some(1)),
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM plrust.artifacts WHERE fn_oid = 'stored_elsewhere'::regproc"
+            )
+        );
+        assert_eq!(Ok(Some(1)), Spi::get_one("SELECT stored_elsewhere()"));
+
+        Spi::run("DROP FUNCTION stored_elsewhere()")?;
+        assert_eq!(
+            Ok(Some(0)),
+            Spi::get_one::<i64>("SELECT count(*) FROM plrust.artifacts")
+        );
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn stored_artifacts_follow_alter_function() -> spi::Result<()> {
+        Spi::run("SET plrust.artifact_storage = 'table'")?;
+        Spi::run("CREATE FUNCTION rekeyed() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$")?;
+        Spi::run("ALTER FUNCTION rekeyed() RENAME TO still_compiled")?;
+        Spi::run("REVOKE ALL ON FUNCTION still_compiled() FROM PUBLIC")?;
+        assert_eq!(Ok(Some(1)), Spi::get_one("SELECT still_compiled()"));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    #[should_panic(expected = "has not been compiled")]
+    fn stale_stored_artifacts_are_refused() -> spi::Result<()> {
+        Spi::run("SET plrust.artifact_storage = 'table'")?;
+        Spi::run("CREATE FUNCTION gone_stale() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$")?;
+        Spi::run(
+            "UPDATE plrust.artifacts SET generation = generation - 1 WHERE fn_oid = 'gone_stale'::regproc",
+        )?;
+        Spi::get_one::<i32>("SELECT gone_stale()").map(|_| ())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn compiles_without_artifacts_table() -> spi::Result<()> {
+        // what a database whose extension hasn't been updated since before `plrust.artifacts` has
+        for trigger in ["plrust_drop_artifacts", "plrust_rekey_artifacts"] {
+            Spi::run(&format!(
+                "ALTER EXTENSION plrust DROP EVENT TRIGGER {trigger}"
+            ))?;
+            Spi::run(&format!("DROP EVENT TRIGGER {trigger}"))?;
+        }
+        Spi::run("ALTER EXTENSION plrust DROP TABLE plrust.artifacts")?;
+        Spi::run("DROP TABLE plrust.artifacts")?;
+
+        Spi::run("CREATE FUNCTION not_updated() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$")?;
+        Spi::run("CREATE OR REPLACE FUNCTION not_updated() RETURNS int LANGUAGE plrust AS $$ Ok(Some(2)) $$")?;
+        assert_eq!(Ok(Some(2)), Spi::get_one("SELECT not_updated()"));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn compiles_without_compile_history_table() -> spi::Result<()> {
+        // what a database whose extension hasn't been updated since before `plrust.compile_history` has
+        Spi::run("ALTER EXTENSION plrust DROP TABLE plrust.compile_history")?;
+        Spi::run("DROP TABLE plrust.compile_history")?;
+
+        Spi::run(
+            "CREATE FUNCTION not_recorded() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$",
+        )?;
+        assert_eq!(Ok(Some(1)), Spi::get_one("SELECT not_recorded()"));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    #[should_panic(expected = "does not match its recorded SHA-256")]
+    fn corrupted_artifact_is_refused() -> spi::Result<()> {
+        Spi::run("CREATE FUNCTION corrupt_me() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$")?;
+        let target = crate::target::tuple().expect("unable to determine compilation target");
+        Spi::run(&format!(
+            r#"UPDATE pg_catalog.pg_proc
+                  SET prosrc = jsonb_set(prosrc::jsonb, '{{lib,{target},hashes,so_sha256}}', '"0000"')::text
+                WHERE oid = 'corrupt_me'::regproc"#
+        ))?;
+        Spi::get_one::<i32>("SELECT corrupt_me()").map(|_| ())
+    }
+
     #[pg_test]
     fn test_point() -> spi::Result<()> {
         Spi::run(
@@ -1165,6 +1583,400 @@ mod tests {
     fn invalid_arg_identifier() -> spi::Result<()> {
         Spi::run("CREATE FUNCTION invalid_arg_identifier(\"this isn't a valid rust identifier\" int) RETURNS int LANGUAGE plrust as $$ Ok(None) $$;")
     }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn invalidated_functions_reload() -> spi::Result<()> {
+        Spi::run(
+            "CREATE FUNCTION invalidate_me() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$",
+        )?;
+        assert_eq!(Ok(Some(1)), Spi::get_one::<i32>("SELECT invalidate_me()"));
+
+        // invalidates the loaded function without needing to recompile it
+        Spi::run("ALTER FUNCTION invalidate_me() RENAME TO invalidated()")?;
+        assert_eq!(Ok(Some(1)), Spi::get_one::<i32>("SELECT invalidated()"));
+
+        // the dropped function is closed, and its replacement is loaded fresh
+        Spi::run("DROP FUNCTION invalidated()")?;
+        Spi::run("CREATE FUNCTION invalidated() RETURNS int LANGUAGE plrust AS $$ Ok(Some(2)) $$")?;
+        assert_eq!(Ok(Some(2)), Spi::get_one::<i32>("SELECT invalidated()"));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn loaded_functions_are_bounded() -> spi::Result<()> {
+        Spi::run("SET plrust.max_loaded_functions = 1")?;
+        Spi::run("CREATE FUNCTION lru_one() RETURNS int LANGUAGE plrust AS $$ Ok(Some(1)) $$")?;
+        Spi::run("CREATE FUNCTION lru_two() RETURNS int LANGUAGE plrust AS $$ Ok(Some(2)) $$")?;
+
+        let stats =
+            "SELECT array[loaded, hits, misses, evictions] FROM plrust.loaded_functions_stats()";
+        let before = Spi::get_one::<Vec<i64>>(stats)?.unwrap();
+
+        assert_eq!(Ok(Some(1)), Spi::get_one::<i32>("SELECT lru_one()"));
+        assert_eq!(Ok(Some(1)), Spi::get_one::<i32>("SELECT lru_one()"));
+        assert_eq!(Ok(Some(2)), Spi::get_one::<i32>("SELECT lru_two()"));
+        assert_eq!(Ok(Some(1)), Spi::get_one::<i32>("SELECT lru_one()"));
+
+        let after = Spi::get_one::<Vec<i64>>(stats)?.unwrap();
+        assert_eq!(1, after[0]);
+        assert_eq!(1, after[1] - before[1]); // the second call to lru_one()
+        assert_eq!(3, after[2] - before[2]); // lru_one(), lru_two(), then lru_one() again
+        assert!(after[3] - before[3] >= 2);
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn function_stats_are_recorded() -> spi::Result<()> {
+        Spi::run("CREATE FUNCTION stats_me(a int) RETURNS int STRICT LANGUAGE plrust AS $$ if a? < 0 { panic!(\"negative\") } Ok(Some(a?)) $$")?;
+        Spi::run("SELECT stats_me(i) FROM generate_series(1, 3) i")?;
+        Spi::run("DO $$ BEGIN PERFORM stats_me(-1); EXCEPTION WHEN OTHERS THEN NULL; END $$")?;
+
+        let stats = Spi::get_three::<i64, i64, i64>(
+            "SELECT calls, panics, loads FROM plrust.stat_functions WHERE fn_oid = 'stats_me(int)'::regprocedure",
+        )?;
+        assert_eq!((Some(4), Some(1), Some(1)), stats);
+        assert_eq!(
+            Ok(Some(true)),
+            Spi::get_one::<bool>(
+                "SELECT library_bytes > 0 AND total_time >= max_time FROM plrust.stat_functions WHERE fn_oid = 'stats_me(int)'::regprocedure"
+            )
+        );
+
+        Spi::run("SELECT plrust.stat_reset()")?;
+        assert_eq!(
+            Ok(Some(0)),
+            Spi::get_one::<i64>("SELECT count(*) FROM plrust.stat_functions WHERE fn_oid = 'stats_me(int)'::regprocedure")
+        );
+
+        // a dropped function's statistics go with it
+        let fn_oid = Spi::get_one::<pg_sys::Oid>("SELECT 'stats_me(int)'::regprocedure::oid")?;
+        Spi::run("SELECT stats_me(1)")?;
+        Spi::run("DROP FUNCTION stats_me(int)")?;
+        assert_eq!(
+            Ok(Some(0)),
+            Spi::get_one_with_args::<i64>(
+                "SELECT count(*) FROM plrust.function_stats() WHERE fn_oid = $1",
+                vec![(PgBuiltInOids::OIDOID.oid(), fn_oid.into_datum())],
+            )
+        );
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn check_source() -> spi::Result<()> {
+        let clean = Spi::get_one::<i64>(
+            "SELECT count(*) FROM plrust.check('Ok(Some(a + b))', array['int', 'int']::regtype[], 'int', true, array['a', 'b'])",
+        )?;
+        assert_eq!(Some(0), clean);
+
+        let error = Spi::get_two::<String, String>(
+            "SELECT level, code FROM plrust.check('Ok(Some(arg1))', array['text']::regtype[], 'int', true) WHERE level = 'error' LIMIT 1",
+        )?;
+        assert_eq!(
+            (Some("error".to_string()), Some("E0308".to_string())),
+            error
+        );
+
+        let forbidden = Spi::get_one::<String>(
+            "SELECT message FROM plrust.check('unsafe { Ok(Some(1)) }', '{}', 'int', true) WHERE level = 'error' LIMIT 1",
+        )?;
+        assert!(forbidden.unwrap().contains("unsafe"));
+
+        let set = Spi::get_one::<i64>(
+            "SELECT count(*) FROM plrust.check('Ok(Some(::pgx::iter::SetOfIterator::new(vec![Some(1)])))', '{}', 'int', true, returns_set => true)",
+        )?;
+        assert_eq!(Some(0), set);
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn check_leaves_nothing_behind() -> spi::Result<()> {
+        // a dependency that can't be fetched fails the check after the crate is generated
+        let error = Spi::get_one::<String>(
+            r#"SELECT message FROM plrust.check('[dependencies]
+plrust-no-such-crate = "1"
+[code]
+Ok(Some(1))', '{}', 'int', true) WHERE level = 'error' LIMIT 1"#,
+        )?;
+        assert!(error.is_some());
+
+        // only look at this backend's checks, as other tests may be checking concurrently
+        // SAFETY: Postgres globally sets these during backend initialization
+        let (db_oid, pid) = unsafe { (pg_sys::MyDatabaseId, pg_sys::MyProcPid) };
+        let prefix = format!(
+            "{}_",
+            crate::plrust::legacy_symbol_name(db_oid, pg_sys::Oid::INVALID)
+        );
+        let left_behind = std::fs::read_dir(crate::gucs::work_dir())
+            .expect("Couldn't read plrust.work_dir")
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                let generation = name.strip_prefix(&prefix)?.parse::<u64>().ok()?;
+                (generation >> 32 == pid as u64).then_some(name)
+            })
+            .collect::<Vec<_>>();
+        assert!(left_behind.is_empty(), "{left_behind:?}");
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    #[should_panic(expected = "permission denied for language plrust")]
+    fn check_requires_language_usage() -> spi::Result<()> {
+        Spi::run("CREATE ROLE plrust_check_denied")?;
+        Spi::run("GRANT USAGE ON SCHEMA plrust TO plrust_check_denied")?;
+        Spi::run("REVOKE USAGE ON LANGUAGE plrust FROM PUBLIC")?;
+        Spi::run("SET ROLE plrust_check_denied")?;
+        Spi::run("SELECT * FROM plrust.check('Ok(Some(1))', '{}', 'int', true)")?;
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn export_generated_crate() -> spi::Result<()> {
+        Spi::run("CREATE FUNCTION export_me(a int) RETURNS int STRICT LANGUAGE plrust AS $$ Ok(Some(a + 1)) $$")?;
+        let tarball = Spi::get_one::<Vec<u8>>(
+            "SELECT plrust.export_crate('export_me(int)', '/src/plrust-trusted-pgx')",
+        )?
+        .expect("no tarball");
+
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&tarball[..]));
+        let mut files = std::collections::BTreeMap::new();
+        for entry in archive.entries().expect("invalid tarball") {
+            let mut entry = entry.expect("invalid tarball entry");
+            let path = entry.path().unwrap().display().to_string();
+            let mut contents = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut contents).unwrap();
+            files.insert(path.split_once('/').unwrap().1.to_string(), contents);
+        }
+
+        assert_eq!(
+            vec!["Cargo.lock", "Cargo.toml", "src/lib.rs"],
+            files.keys().collect::<Vec<_>>()
+        );
+        let cargo_toml = files["Cargo.toml"]
+            .parse::<toml::Table>()
+            .expect("invalid Cargo.toml");
+        assert_eq!(
+            Some("/src/plrust-trusted-pgx"),
+            cargo_toml["dependencies"]["pgx"]
+                .get("path")
+                .and_then(|p| p.as_str())
+        );
+        assert!(files["src/lib.rs"].contains("a + 1"));
+        // the lockfile the function was built with, renamed for the exported crate
+        let crate_name = cargo_toml["package"]["name"].as_str().unwrap();
+        assert!(files["Cargo.lock"].contains(&format!("name = \"{crate_name}\"")));
+        Ok(())
+    }
+
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn warm_functions() -> spi::Result<()> {
+        Spi::run("CREATE FUNCTION warm_me(a int, b int) RETURNS int LANGUAGE plrust AS $$ Ok(Some(a? + b?)) $$")?;
+        Spi::run("CREATE FUNCTION not_plrust() RETURNS int LANGUAGE sql AS $$ SELECT 1 $$")?;
+
+        let misses = "SELECT misses FROM plrust.loaded_functions_stats()";
+        let before = Spi::get_one::<i64>(misses)?.unwrap();
+        let loaded = Spi::get_one::<Vec<bool>>(
+            "SELECT array_agg(loaded ORDER BY signature) FROM plrust.warm(array['warm_me(int, int)', 'not_plrust()']::regprocedure[])",
+        )?;
+        assert_eq!(Some(vec![false, true]), loaded);
+
+        // already loaded, so calling it isn't another miss
+        assert_eq!(Ok(Some(3)), Spi::get_one::<i32>("SELECT warm_me(1, 2)"));
+        assert_eq!(Ok(Some(before + 1)), Spi::get_one::<i64>(misses));
+        Ok(())
+    }
+
+    #[cfg(feature = "trusted")]
+    #[pg_test]
+    #[search_path(@extschema@)]
+    fn background_restore_policy_requires_superuser() -> spi::Result<()> {
+        Spi::run("CREATE FUNCTION restored_inline(i int) RETURNS int STRICT LANGUAGE plrust AS $$ Ok(Some(i + 1)) $$")?;
+        let prosrc = Spi::get_one::<String>(
+            "SELECT prosrc FROM pg_catalog.pg_proc WHERE oid = 'restored_inline'::regproc",
+        )?
+        .expect("no prosrc");
+        Spi::run("DROP FUNCTION restored_inline(int)")?;
+
+        Spi::run("CREATE ROLE plrust_restorer")?;
+        Spi::run("GRANT USAGE, CREATE ON SCHEMA plrust TO plrust_restorer")?;
+        Spi::run("SET plrust.restore_policy = 'background'")?;
+        Spi::run("SET ROLE plrust_restorer")?;
+        Spi::run(&format!(
+            "CREATE FUNCTION restored_inline(i int) RETURNS int STRICT LANGUAGE plrust AS $restored${prosrc}$restored$"
+        ))?;
+
+        // compiled as it was created, rather than left for the background worker
+        assert_eq!(
+            Ok(Some(42)),
+            Spi::get_one::<i32>("SELECT restored_inline(41)")
+        );
+        Ok(())
+    }
+
+    #[pg_test]
+    fn dump_and_restore() -> spi::Result<()> {
+        let dump_dir = tempdir::TempDir::new("plrust-dump").expect("Couldn't create tempdir");
+        let dump_file = dump_dir.path().join("plrust.dump");
+        let dump_file = dump_file.to_str().unwrap();
+        let databases = [
+            "plrust_dump_source",
+            "plrust_restore_recompile",
+            "plrust_restore_trust",
+            "plrust_restore_background",
+        ];
+        for database in databases {
+            pg_client("dropdb", &["--if-exists", database], &[]);
+        }
+
+        pg_client("createdb", &["plrust_dump_source"], &[]);
+        pg_client(
+            "psql",
+            &[
+                "-d",
+                "plrust_dump_source",
+                "-v",
+                "ON_ERROR_STOP=1",
+                "-c",
+                "CREATE EXTENSION plrust",
+                "-c",
+                "CREATE FUNCTION dumped(i int) RETURNS int STRICT LANGUAGE plrust AS $$ Ok(Some(i + 1)) $$",
+            ],
+            &[],
+        );
+        pg_client(
+            "pg_dump",
+            &["-Fc", "-f", dump_file, "plrust_dump_source"],
+            &[],
+        );
+
+        let lib_query =
+            "SELECT prosrc::jsonb->'lib' FROM pg_catalog.pg_proc WHERE proname = 'dumped'";
+        let dumped_lib = pg_client(
+            "psql",
+            &["-At", "-d", "plrust_dump_source", "-c", lib_query],
+            &[],
+        );
+
+        for policy in ["recompile", "trust"] {
+            let database = format!("plrust_restore_{policy}");
+            let pgoptions = format!("-c plrust.restore_policy={policy}");
+            pg_client("createdb", &[&database], &[]);
+            pg_client(
+                "pg_restore",
+                &["--exit-on-error", "-d", &database, dump_file],
+                &[("PGOPTIONS", &pgoptions)],
+            );
+
+            let result = pg_client(
+                "psql",
+                &["-At", "-d", &database, "-c", "SELECT dumped(41)"],
+                &[],
+            );
+            assert_eq!("42", result.trim());
+
+            // trusted artifacts are restored exactly as they were dumped, whereas recompiling
+            // produces a new artifact for the function's new Oid
+            let restored_lib = pg_client("psql", &["-At", "-d", &database, "-c", lib_query], &[]);
+            assert_eq!(policy == "trust", restored_lib == dumped_lib);
+        }
+
+        // the restore is left with only the function's source code, which the database's
+        // background worker compiles once the restore commits
+        pg_client("createdb", &["plrust_restore_background"], &[]);
+        pg_client(
+            "pg_restore",
+            &[
+                "--exit-on-error",
+                "--single-transaction",
+                "-d",
+                "plrust_restore_background",
+                dump_file,
+            ],
+            &[("PGOPTIONS", "-c plrust.restore_policy=background")],
+        );
+        let compiled_query =
+            "SELECT left(prosrc, 1) = '{' FROM pg_catalog.pg_proc WHERE proname = 'dumped'";
+        let compiled = (0..300).any(|_| {
+            let compiled = pg_client(
+                "psql",
+                &[
+                    "-At",
+                    "-d",
+                    "plrust_restore_background",
+                    "-c",
+                    compiled_query,
+                ],
+                &[],
+            );
+            if compiled.trim() != "t" {
+                std::thread::sleep(std::time::Duration::from_secs(1));
+            }
+            compiled.trim() == "t"
+        });
+        assert!(compiled, "the background worker never compiled `dumped`");
+        let result = pg_client(
+            "psql",
+            &[
+                "-At",
+                "-d",
+                "plrust_restore_background",
+                "-c",
+                "SELECT dumped(41)",
+            ],
+            &[],
+        );
+        assert_eq!("42", result.trim());
+
+        for database in databases {
+            pg_client("dropdb", &[database], &[]);
+        }
+        Ok(())
+    }
+
+    /// Run one of Postgres' client programs, from the same installation as this server, against this
+    /// server as the current user.  Panics if the program fails
+    fn pg_client(program: &str, args: &[&str], env: &[(&str, &str)]) -> String {
+        let postgres = unsafe {
+            // SAFETY:  Postgres assigns `my_exec_path` once early in its startup process
+            std::ffi::CStr::from_ptr(pg_sys::my_exec_path.as_ptr())
+        };
+        let bindir = std::path::Path::new(postgres.to_str().unwrap())
+            .parent()
+            .unwrap()
+            .to_path_buf();
+        let socket_dirs = Spi::get_one::<String>("SHOW unix_socket_directories")
+            .unwrap()
+            .unwrap_or_default();
+        let host = match socket_dirs.split(',').next().map(str::trim) {
+            Some(dir) if !dir.is_empty() => dir.to_string(),
+            _ => "localhost".to_string(),
+        };
+        let port = Spi::get_one::<String>("SHOW port").unwrap().unwrap();
+        let user = Spi::get_one::<String>("SELECT current_user::text")
+            .unwrap()
+            .unwrap();
+
+        let output = std::process::Command::new(bindir.join(program))
+            .args(["-h", &host, "-p", &port, "-U", &user])
+            .args(args)
+            .envs(env.iter().copied())
+            .output()
+            .unwrap_or_else(|e| panic!("unable to execute `{program}`: {e}"));
+        assert!(
+            output.status.success(),
+            "`{program}` failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
 }
 
 #[cfg(any(test, feature = "pg_test"))]
@@ -1191,7 +2003,7 @@ pub mod pg_test {
         allowed_deps
             .write_all(
                 r#"owo-colors = "3.5.0"
-tokio = { version = "1.19.2", features = ["rt", "net"]}"#
+tokio = { version = "1.19.2", features = ["rt", "net"], build_script_ok = true }"#
                     .as_bytes(),
             )
             .unwrap();
@@ -1199,6 +2011,26 @@ tokio = { version = "1.19.2", features = ["rt", "net"]}"#
         temp_allowed_deps_dir
     });
 
+    static PLRUST_ARTIFACT_SIGNING_KEY_DIRECTORY: Lazy<TempDir> = Lazy::new(|| {
+        let temp_key_dir = TempDir::new("plrust-signing-key").expect("Couldn't create tempdir");
+        std::fs::write(
+            temp_key_dir.path().join("signing.key"),
+            "6b2f0d1e4c9a87355e21f0b7c3d94a6e18f5b20c7d3e9a41b6c8f02d5e7a9134\n",
+        )
+        .unwrap();
+        temp_key_dir
+    });
+
+    static PLRUST_ARTIFACT_SIGNING_KEY: Lazy<String> = Lazy::new(|| {
+        format!(
+            "plrust.artifact_signing_key='{}'",
+            PLRUST_ARTIFACT_SIGNING_KEY_DIRECTORY
+                .path()
+                .join("signing.key")
+                .display()
+        )
+    });
+
     static PLRUST_ALLOWED_DEPENDENCIES: Lazy<String> = Lazy::new(|| {
         format!(
             "plrust.allowed_dependencies='{}'",
@@ -1219,6 +2051,7 @@ tokio = { version = "1.19.2", features = ["rt", "net"]}"#
             &*WORK_DIR,
             &*LOG_LEVEL,
             &*PLRUST_ALLOWED_DEPENDENCIES,
+            &*PLRUST_ARTIFACT_SIGNING_KEY,
             "shared_preload_libraries='plrust'",
         ]
     }
@@ -0,0 +1,93 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Checking a function's dependencies against a local copy of a RustSec advisory database.
+//!
+//! `plrust.advisory_db` is a directory laid out like https://github.com/rustsec/advisory-db, with
+//! each crate's advisories in `crates/<name>/`, and is kept up to date by whoever runs the server.
+//! A dependency at a version an advisory applies to is refused or warned about, depending on
+//! `plrust.advisory_policy`.  Informational advisories, such as a crate being unmaintained, are
+//! only ever warned about.
+//!
+//! Reading the database itself is left to [`AdvisoryDb`], which `plrust-compile` shares.
+use pgx::pg_sys;
+
+use crate::gucs;
+use crate::pgproc::PgProc;
+use crate::user_crate::advisory_db::{AdvisoryDb, Finding};
+use crate::user_crate::lockfile::Lockfile;
+
+/// The local RustSec advisory database at `path`, raising a WARNING for anything it warns about
+pub(crate) fn open(path: &str) -> AdvisoryDb {
+    AdvisoryDb::new(path, |warning| pgx::warning!("{warning}"))
+}
+
+/// The dependencies in `dependencies`, a `[dependencies]` table, that are pinned to an exact version
+pub(crate) fn pinned_dependencies(
+    dependencies: &toml::value::Table,
+) -> Vec<(String, semver::Version)> {
+    dependencies
+        .iter()
+        .filter_map(|(name, dependency)| {
+            let requirement = match dependency {
+                toml::Value::String(version) => version.as_str(),
+                toml::Value::Table(table) => table.get("version")?.as_str()?,
+                _ => return None,
+            };
+            let version =
+                semver::Version::parse(requirement.trim().strip_prefix('=')?.trim()).ok()?;
+            Some((name.clone(), version))
+        })
+        .collect()
+}
+
+/// Check `dependencies`, as crate names and the versions being built, against `plrust.advisory_db`,
+/// if it's set, refusing or warning about those with advisories as `plrust.advisory_policy` says
+pub(crate) fn check_dependencies<'a>(
+    dependencies: impl IntoIterator<Item = (&'a str, &'a semver::Version)>,
+) -> eyre::Result<()> {
+    let Some(db) = gucs::advisory_db() else {
+        return Ok(());
+    };
+    let denied = db.denied(gucs::advisory_policy()?, dependencies)?;
+
+    if !denied.is_empty() {
+        eyre::bail!(
+            "The following dependencies have advisories in `plrust.advisory_db`:\n    {}",
+            denied
+                .iter()
+                .map(Finding::to_string)
+                .collect::<Vec<_>>()
+                .join("\n    ")
+        );
+    }
+    Ok(())
+}
+
+/// Check the specified function's recorded dependencies against `db`: every crate in the lockfile it
+/// was last compiled with, or if it doesn't have one, the dependencies it pins to an exact version
+pub(crate) fn scan_function(db: &AdvisoryDb, fn_oid: pg_sys::Oid) -> eyre::Result<Vec<Finding>> {
+    let dependencies = match crate::prosrc::lockfile(fn_oid)? {
+        Some(lockfile) => Lockfile::parse(&lockfile)?
+            .locked_dependencies()
+            .into_iter()
+            .filter_map(|(name, version)| {
+                Some((name.to_string(), semver::Version::parse(version).ok()?))
+            })
+            .collect(),
+        None => pinned_dependencies(&super::declared_dependencies(
+            &PgProc::new(fn_oid)?.prosrc(),
+        )?),
+    };
+
+    let mut findings = Vec::new();
+    for (name, version) in &dependencies {
+        findings.extend(db.findings(name, version)?);
+    }
+    Ok(findings)
+}
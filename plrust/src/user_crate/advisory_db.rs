@@ -0,0 +1,229 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Reading a local copy of a RustSec advisory database, laid out like
+//! https://github.com/rustsec/advisory-db with each crate's advisories in `crates/<name>/`.
+//!
+//! Nothing in here may depend on pgx, as `plrust-compile` includes this file as-is to check a
+//! function's dependencies against the same database the server does.
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use eyre::WrapErr;
+use serde::Deserialize;
+
+/// What to do with a function that depends on a crate version with an advisory
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum AdvisoryPolicy {
+    /// refuse to compile it
+    Deny,
+    /// compile it, with a warning
+    Warn,
+}
+
+impl FromStr for AdvisoryPolicy {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "deny" => Ok(AdvisoryPolicy::Deny),
+            "warn" => Ok(AdvisoryPolicy::Warn),
+            other => eyre::bail!(
+                "unrecognized `plrust.advisory_policy` value `{other}`.  Expected `deny` or `warn`"
+            ),
+        }
+    }
+}
+
+/// The parts of an advisory's toml we need
+#[derive(Debug, Deserialize)]
+struct AdvisoryFile {
+    advisory: AdvisoryMetadata,
+    #[serde(default)]
+    versions: AdvisoryVersions,
+}
+
+#[derive(Debug, Deserialize)]
+struct AdvisoryMetadata {
+    id: String,
+    /// only in the older `.toml` format, where the title isn't a markdown heading
+    #[serde(default)]
+    title: Option<String>,
+    /// such as `unmaintained` or `unsound`, for advisories that aren't vulnerabilities
+    #[serde(default)]
+    informational: Option<String>,
+    /// the date it was withdrawn, as it turned out not to be valid
+    #[serde(default)]
+    withdrawn: Option<toml::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AdvisoryVersions {
+    #[serde(default)]
+    patched: Vec<String>,
+    #[serde(default)]
+    unaffected: Vec<String>,
+}
+
+/// An advisory that applies to a crate version a function depends on
+#[derive(Debug, Clone)]
+pub(crate) struct Finding {
+    pub(crate) name: String,
+    pub(crate) version: semver::Version,
+    /// such as `RUSTSEC-2020-0071`
+    pub(crate) id: String,
+    pub(crate) title: Option<String>,
+    pub(crate) informational: Option<String>,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{} {}` has advisory {}",
+            self.name, self.version, self.id
+        )?;
+        if let Some(kind) = &self.informational {
+            write!(f, " ({kind})")?;
+        }
+        if let Some(title) = &self.title {
+            write!(f, ": {title}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A local RustSec advisory database
+pub(crate) struct AdvisoryDb {
+    path: PathBuf,
+    /// what to do with a warning, about an advisory that couldn't be read or one that's only warned
+    /// about
+    warn: fn(&str),
+}
+
+impl AdvisoryDb {
+    pub(crate) fn new(path: &str, warn: fn(&str)) -> Self {
+        AdvisoryDb {
+            path: path.into(),
+            warn,
+        }
+    }
+
+    /// The advisories that apply to `version` of the crate `name`
+    pub(crate) fn findings(
+        &self,
+        name: &str,
+        version: &semver::Version,
+    ) -> eyre::Result<Vec<Finding>> {
+        let crate_dir = self.path.join("crates").join(name);
+        let Ok(entries) = std::fs::read_dir(&crate_dir) else {
+            // no advisories for the crate
+            return Ok(vec![]);
+        };
+
+        let mut findings = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            // one malformed advisory, perhaps in a newer format than we understand, mustn't keep the
+            // rest from being checked
+            let (advisory, title) = match read_advisory(&path) {
+                Ok(Some(advisory)) => advisory,
+                Ok(None) => continue,
+                Err(e) => {
+                    (self.warn)(&format!("skipping advisory `{}`: {e:#}", path.display()));
+                    continue;
+                }
+            };
+            if advisory.advisory.withdrawn.is_some() {
+                continue;
+            }
+            match affects(&advisory.versions, version) {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(e) => {
+                    (self.warn)(&format!("skipping advisory `{}`: {e:#}", path.display()));
+                    continue;
+                }
+            }
+            findings.push(Finding {
+                name: name.to_string(),
+                version: version.clone(),
+                id: advisory.advisory.id,
+                title: advisory.advisory.title.or(title),
+                informational: advisory.advisory.informational,
+            });
+        }
+        findings.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(findings)
+    }
+
+    /// Check `dependencies`, as crate names and the versions being built, returning the advisories
+    /// that keep a function from being compiled under `policy`.  The rest are warned about
+    pub(crate) fn denied<'a>(
+        &self,
+        policy: AdvisoryPolicy,
+        dependencies: impl IntoIterator<Item = (&'a str, &'a semver::Version)>,
+    ) -> eyre::Result<Vec<Finding>> {
+        let mut denied = Vec::new();
+        for (name, version) in dependencies {
+            for finding in self.findings(name, version)? {
+                match (policy, &finding.informational) {
+                    (AdvisoryPolicy::Deny, None) => denied.push(finding),
+                    _ => (self.warn)(&finding.to_string()),
+                }
+            }
+        }
+        Ok(denied)
+    }
+}
+
+/// Read the advisory at `path`, which is either markdown with a fenced toml block followed by a
+/// heading with its title, or toml on its own.  `None` if it's neither
+fn read_advisory(path: &Path) -> eyre::Result<Option<(AdvisoryFile, Option<String>)>> {
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    if !matches!(extension, Some("md") | Some("toml")) {
+        return Ok(None);
+    }
+    let contents = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("Reading advisory `{}`", path.display()))?;
+
+    let (toml, title) = match extension {
+        Some("md") => {
+            let Some(front_matter) = contents
+                .trim_start()
+                .strip_prefix("```toml")
+                .and_then(|rest| rest.split_once("```"))
+            else {
+                return Ok(None);
+            };
+            let title = front_matter
+                .1
+                .lines()
+                .find_map(|line| line.strip_prefix("# "))
+                .map(|title| title.trim().to_string());
+            (front_matter.0, title)
+        }
+        _ => (contents.as_str(), None),
+    };
+    let advisory =
+        toml::from_str(toml).wrap_err_with(|| format!("Parsing advisory `{}`", path.display()))?;
+    Ok(Some((advisory, title)))
+}
+
+/// Is `version` neither patched nor unaffected?
+fn affects(versions: &AdvisoryVersions, version: &semver::Version) -> eyre::Result<bool> {
+    for req in versions.patched.iter().chain(&versions.unaffected) {
+        let req = semver::VersionReq::parse(req)
+            .wrap_err_with(|| format!("Parsing advisory version requirement `{req}`"))?;
+        if req.matches(version) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
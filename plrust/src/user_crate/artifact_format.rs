@@ -0,0 +1,179 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! How compiled artifacts are encoded, hashed, and signed in the json PL/Rust stores in
+//! `pg_catalog.pg_proc.prosrc`.
+//!
+//! Nothing in here may depend on pgx, or on anything else that only works inside a Postgres
+//! backend, because `plrust-compile` includes this file as-is so the server accepts what it
+//! produces.
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use base64::Engine;
+use eyre::WrapErr;
+use flate2::read::GzEncoder;
+use flate2::Compression;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::dependencies::DependencyLints;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Anything shorter than this is too easily guessed to be used as an HMAC-SHA256 key
+const MIN_KEY_LEN: usize = 32;
+
+/// Bumped if the signed message format ever changes
+const SIGNATURE_VERSION: &str = "plrust-artifact-v3";
+
+/// Compressing happens once per compilation, which is dwarfed by the compilation itself, so we can
+/// afford a high level
+const ZSTD_LEVEL: i32 = 19;
+
+pub(crate) const BASE64_ENGINE: base64::engine::GeneralPurpose =
+    base64::engine::GeneralPurpose::new(
+        &base64::alphabet::URL_SAFE,
+        base64::engine::general_purpose::NO_PAD,
+    );
+
+/// How a compiled artifact's bytes are stored in `pg_proc.prosrc`.  New artifacts are encoded as
+/// `plrust.artifact_encoding` says, but every variant must remain decodable forever
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub(crate) enum Encoding {
+    GzBase64,
+    ZstdBase64,
+}
+
+impl FromStr for Encoding {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "gzip" => Ok(Encoding::GzBase64),
+            "zstd" => Ok(Encoding::ZstdBase64),
+            other => eyre::bail!(
+                "unrecognized `plrust.artifact_encoding` value `{other}`.  Expected `zstd` or `gzip`"
+            ),
+        }
+    }
+}
+
+impl Encoding {
+    /// Compress and base64 encode a shared library
+    pub(crate) fn encode(self, so_bytes: &[u8]) -> eyre::Result<String> {
+        let compressed_bytes = match self {
+            Encoding::GzBase64 => {
+                let mut compressed_bytes = Vec::new();
+                GzEncoder::new(so_bytes, Compression::best()).read_to_end(&mut compressed_bytes)?;
+                compressed_bytes
+            }
+            Encoding::ZstdBase64 => zstd::bulk::compress(so_bytes, ZSTD_LEVEL)?,
+        };
+        Ok(BASE64_ENGINE.encode(compressed_bytes))
+    }
+}
+
+/// Everything, other than the function's source code, that determines the bytes of a compiled
+/// artifact.  Recorded alongside each artifact so builds can be audited and compared across servers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct BuildInputs {
+    /// the output of `plrustc -vV`
+    pub(crate) toolchain: String,
+    /// the `plrust-trusted-pgx` version the function was compiled against
+    pub(crate) trusted_pgx_version: String,
+    /// SHA-256 of the `Cargo.lock` the user crate's dependencies were resolved to
+    pub(crate) lockfile_sha256: Option<String>,
+    /// Missing unless compiled with `plrust.dependency_lints`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) dependency_lints: Option<DependencyLints>,
+    /// SHA-256 of the `plrust.allowed_dependencies` the function's dependencies were checked
+    /// against, see [`super::dependencies::allow_list_sha256`].  Missing when there was none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) allow_list_sha256: Option<String>,
+}
+
+impl BuildInputs {
+    /// SHA-256 over all of the build inputs
+    pub(crate) fn sha256(&self) -> String {
+        sha256_hex(&serde_json::to_vec(self).expect("unable to serialize BuildInputs to json"))
+    }
+}
+
+/// Lowercase hex encoding of the SHA-256 digest of `bytes`
+pub(crate) fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Read an artifact signing key file.  Key files are commonly written by hand or with something
+/// like `openssl rand -hex 32 > key`, so a trailing newline isn't part of the key
+pub(crate) fn read_signing_key(path: &Path) -> eyre::Result<Vec<u8>> {
+    let mut key = std::fs::read(path)
+        .wrap_err_with(|| format!("Unable to read signing key file `{}`", path.display()))?;
+    while key.last().map_or(false, u8::is_ascii_whitespace) {
+        key.pop();
+    }
+    if key.len() < MIN_KEY_LEN {
+        eyre::bail!(
+            "signing key file `{}` must contain at least {MIN_KEY_LEN} bytes",
+            path.display()
+        );
+    }
+    Ok(key)
+}
+
+/// What an artifact's signature covers: its compilation target, entry symbol, the lints it was
+/// compiled with, what it claims about the function it was compiled for, the digest of its recorded
+/// [`BuildInputs`], and the digest of the artifact itself
+pub(crate) struct SignedMessage {
+    pub(crate) target: String,
+    pub(crate) symbol: String,
+    pub(crate) lints: String,
+    pub(crate) function: String,
+    pub(crate) src_sha256: String,
+    pub(crate) trusted_pgx_version: String,
+    pub(crate) build_inputs_sha256: String,
+    pub(crate) so_sha256: String,
+}
+
+impl SignedMessage {
+    /// An HMAC-SHA256 of the message under `key`
+    pub(crate) fn mac(&self, key: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(&self.to_bytes());
+        mac
+    }
+
+    /// The message's signature under `key`, as it's recorded
+    pub(crate) fn sign(&self, key: &[u8]) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(self.mac(key).finalize().into_bytes())
+    }
+
+    /// Fields are separated by NUL, which can't appear in any of them
+    fn to_bytes(&self) -> Vec<u8> {
+        [
+            SIGNATURE_VERSION,
+            self.target.as_str(),
+            self.symbol.as_str(),
+            self.lints.as_str(),
+            self.function.as_str(),
+            self.src_sha256.as_str(),
+            self.trusted_pgx_version.as_str(),
+            self.build_inputs_sha256.as_str(),
+            self.so_sha256.as_str(),
+        ]
+        .join("\0")
+        .into_bytes()
+    }
+}
@@ -15,8 +15,12 @@ use color_eyre::{Section, SectionExt};
 use eyre::{eyre, WrapErr};
 use pgx::pg_sys;
 
+use crate::gucs::get_trusted_pgx_version;
+use crate::signing::Claims;
 use crate::target::{CompilationTarget, CrossCompilationTarget};
-use crate::user_crate::cargo::cargo;
+use crate::user_crate::artifact_format::{sha256_hex, BuildInputs};
+use crate::user_crate::cargo::{cargo, plrustc_version, rustflags};
+use crate::user_crate::dependencies::DependencyLints;
 use crate::user_crate::lint::LintSet;
 use crate::{
     gucs,
@@ -33,12 +37,69 @@ pub(crate) struct FnBuild {
     generation_number: u64,
     db_oid: pg_sys::Oid,
     fn_oid: pg_sys::Oid,
+    /// the crate, and the function it exports, are both named after the function's content
+    crate_name: String,
     crate_dir: PathBuf,
     lints: LintSet,
+    claims: Claims,
+    /// the dependencies to compile with the `plrust_lints` group, with `plrust.dependency_lints`
+    dependency_lints: Option<DependencyLints>,
+    /// see [`BuildInputs::allow_list_sha256`]
+    allow_list_sha256: Option<String>,
 }
 
 impl CrateState for FnBuild {}
 
+/// A message from the compiler about the user's function.  Line and column numbers are relative to
+/// the generated `lib.rs`, which `rendered` quotes.
+#[derive(Debug, Clone)]
+pub(crate) struct Diagnostic {
+    /// `error`, `warning`, `note`, or `help`
+    pub(crate) level: String,
+    /// the lint or error code, such as `unsafe_code` or `E0308`
+    pub(crate) code: Option<String>,
+    pub(crate) message: String,
+    pub(crate) line: Option<i32>,
+    pub(crate) column: Option<i32>,
+    /// the diagnostic as the compiler would print it
+    pub(crate) rendered: Option<String>,
+}
+
+impl Diagnostic {
+    /// An error that didn't come from the compiler
+    pub(crate) fn error(message: String) -> Self {
+        Diagnostic {
+            level: "error".to_string(),
+            code: None,
+            message,
+            line: None,
+            column: None,
+            rendered: None,
+        }
+    }
+
+    /// From the `message` of a `cargo --message-format=json` "compiler-message"
+    fn from_compiler_message(message: &serde_json::Value) -> Self {
+        let primary_span = message["spans"]
+            .as_array()
+            .and_then(|spans| spans.iter().find(|span| span["is_primary"] == true));
+        let span_field = |field: &str| {
+            primary_span
+                .and_then(|span| span[field].as_i64())
+                .map(|n| n as i32)
+        };
+
+        Diagnostic {
+            level: message["level"].as_str().unwrap_or("error").to_string(),
+            code: message["code"]["code"].as_str().map(String::from),
+            message: message["message"].as_str().unwrap_or_default().to_string(),
+            line: span_field("line_start"),
+            column: span_field("column_start"),
+            rendered: message["rendered"].as_str().map(String::from),
+        }
+    }
+}
+
 impl FnBuild {
     #[tracing::instrument(level = "debug", skip_all, fields(db_oid = %db_oid, fn_oid = %fn_oid, crate_name = %crate_name, crate_dir = %crate_dir.display()))]
     pub(crate) fn new(
@@ -48,18 +109,25 @@ impl FnBuild {
         crate_name: String,
         crate_dir: PathBuf,
         lints: LintSet,
+        claims: Claims,
+        dependency_lints: Option<DependencyLints>,
+        allow_list_sha256: Option<String>,
     ) -> Self {
         Self {
             generation_number,
             db_oid,
             fn_oid,
+            crate_name,
             crate_dir,
             lints,
+            claims,
+            dependency_lints,
+            allow_list_sha256,
         }
     }
 
-    fn user_crate_name(&self) -> String {
-        crate::plrust::crate_name(self.db_oid, self.fn_oid, self.generation_number)
+    fn user_crate_name(&self) -> &str {
+        &self.crate_name
     }
 
     #[tracing::instrument(
@@ -73,14 +141,20 @@ impl FnBuild {
         ))]
     pub(crate) fn build(self, target_dir: &Path) -> eyre::Result<Vec<(FnLoad, Output)>> {
         let (this_target, cross_compilation_targets) = gucs::compilation_targets()?;
+        let toolchain = plrustc_version()?;
         let mut results = Vec::new();
 
         // always build for this host machine
-        results.push(self.build_internal(target_dir, this_target.clone(), None)?);
+        results.push(self.build_internal(target_dir, this_target.clone(), None, &toolchain)?);
 
         // and then do the others, which is guaranteed not to contain the exact same triple as `this_target`
         for target in cross_compilation_targets {
-            results.push(self.build_internal(target_dir, target.target(), Some(target))?);
+            results.push(self.build_internal(
+                target_dir,
+                target.target(),
+                Some(target),
+                &toolchain,
+            )?);
         }
         Ok(results)
     }
@@ -101,9 +175,11 @@ impl FnBuild {
         cargo_target_dir: &Path,
         target_triple: CompilationTarget,
         cross_compilation_target: Option<CrossCompilationTarget>,
+        toolchain: &str,
     ) -> eyre::Result<(FnLoad, Output)> {
         let mut command = cargo(cargo_target_dir, cross_compilation_target)?;
         set_plrustc_vars(&mut command, self, cargo_target_dir)?;
+        set_rustflags(&mut command, self)?;
 
         command.current_dir(&self.crate_dir);
         command.arg("rustc");
@@ -112,6 +188,7 @@ impl FnBuild {
         command.arg(&target_triple);
 
         let output = command.output().wrap_err("`cargo` execution failure")?;
+        crate::history::capture_cargo_output(&target_triple, &output);
 
         if output.status.success() {
             let so_bytes = {
@@ -126,15 +203,37 @@ impl FnBuild {
                 std::fs::read(&so_path)?
             };
 
+            let symbol = self.crate_name.clone();
+            let build_inputs = BuildInputs {
+                toolchain: toolchain.to_string(),
+                trusted_pgx_version: get_trusted_pgx_version(),
+                lockfile_sha256: std::fs::read(self.crate_dir.join("Cargo.lock"))
+                    .ok()
+                    .map(|lockfile| sha256_hex(&lockfile)),
+                dependency_lints: self.dependency_lints.clone(),
+                allow_list_sha256: self.allow_list_sha256.clone(),
+            };
+            let signature = crate::signing::sign(
+                &target_triple,
+                Some(&symbol),
+                &self.lints,
+                &self.claims,
+                Some(&build_inputs),
+                &so_bytes,
+            )?;
+
             Ok((
                 FnLoad::new(
                     self.generation_number,
                     self.db_oid,
                     self.fn_oid,
                     target_triple,
-                    Some(crate::plrust::symbol_name(self.db_oid, self.fn_oid)),
+                    Some(symbol),
                     so_bytes,
                     self.lints.clone(),
+                    self.claims.clone(),
+                    signature,
+                    Some(build_inputs),
                 ),
                 output,
             ))
@@ -152,15 +251,67 @@ impl FnBuild {
                         .header("Source Code:")
                 }));
 
-            // Clean up on error but don't let this error replace our user's error!
-            if let Err(e) = std::fs::remove_dir_all(&self.crate_dir) {
-                pgx::log!("Problem during removing crate directory: {e}")
-            };
-
             err?
         }
     }
 
+    /// Type-check and lint the crate for this host's target, without producing a shared library,
+    /// and return the compiler's diagnostics.  Anything `cargo` wrote for the crate is removed
+    /// afterwards, but not the crate itself, see [`UserCrate::remove_crate_dir_on_drop`].
+    ///
+    /// [`UserCrate::remove_crate_dir_on_drop`]: crate::user_crate::UserCrate::remove_crate_dir_on_drop
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            db_oid = %self.db_oid,
+            fn_oid = %self.fn_oid,
+            crate_dir = %self.crate_dir.display(),
+            target_dir = tracing::field::display(cargo_target_dir.display()),
+        ))]
+    pub(crate) fn check(self, cargo_target_dir: &Path) -> eyre::Result<Vec<Diagnostic>> {
+        let this_target = crate::target::tuple()?;
+        let mut command = cargo(cargo_target_dir, None)?;
+        set_plrustc_vars(&mut command, &self, cargo_target_dir)?;
+        set_rustflags(&mut command, &self)?;
+
+        command.current_dir(&self.crate_dir);
+        command.arg("check");
+        command.arg("--release");
+        command.arg("--target");
+        command.arg(this_target);
+        command.arg("--message-format=json");
+
+        let output = command.output().wrap_err("`cargo` execution failure");
+
+        // forget the metadata `cargo check` left in the shared target directory
+        if let Ok(mut clean) = cargo(cargo_target_dir, None) {
+            clean.current_dir(&self.crate_dir);
+            clean.args(["clean", "--release", "--target"]);
+            clean.arg(this_target);
+            clean.args(["-p", self.user_crate_name()]);
+            if let Err(e) = clean.output() {
+                pgx::log!("Problem cleaning checked crate: {e}")
+            }
+        }
+
+        let output = output?;
+        let stdout = String::from_utf8(output.stdout).wrap_err("cargo stdout was not UTF-8")?;
+        let mut diagnostics = stdout
+            .lines()
+            .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+            .filter(|message| message["reason"] == "compiler-message")
+            .map(|message| Diagnostic::from_compiler_message(&message["message"]))
+            .collect::<Vec<_>>();
+
+        if !output.status.success() && !diagnostics.iter().any(|d| d.level == "error") {
+            // `cargo` itself failed, likely resolving dependencies, rather than the compiler
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            diagnostics.push(Diagnostic::error(stderr.trim().to_string()));
+        }
+        Ok(diagnostics)
+    }
+
     // for #[tracing] purposes
     pub(crate) fn fn_oid(&self) -> pg_sys::Oid {
         self.fn_oid
@@ -200,3 +351,35 @@ fn set_plrustc_vars(command: &mut Command, build: &FnBuild, target_dir: &Path) -
 
     Ok(())
 }
+
+/// Keep machine-specific paths out of the compiled artifact so that two servers compiling the same
+/// function produce the same bytes, and have `plrustc` lint the dependencies it should.
+///
+/// Every user crate is provisioned under the same work directory, so remapping that directory (rather
+/// than each crate's own directory) keeps `RUSTFLAGS` identical across functions and lets `cargo`
+/// reuse already-compiled dependencies.  We use `CARGO_ENCODED_RUSTFLAGS` because these paths may
+/// contain spaces.
+fn set_rustflags(command: &mut Command, build: &FnBuild) -> eyre::Result<()> {
+    let mut flags = rustflags();
+    if let Some(work_dir) = build.crate_dir.parent() {
+        flags.push(format!(
+            "--remap-path-prefix={}=/plrust",
+            path2string(work_dir)?
+        ));
+    }
+    if let Ok(cargo_home) = home::cargo_home() {
+        flags.push(format!(
+            "--remap-path-prefix={}=/cargo",
+            path2string(&cargo_home)?
+        ));
+    }
+    if let Some(flag) = build
+        .dependency_lints
+        .as_ref()
+        .and_then(DependencyLints::rustflag)
+    {
+        flags.push(flag);
+    }
+    command.env("CARGO_ENCODED_RUSTFLAGS", flags.join("\x1f"));
+    Ok(())
+}
@@ -11,6 +11,7 @@ use std::ffi::CStr;
 use std::path::Path;
 use std::process::Command;
 
+use eyre::WrapErr;
 use pgx::{pg_sys, PgMemoryContexts};
 
 use crate::gucs::PLRUST_PATH_OVERRIDE;
@@ -29,15 +30,40 @@ pub(crate) fn cargo(
     sanitize_env(&mut command);
 
     command.env("CARGO_TARGET_DIR", &cargo_target_dir);
+    // Don't use `env_remove` to avoid inheriting rustflags via the normal
+    // search.
+    command.env("RUSTFLAGS", rustflags().join(" "));
+
+    Ok(command)
+}
+
+/// The `RUSTFLAGS` every `cargo` execution needs
+pub(crate) fn rustflags() -> Vec<String> {
     if cfg!(target_os = "macos") {
-        command.env("RUSTFLAGS", "-Clink-args=-Wl,-undefined,dynamic_lookup");
+        vec!["-Clink-args=-Wl,-undefined,dynamic_lookup".into()]
     } else {
-        // Don't use `env_remove` to avoid inheriting rustflags via the normal
-        // search.
-        command.env("RUSTFLAGS", "");
+        vec![]
     }
+}
 
-    Ok(command)
+/// The output of `plrustc -vV`, which identifies the exact compiler (and its LLVM) that builds user
+/// functions
+pub(crate) fn plrustc_version() -> eyre::Result<String> {
+    let mut command = Command::new("plrustc");
+    configure_path(&mut command)?;
+    sanitize_env(&mut command);
+    command.arg("-vV");
+
+    let output = command
+        .output()
+        .wrap_err("unable to execute `plrustc -vV`")?;
+    if !output.status.success() {
+        eyre::bail!(
+            "`plrustc -vV` failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
 
 /// `cargo` needs a PATH in order to find its tools and we have some rules about setting that up...
@@ -127,6 +153,7 @@ fn sanitize_env(command: &mut Command) {
     command.env_remove("OUT_DIR"); // rust's default decision for OUT_DIR is perfectly acceptable to PL/Rust
     command.env_remove("RUSTC_WRAPPER"); // plrustc doesn't like being invoked with RUSTC_WRAPPER set.
     command.env_remove("RUSTC_WORKSPACE_WRAPPER"); // ditto.
+    command.env_remove("CARGO_ENCODED_RUSTFLAGS"); // would take precedence over the RUSTFLAGS we set
 }
 
 /// Asks Postgres, via FFI, for all of its compile-time configuration data.  This is the full
@@ -0,0 +1,259 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Generating the `lib.rs` and `Cargo.toml` of a user function's crate.
+//!
+//! Nothing in here may depend on pgx, or on anything else that only works inside a Postgres
+//! backend, because `plrust-compile` includes this file as-is to generate exactly the same crates
+//! outside of Postgres.
+use eyre::WrapErr;
+use quote::{quote, ToTokens};
+
+/// The shape of the Rust function generated around the user's code
+pub(crate) enum UserFn<'a> {
+    Function {
+        arguments: &'a [syn::FnArg],
+        return_type: &'a syn::Type,
+    },
+    Trigger,
+}
+
+/// A user function argument named `name` of the Rust type `bare`, which is optional unless the
+/// function is `STRICT`
+pub(crate) fn argument(
+    name: &syn::Ident,
+    bare: &syn::Type,
+    is_strict: bool,
+) -> eyre::Result<syn::FnArg> {
+    let rust_type: syn::Type = match is_strict {
+        true => bare.clone(),
+        false => syn::parse2(quote! {
+            Option<#bare>
+        })
+        .wrap_err("Wrapping argument type")?,
+    };
+
+    syn::parse2(quote! {
+        #name: #rust_type
+    })
+    .wrap_err("Making argument pattern type")
+}
+
+/// A user function's return type, returning the Rust type `bare`, or a set of them
+pub(crate) fn return_type(bare: &syn::Type, return_set: bool) -> eyre::Result<syn::Type> {
+    match return_set {
+        true => syn::parse2(quote! { ::std::result::Result<Option<::pgx::iter::SetOfIterator<'a, Option<#bare>>>, Box<dyn std::error::Error + Send + Sync + 'static>> })
+            .wrap_err("Wrapping return type"),
+        false => syn::parse2(
+            quote! { ::std::result::Result<Option<#bare>, Box<dyn std::error::Error + Send + Sync + 'static>> },
+        )
+        .wrap_err("Wrapping return type"),
+    }
+}
+
+/// The generated function's Rust signature.  It reflects the SQL function's argument and return
+/// types, and whether it's `STRICT` or returns a `SETOF`, which is everything about the function
+/// its compiled artifact depends on besides the user's code
+pub(crate) fn signature(user_fn: &UserFn) -> String {
+    match user_fn {
+        UserFn::Function {
+            arguments,
+            return_type,
+        } => quote! { fn(#( #arguments ),*) -> #return_type }.to_string(),
+        UserFn::Trigger => "trigger".to_string(),
+    }
+}
+
+/// The name of the generated function, and of the crate it's compiled in, for a function with the
+/// given [`signature`] and source code.  It's derived from nothing else -- not the function's Oid,
+/// nor the server it's compiled on -- so that compiling the same function produces the same bytes.
+pub(crate) fn symbol_name(signature: &str, src: &str) -> String {
+    let hash = super::artifact_format::sha256_hex(format!("{signature}\0{src}").as_bytes());
+    format!("plrust_fn_{}", &hash[..16])
+}
+
+/// Generates the lib.rs of a user function's crate, with the user's code as the body of a function
+/// named `symbol_name`, compiled under `lints`
+pub(crate) fn lib_rs(
+    symbol_name: &str,
+    user_fn: UserFn,
+    user_code: &syn::Block,
+    lints: &impl ToTokens,
+) -> eyre::Result<syn::File> {
+    let symbol_ident = proc_macro2::Ident::new(symbol_name, proc_macro2::Span::call_site());
+    let (bare_fn, entry_attr): (syn::ItemFn, syn::Attribute) = match user_fn {
+        UserFn::Function {
+            arguments,
+            return_type,
+        } => (
+            syn::parse2(quote! {
+                fn #symbol_ident<'a>(
+                    #( #arguments ),*
+                ) -> #return_type
+                #user_code
+            })
+            .wrap_err("Parsing generated user function")?,
+            syn::parse_quote! { #[pg_extern] },
+        ),
+        UserFn::Trigger => (
+            syn::parse2(quote! {
+                fn #symbol_ident<'a>(
+                    trigger: &'a ::pgx::PgTrigger<'a>,
+                ) -> ::core::result::Result<
+                    Option<::pgx::heap_tuple::PgHeapTuple<'a, impl ::pgx::WhoAllocated>>,
+                    Box<dyn std::error::Error>,
+                > #user_code
+            })
+            .wrap_err("Parsing generated user trigger")?,
+            syn::parse_quote! { #[pg_trigger] },
+        ),
+    };
+
+    let opened = unsafe_mod(bare_fn.clone(), entry_attr)?;
+    let forbidden = safe_mod(bare_fn, lints)?;
+    compose_lib_from_mods([opened, forbidden])
+}
+
+/// Throw all the libs into this, we will write this once.
+fn compose_lib_from_mods<const N: usize>(modules: [syn::ItemMod; N]) -> eyre::Result<syn::File> {
+    let mut skeleton: syn::File = syn::parse2(quote! {
+        #![deny(unsafe_op_in_unsafe_fn)]
+    })
+    .wrap_err("Generating lib skeleton")?;
+
+    for module in modules {
+        skeleton.items.push(module.into());
+    }
+    Ok(skeleton)
+}
+
+/// Used by both the unsafe and safe module.
+pub(crate) fn shared_imports() -> syn::ItemUse {
+    syn::parse_quote!(
+        // we (plrust + pgx) fully qualify all pgx imports with `::pgx`, so if the user's function
+        // doesn't use any other pgx items we don't want a compiler warning
+        #[allow(unused_imports)]
+        use pgx::prelude::*;
+    )
+}
+
+fn unsafe_mod(
+    mut called_fn: syn::ItemFn,
+    entry_attr: syn::Attribute,
+) -> eyre::Result<syn::ItemMod> {
+    let imports = shared_imports();
+    called_fn.attrs.push(entry_attr);
+
+    // Use pub mod so that symbols inside are found, opened, and called
+    syn::parse2(quote! {
+        pub mod opened {
+            #imports
+
+            #[allow(unused_lifetimes)]
+            #called_fn
+        }
+    })
+    .wrap_err("Could not create opened module")
+}
+
+fn safe_mod(bare_fn: syn::ItemFn, lints: &impl ToTokens) -> eyre::Result<syn::ItemMod> {
+    let imports = shared_imports();
+
+    syn::parse2(quote! {
+        #[deny(unknown_lints)]
+        mod forbidden {
+            #lints
+            #imports
+
+            #[allow(unused_lifetimes)]
+            #bare_fn
+        }
+    })
+    .wrap_err("Could not create forbidden module")
+}
+
+/// The `Cargo.toml` of a user function's crate, before the user's dependencies are added
+pub(crate) fn cargo_toml_template(
+    crate_name: &str,
+    version_feature: &str,
+    trusted_pgx_version: &str,
+) -> toml::Table {
+    let mut toml = toml::toml! {
+        [package]
+        edition = "2021"
+        name = crate_name
+        version = "0.0.0"
+
+        [features]
+        default = [version_feature]
+
+        [lib]
+        crate-type = ["cdylib"]
+
+        [dependencies]
+        pgx = { version = trusted_pgx_version, package = "plrust-trusted-pgx" }
+
+        /* User deps added here */
+
+        [profile.release]
+        codegen-units = 1_usize
+        debug-assertions = true
+        opt-level = 3_usize
+        panic = "unwind"
+        strip = true
+    };
+
+    // if the `PLRUST_TRUSTED_PGX_OVERRIDE` environment variable is set at compile time
+    // we'll use that for the `pgx = ` line in the toml file.  This is really a plrust-developer
+    // convenience to allow tweaking where "plrust-trusted-pgx" is found during CI runs
+    //
+    // An example of how to use this is:
+    //
+    // ```
+    // $ cd plrust
+    // $ PLRUST_TRUSTED_PGX_OVERRIDE="pgx = { path = '~/code/plrust/plrust-trusted-pgx', package='plrust-trusted-pgx' }" \
+    // cargo pgx run
+    // ```
+    if let Some(trusted_pgx_override) = option_env!("PLRUST_TRUSTED_PGX_OVERRIDE") {
+        if let Some(toml::Value::Table(dependencies)) = toml.get_mut("dependencies") {
+            let new_dependencies = trusted_pgx_override
+                .parse::<toml::Table>()
+                .expect("failed to parse new dependency block using `PLRUST_TRUSTED_PGX_OVERRIDE`");
+
+            *dependencies = new_dependencies;
+        }
+    }
+
+    toml
+}
+
+/// Split the user's function source into its code, as a block, and its `[dependencies]` section
+pub(crate) fn split_source_and_deps(code_and_deps: &str) -> (String, String) {
+    enum Parse {
+        Code,
+        Deps,
+    }
+
+    let mut deps_block = String::new();
+    let mut code_block = String::from("{ ");
+    let mut parse = Parse::Code;
+
+    for line in code_and_deps.trim().split_inclusive('\n') {
+        match line.trim() {
+            "[dependencies]" => parse = Parse::Deps,
+            "[code]" => parse = Parse::Code,
+            _ => match parse {
+                Parse::Code => code_block.push_str(line),
+                Parse::Deps => deps_block.push_str(line),
+            },
+        }
+    }
+
+    code_block.push_str("\n}");
+    (code_block, deps_block)
+}
@@ -6,10 +6,10 @@ All rights reserved.
 Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
 */
 
-use crate::{user_crate::oid_to_syn_type, PlRustError};
-use eyre::WrapErr;
-use pgx::PgOid;
-use quote::quote;
+use crate::pgproc::PgProc;
+use crate::user_crate::codegen::{self, UserFn};
+use crate::user_crate::oid_to_syn_type;
+use pgx::{pg_sys, PgOid};
 
 /// What kind of PL/Rust function must be built
 
@@ -41,36 +41,11 @@ impl CrateVariant {
     ) -> eyre::Result<Self> {
         let mut arguments = Vec::new();
         for (argument_oid, arg_name) in argument_oids_and_names.into_iter() {
-            let rust_type: syn::Type = {
-                let bare = oid_to_syn_type(&argument_oid, false)?;
-                match is_strict {
-                    true => bare,
-                    false => syn::parse2(quote! {
-                        Option<#bare>
-                    })
-                    .wrap_err("Wrapping argument type")?,
-                }
-            };
-
-            let rust_pat_type: syn::FnArg = syn::parse2(quote! {
-                #arg_name: #rust_type
-            })
-            .map_err(PlRustError::Parse)
-            .wrap_err("Making argument pattern type")?;
-            arguments.push(rust_pat_type);
+            let bare = oid_to_syn_type(&argument_oid, false)?;
+            arguments.push(codegen::argument(&arg_name, &bare, is_strict)?);
         }
 
-        let return_type: syn::Type = {
-            let bare = oid_to_syn_type(&return_oid, true)?;
-            match return_set {
-                true => syn::parse2(quote! { ::std::result::Result<Option<::pgx::iter::SetOfIterator<'a, Option<#bare>>>, Box<dyn std::error::Error + Send + Sync + 'static>> })
-                    .wrap_err("Wrapping return type")?,
-                false => syn::parse2(
-                    quote! { ::std::result::Result<Option<#bare>, Box<dyn std::error::Error + Send + Sync + 'static>> },
-                )
-                .wrap_err("Wrapping return type")?,
-            }
-        };
+        let return_type = codegen::return_type(&oid_to_syn_type(&return_oid, true)?, return_set)?;
 
         Ok(Self::Function {
             arguments,
@@ -85,4 +60,63 @@ impl CrateVariant {
     pub(crate) fn trigger() -> Self {
         Self::Trigger
     }
+
+    /// The variant for the function described by `meta`, as it's currently defined
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub(crate) fn from_pg_proc(meta: &PgProc) -> eyre::Result<Self> {
+        if meta.prorettype() == pg_sys::TRIGGEROID {
+            return Ok(CrateVariant::trigger());
+        }
+
+        let argnames = meta.proargnames();
+        let argtypes = meta.proargtypes();
+
+        // quick fix for issue #197 (and likely related problems) -- we don't yet support these things
+        let argmodes = meta.proargmodes();
+        if argmodes.contains(&('t' as i8)) {
+            todo!("RETURNS TABLE functions")
+        } else if argmodes.contains(&('o' as i8)) {
+            todo!("OUT arguments")
+        } else if argmodes.contains(&('b' as i8)) {
+            todo!("INOUT arguments")
+        }
+
+        // we must have the same number of argument names and argument types.  It's seemingly
+        // impossible that we never would, but lets make sure as it's an invariant from this
+        // point forward
+        assert_eq!(argnames.len(), argtypes.len());
+
+        let argument_oids_and_names = argtypes
+            .into_iter()
+            .map(|oid| PgOid::from(oid))
+            .zip(argnames.into_iter())
+            .collect();
+
+        CrateVariant::function(
+            argument_oids_and_names,
+            PgOid::from(meta.prorettype()),
+            meta.proretset(),
+            meta.proisstrict(),
+        )
+    }
+
+    /// The shape of the Rust function to generate for this variant
+    pub(crate) fn user_fn(&self) -> UserFn {
+        match self {
+            CrateVariant::Function {
+                arguments,
+                return_type,
+                ..
+            } => UserFn::Function {
+                arguments,
+                return_type,
+            },
+            CrateVariant::Trigger => UserFn::Trigger,
+        }
+    }
+
+    /// See [`codegen::signature`]
+    pub(crate) fn signature(&self) -> String {
+        codegen::signature(&self.user_fn())
+    }
 }
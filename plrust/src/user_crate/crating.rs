@@ -6,15 +6,19 @@ All rights reserved.
 Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
 */
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use eyre::WrapErr;
-use pgx::{pg_sys, PgOid};
-use quote::quote;
+use pgx::{pg_sys, PgOid, Spi};
 
-use crate::gucs::get_trusted_pgx_version;
+use crate::gucs::{self, get_trusted_pgx_version};
 use crate::pgproc::PgProc;
+use crate::prosrc::maybe_extract_source_from_json;
+use crate::signing::Claims;
+use crate::user_crate::codegen;
+pub(crate) use crate::user_crate::codegen::shared_imports;
 use crate::user_crate::lint::{compile_lints, LintSet};
+use crate::user_crate::lockfile::Lockfile;
 use crate::{
     user_crate::{parse_source_and_deps, CrateState, CrateVariant, FnVerify},
     PlRustError,
@@ -34,6 +38,15 @@ pub(crate) struct FnCrating {
     user_dependencies: toml::value::Table,
     user_code: syn::Block,
     variant: CrateVariant,
+    /// see [`codegen::symbol_name`]
+    symbol_name: String,
+    /// what the compiled artifact's signature will vouch for
+    claims: Claims,
+    /// the parts of `plrust.allowed_dependencies` that apply to the function, if there's an allow-list
+    allow_list: Option<toml::value::Table>,
+    /// the `Cargo.lock` from the function's previous compilation, to resolve its dependencies the
+    /// same way again
+    lockfile: Option<String>,
 }
 
 impl FnCrating {
@@ -46,6 +59,9 @@ impl FnCrating {
         user_code: syn::Block,
         variant: CrateVariant,
     ) -> Self {
+        let src = quote::ToTokens::to_token_stream(&user_code).to_string();
+        let symbol_name = codegen::symbol_name(&variant.signature(), &src);
+        let claims = Claims::new(variant.signature(), &src, get_trusted_pgx_version());
         Self {
             generation_number,
             db_oid,
@@ -53,6 +69,10 @@ impl FnCrating {
             user_dependencies: user_deps.into(),
             user_code,
             variant,
+            symbol_name,
+            claims,
+            allow_list: None,
+            lockfile: None,
         }
     }
 
@@ -63,90 +83,88 @@ impl FnCrating {
     ) -> eyre::Result<Self> {
         let meta = PgProc::new(fn_oid)?;
         let generation_number = meta.generation_number();
-        let (user_code, user_dependencies) = parse_source_and_deps(&meta.prosrc())?;
+        let schema = meta.namespace_name();
+        let (user_code, user_dependencies, allow_list) =
+            parse_source_and_deps(&meta.prosrc(), meta.proowner(), schema.as_deref())?;
+        let lockfile = crate::prosrc::stored_lockfile(fn_oid, &meta)?;
 
-        let variant = match meta.prorettype() == pg_sys::TRIGGEROID {
+        let variant = CrateVariant::from_pg_proc(&meta)?;
+        let src = maybe_extract_source_from_json(&meta.prosrc());
+        let symbol_name = codegen::symbol_name(&variant.signature(), &src);
+        let claims = Claims::new(variant.signature(), &src, get_trusted_pgx_version());
+
+        Ok(Self {
+            generation_number,
+            db_oid,
+            fn_oid,
+            user_code,
+            user_dependencies,
+            variant,
+            symbol_name,
+            claims,
+            allow_list,
+            lockfile,
+        })
+    }
+
+    /// For checking PL/Rust source that isn't (yet) a function.  `fn_oid` is [`pg_sys::InvalidOid`]
+    /// and the caller must pick a `generation_number` that won't collide with other checks.
+    #[tracing::instrument(level = "debug", skip(source))]
+    pub(crate) fn try_from_source(
+        db_oid: pg_sys::Oid,
+        generation_number: u64,
+        source: &str,
+        argument_oids_and_names: Vec<(PgOid, syn::Ident)>,
+        return_oid: PgOid,
+        return_set: bool,
+        is_strict: bool,
+    ) -> eyre::Result<Self> {
+        // it's checked as if the current user were creating it in the current schema
+        let schema = Spi::get_one::<String>("SELECT current_schema()")?;
+        // SAFETY:  Postgres sets the current user before we're ever called
+        let owner = unsafe { pg_sys::GetUserId() };
+        let (user_code, user_dependencies, allow_list) =
+            parse_source_and_deps(source, owner, schema.as_deref())?;
+        let variant = match return_oid.value() == pg_sys::TRIGGEROID {
             true => CrateVariant::trigger(),
             false => {
-                let argnames = meta.proargnames();
-                let argtypes = meta.proargtypes();
-
-                // quick fix for issue #197 (and likely related problems) -- we don't yet support these things
-                let argmodes = meta.proargmodes();
-                if argmodes.contains(&('t' as i8)) {
-                    todo!("RETURNS TABLE functions")
-                } else if argmodes.contains(&('o' as i8)) {
-                    todo!("OUT arguments")
-                } else if argmodes.contains(&('b' as i8)) {
-                    todo!("INOUT arguments")
-                }
-
-                // we must have the same number of argument names and argument types.  It's seemingly
-                // impossible that we never would, but lets make sure as it's an invariant from this
-                // point forward
-                assert_eq!(argnames.len(), argtypes.len());
-
-                let argument_oids_and_names = argtypes
-                    .into_iter()
-                    .map(|oid| PgOid::from(oid))
-                    .zip(argnames.into_iter())
-                    .collect();
-
-                CrateVariant::function(
-                    argument_oids_and_names,
-                    PgOid::from(meta.prorettype()),
-                    meta.proretset(),
-                    meta.proisstrict(),
-                )?
+                CrateVariant::function(argument_oids_and_names, return_oid, return_set, is_strict)?
             }
         };
+        let symbol_name = codegen::symbol_name(&variant.signature(), source);
+        let claims = Claims::new(variant.signature(), source, get_trusted_pgx_version());
 
         Ok(Self {
             generation_number,
             db_oid,
-            fn_oid,
+            fn_oid: pg_sys::InvalidOid,
             user_code,
             user_dependencies,
             variant,
+            symbol_name,
+            claims,
+            allow_list,
+            lockfile: None,
         })
     }
+
+    /// The crate is named after the function it holds, see [`codegen::symbol_name`]
     pub(crate) fn crate_name(&self) -> String {
-        crate::plrust::crate_name(self.db_oid, self.fn_oid, self.generation_number)
+        self.symbol_name.clone()
+    }
+
+    pub(crate) fn symbol_name(&self) -> &str {
+        &self.symbol_name
     }
 
     /// Generates the lib.rs to write
     pub(crate) fn lib_rs(&self) -> eyre::Result<(syn::File, LintSet)> {
-        let symbol_name = crate::plrust::symbol_name(self.db_oid, self.fn_oid);
-        let symbol_ident = proc_macro2::Ident::new(&symbol_name, proc_macro2::Span::call_site());
+        let symbol_name = &self.symbol_name;
         tracing::trace!(symbol_name = %symbol_name, "Generating `lib.rs` for validation step");
 
-        let user_code = &self.user_code;
-        let user_fn: syn::ItemFn = match &self.variant {
-            CrateVariant::Function {
-                ref arguments,
-                ref return_type,
-                ..
-            } => syn::parse2(quote! {
-                fn #symbol_ident<'a>(
-                    #( #arguments ),*
-                ) -> #return_type
-                #user_code
-            })
-            .wrap_err("Parsing generated user function")?,
-            CrateVariant::Trigger => syn::parse2(quote! {
-                fn #symbol_ident<'a>(
-                    trigger: &'a ::pgx::PgTrigger<'a>,
-                ) -> ::core::result::Result<
-                    Option<::pgx::heap_tuple::PgHeapTuple<'a, impl ::pgx::WhoAllocated>>,
-                    Box<dyn std::error::Error>,
-                > #user_code
-            })
-            .wrap_err("Parsing generated user trigger")?,
-        };
-        let opened = unsafe_mod(user_fn.clone(), &self.variant)?;
-        let (forbidden, lints) = safe_mod(user_fn)?;
-
-        Ok((compose_lib_from_mods([opened, forbidden])?, lints))
+        let lints = compile_lints();
+        let lib_rs = codegen::lib_rs(symbol_name, self.variant.user_fn(), &self.user_code, &lints)?;
+        Ok((lib_rs, lints))
     }
 
     #[tracing::instrument(level = "debug", skip_all, fields(db_oid = %self.db_oid, fn_oid = %self.fn_oid))]
@@ -210,11 +228,70 @@ impl FnCrating {
         Ok(cargo_manifest)
     }
 
+    /// The generated `Cargo.toml` and `src/lib.rs`, and the `Cargo.lock` and `.cargo/config.toml`
+    /// when there are any, as [`FnCrating::provision`] would write them, keyed by their path relative
+    /// to the crate directory.
+    ///
+    /// With `trusted_pgx_path`, the `plrust-trusted-pgx` dependency points at that local checkout
+    /// instead of crates.io, so the crate can be opened in an IDE alongside its source.
+    #[tracing::instrument(level = "debug", skip_all, fields(db_oid = %self.db_oid, fn_oid = %self.fn_oid))]
+    pub(crate) fn export(
+        &self,
+        trusted_pgx_path: Option<&str>,
+    ) -> eyre::Result<Vec<(String, String)>> {
+        let (lib_rs, _) = self.lib_rs()?;
+        let mut cargo_toml = self.cargo_toml()?;
+        if let Some(path) = trusted_pgx_path {
+            let Some(toml::Value::Table(dependencies)) = cargo_toml.get_mut("dependencies") else {
+                return Err(PlRustError::GeneratingCargoToml)
+                    .wrap_err("Getting `[dependencies]`")?;
+            };
+            let mut pgx_table = toml::value::Table::new();
+            pgx_table.insert("path".into(), toml::Value::String(path.to_string()));
+            pgx_table.insert(
+                "package".into(),
+                toml::Value::String("plrust-trusted-pgx".to_string()),
+            );
+            dependencies.insert("pgx".into(), toml::Value::Table(pgx_table));
+        }
+
+        let mut files = vec![
+            (
+                "Cargo.toml".to_string(),
+                toml::to_string(&cargo_toml).wrap_err("Stringifying generated `Cargo.toml`")?,
+            ),
+            ("src/lib.rs".to_string(), prettyplease::unparse(&lib_rs)),
+        ];
+        if let Some(lockfile) = &self.lockfile {
+            files.push((
+                "Cargo.lock".to_string(),
+                Lockfile::for_crate(lockfile, &self.crate_name())?,
+            ));
+        }
+        if let Some(source) = gucs::crate_source()? {
+            files.push((
+                ".cargo/config.toml".to_string(),
+                toml::to_string(&source.cargo_config())
+                    .wrap_err("Stringifying the cargo config")?,
+            ));
+        }
+        Ok(files)
+    }
+
+    /// The directory [`FnCrating::provision`] generates the crate in under `parent_dir`
+    pub(crate) fn crate_dir(&self, parent_dir: &Path) -> PathBuf {
+        parent_dir.join(crate::plrust::crate_dir_name(
+            self.db_oid,
+            self.fn_oid,
+            self.generation_number,
+        ))
+    }
+
     /// Provision into a given folder and return the crate directory.
     #[tracing::instrument(level = "debug", skip_all, fields(db_oid = %self.db_oid, fn_oid = %self.fn_oid, parent_dir = %parent_dir.display()))]
     pub(crate) fn provision(&self, parent_dir: &Path) -> eyre::Result<FnVerify> {
         let crate_name = self.crate_name();
-        let crate_dir = parent_dir.join(&crate_name);
+        let crate_dir = self.crate_dir(parent_dir);
         let src_dir = crate_dir.join("src");
         std::fs::create_dir_all(&src_dir).wrap_err(
             "Could not create crate directory in configured `plrust.work_dir` location",
@@ -233,6 +310,18 @@ impl FnCrating {
         )
         .wrap_err("Writing generated `Cargo.toml`")?;
 
+        if let Some(source) = gucs::crate_source()? {
+            source.check_available(&cargo_toml)?;
+            source.configure(&crate_dir)?;
+        }
+        if let Some(lockfile) = &self.lockfile {
+            std::fs::write(
+                crate_dir.join("Cargo.lock"),
+                Lockfile::for_crate(lockfile, &crate_name)?,
+            )
+            .wrap_err("Writing stored `Cargo.lock`")?;
+        }
+
         Ok(FnVerify::new(
             self.generation_number,
             self.db_oid,
@@ -240,132 +329,23 @@ impl FnCrating {
             crate_name,
             crate_dir,
             lints,
+            self.claims.clone(),
+            self.lockfile.clone(),
+            self.allow_list.clone(),
+            gucs::dependency_pinning()?,
         ))
     }
 }
 
-/// Throw all the libs into this, we will write this once.
-fn compose_lib_from_mods<const N: usize>(modules: [syn::ItemMod; N]) -> eyre::Result<syn::File> {
-    let mut skeleton: syn::File = syn::parse2(quote! {
-        #![deny(unsafe_op_in_unsafe_fn)]
-    })
-    .wrap_err("Generating lib skeleton")?;
-
-    for module in modules {
-        skeleton.items.push(module.into());
-    }
-    Ok(skeleton)
-}
-
-/// Used by both the unsafe and safe module.
-pub(crate) fn shared_imports() -> syn::ItemUse {
-    syn::parse_quote!(
-        // we (plrust + pgx) fully qualify all pgx imports with `::pgx`, so if the user's function
-        // doesn't use any other pgx items we don't want a compiler warning
-        #[allow(unused_imports)]
-        use pgx::prelude::*;
-    )
-}
-
 pub(crate) fn cargo_toml_template(crate_name: &str, version_feature: &str) -> toml::Table {
-    let trusted_pgx_version = get_trusted_pgx_version();
-    let mut toml = toml::toml! {
-        [package]
-        edition = "2021"
-        name = crate_name
-        version = "0.0.0"
-
-        [features]
-        default = [version_feature]
-
-        [lib]
-        crate-type = ["cdylib"]
-
-        [dependencies]
-        pgx = { version = trusted_pgx_version, package = "plrust-trusted-pgx" }
-
-        /* User deps added here */
-
-        [profile.release]
-        debug-assertions = true
-        opt-level = 3_usize
-        panic = "unwind"
-    };
-
-    // if the `PLRUST_TRUSTED_PGX_OVERRIDE` environment variable is set at compile time
-    // we'll use that for the `pgx = ` line in the toml file.  This is really a plrust-developer
-    // convenience to allow tweaking where "plrust-trusted-pgx" is found during CI runs
-    //
-    // An example of how to use this is:
-    //
-    // ```
-    // $ cd plrust
-    // $ PLRUST_TRUSTED_PGX_OVERRIDE="pgx = { path = '~/code/plrust/plrust-trusted-pgx', package='plrust-trusted-pgx' }" \
-    // cargo pgx run
-    // ```
-    if let Some(trusted_pgx_override) = option_env!("PLRUST_TRUSTED_PGX_OVERRIDE") {
-        if let Some(toml::Value::Table(dependencies)) = toml.get_mut("dependencies") {
-            let new_dependencies = trusted_pgx_override
-                .parse::<toml::Table>()
-                .expect("failed to parse new dependency block using `PLRUST_TRUSTED_PGX_OVERRIDE`");
-
-            *dependencies = new_dependencies;
-        }
-    }
-
-    toml
-}
-
-fn unsafe_mod(mut called_fn: syn::ItemFn, variant: &CrateVariant) -> eyre::Result<syn::ItemMod> {
-    let imports = shared_imports();
-
-    match variant {
-        CrateVariant::Function { .. } => {
-            called_fn.attrs.push(syn::parse_quote! {
-                #[pg_extern]
-            });
-        }
-        CrateVariant::Trigger => {
-            called_fn.attrs.push(syn::parse_quote! {
-                #[pg_trigger]
-            });
-        }
-    };
-
-    // Use pub mod so that symbols inside are found, opened, and called
-    syn::parse2(quote! {
-        pub mod opened {
-            #imports
-
-            #[allow(unused_lifetimes)]
-            #called_fn
-        }
-    })
-    .wrap_err("Could not create opened module")
-}
-
-fn safe_mod(bare_fn: syn::ItemFn) -> eyre::Result<(syn::ItemMod, LintSet)> {
-    let imports = shared_imports();
-    let lints = compile_lints();
-
-    let code = syn::parse2(quote! {
-        #[deny(unknown_lints)]
-        mod forbidden {
-            #lints
-            #imports
-
-            #[allow(unused_lifetimes)]
-            #bare_fn
-        }
-    })
-    .wrap_err("Could not create forbidden module")?;
-    Ok((code, lints))
+    codegen::cargo_toml_template(crate_name, version_feature, &get_trusted_pgx_version())
 }
 
 #[cfg(any(test, feature = "pg_test"))]
 #[pgx::pg_schema]
 mod tests {
     use pgx::*;
+    use quote::quote;
     use syn::parse_quote;
 
     use super::*;
@@ -401,9 +381,8 @@ mod tests {
                 variant,
             );
 
-            let symbol_name = crate::plrust::symbol_name(db_oid, fn_oid);
             let symbol_ident =
-                proc_macro2::Ident::new(&symbol_name, proc_macro2::Span::call_site());
+                proc_macro2::Ident::new(generated.symbol_name(), proc_macro2::Span::call_site());
 
             let (generated_lib_rs, lints) = generated.lib_rs()?;
             let imports = shared_imports();
@@ -472,9 +451,8 @@ mod tests {
                 variant,
             );
 
-            let symbol_name = crate::plrust::symbol_name(db_oid, fn_oid);
             let symbol_ident =
-                proc_macro2::Ident::new(&symbol_name, proc_macro2::Span::call_site());
+                proc_macro2::Ident::new(generated.symbol_name(), proc_macro2::Span::call_site());
 
             let (generated_lib_rs, lints) = generated.lib_rs()?;
             let imports = shared_imports();
@@ -543,9 +521,8 @@ mod tests {
                 variant,
             );
 
-            let symbol_name = crate::plrust::symbol_name(db_oid, fn_oid);
             let symbol_ident =
-                proc_macro2::Ident::new(&symbol_name, proc_macro2::Span::call_site());
+                proc_macro2::Ident::new(generated.symbol_name(), proc_macro2::Span::call_site());
 
             let (generated_lib_rs, lints) = generated.lib_rs()?;
             let imports = shared_imports();
@@ -605,9 +582,8 @@ mod tests {
                 variant,
             );
 
-            let symbol_name = crate::plrust::symbol_name(db_oid, fn_oid);
             let symbol_ident =
-                proc_macro2::Ident::new(&symbol_name, proc_macro2::Span::call_site());
+                proc_macro2::Ident::new(generated.symbol_name(), proc_macro2::Span::call_site());
 
             let (generated_lib_rs, lints) = generated.lib_rs()?;
             let imports = shared_imports();
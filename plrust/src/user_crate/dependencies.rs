@@ -0,0 +1,910 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! The `[dependencies]` of a user function, and checking them against an allow-list.
+//!
+//! A dependency is either `foo = "1.0"` or a table like
+//! `foo = { version = "1.0", features = ["bar"], default-features = false }`.  Anything that could
+//! build something other than the named crate from crates.io, such as a `path` or `git` source, is
+//! refused.
+//!
+//! Besides the crates allowed to everyone, the allow-list may have `[roles.<role>]` and
+//! `[schemas.<schema>]` sections, each listing more crates in the same format.  A function may use
+//! the crates in the sections for the roles its owner has the privileges of and the section for the
+//! schema it's in, as well as everyone's.
+//!
+//! When there's an allow-list, a crate the function's dependencies need that has a build script, or
+//! is a proc-macro, runs code as the postgres user when it's built, outside of postgrestd.  Each
+//! must be marked as audited with `build_script_ok = true` or `proc_macro_ok = true` in its
+//! allow-list entry for the version that's built.
+//!
+//! With `plrust.dependency_lints`, those crates are also compiled with the `plrust_lints` group,
+//! unless marked `audited = true`.
+//!
+//! Nothing in here may depend on pgx, as `plrust-compile` includes this file as-is.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+
+use eyre::{eyre, WrapErr};
+use serde::{Deserialize, Serialize};
+
+/// The keys of a table-form dependency we support
+const SUPPORTED_KEYS: &[&str] = &["version", "features", "default-features"];
+
+/// The keys of a table-form dependency that would build something other than the named crate from
+/// crates.io
+const FORBIDDEN_KEYS: &[&str] = &[
+    "path",
+    "git",
+    "branch",
+    "tag",
+    "rev",
+    "registry",
+    "registry-index",
+    "package",
+];
+
+/// The longest crate name `cargo` accepts
+const MAX_CRATE_NAME_LEN: usize = 64;
+
+/// The allow-list section of crates allowed to functions owned by a role, or by one of its members
+const ROLES_SECTION: &str = "roles";
+
+/// The allow-list section of crates allowed to functions in a schema
+const SCHEMAS_SECTION: &str = "schemas";
+
+/// The package of the `pgx` dependency every user crate has.  What it needs to build is PL/Rust's
+/// concern, not the allow-list's
+const TRUSTED_PGX_PACKAGE: &str = "plrust-trusted-pgx";
+
+/// The argument `plrustc` takes, in `RUSTFLAGS`, naming the dependency packages, as `name@version`,
+/// to apply the `plrust_lints` group to.  It's in `RUSTFLAGS` so `cargo` rebuilds whatever it changes for
+const LINT_DEPENDENCIES_ARG: &str = "--plrustc-lint-dependencies";
+
+/// A single dependency from a user function's `[dependencies]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UserDependency {
+    pub(crate) name: String,
+    pub(crate) version: String,
+    pub(crate) features: Vec<String>,
+    /// `None` unless the user said whether they want the crate's default features
+    pub(crate) default_features: Option<bool>,
+}
+
+impl Display for UserDependency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} = \"{}\"", self.name, self.version)?;
+        if !self.features.is_empty() {
+            write!(f, " with features {:?}", self.features)?;
+        }
+        if self.default_features == Some(false) {
+            write!(f, " without default features")?;
+        }
+        Ok(())
+    }
+}
+
+impl UserDependency {
+    /// As it's written in the user crate's `Cargo.toml`
+    pub(crate) fn to_toml(&self) -> toml::Value {
+        if self.features.is_empty() && self.default_features.is_none() {
+            return toml::Value::String(self.version.clone());
+        }
+
+        let mut table = toml::value::Table::new();
+        table.insert("version".into(), self.version.clone().into());
+        if !self.features.is_empty() {
+            table.insert("features".into(), self.features.clone().into());
+        }
+        if let Some(default_features) = self.default_features {
+            table.insert("default-features".into(), default_features.into());
+        }
+        toml::Value::Table(table)
+    }
+
+    pub(crate) fn parse(name: &str, value: &toml::Value) -> eyre::Result<Self> {
+        check_crate_name(name)?;
+        match value {
+            toml::Value::String(version) => Ok(UserDependency {
+                name: name.to_string(),
+                version: version.clone(),
+                features: vec![],
+                default_features: None,
+            }),
+            toml::Value::Table(table) => {
+                for key in table.keys() {
+                    if FORBIDDEN_KEYS.contains(&key.as_str()) {
+                        eyre::bail!(
+                            "dependency `{name}` may not specify `{key}`.  Only crates from crates.io are supported"
+                        );
+                    } else if !SUPPORTED_KEYS.contains(&key.as_str()) {
+                        eyre::bail!(
+                            "dependency `{name}` specifies unsupported key `{key}`.  Only {SUPPORTED_KEYS:?} are supported"
+                        );
+                    }
+                }
+
+                let version = match table.get("version") {
+                    Some(toml::Value::String(version)) => version.clone(),
+                    Some(_) => eyre::bail!("dependency `{name}`'s `version` must be a string"),
+                    None => eyre::bail!("dependency `{name}` must specify a `version`"),
+                };
+                let features = match table.get("features") {
+                    None => vec![],
+                    Some(toml::Value::Array(features)) => features
+                        .iter()
+                        .map(|feature| {
+                            feature.as_str().map(String::from).ok_or_else(|| {
+                                eyre!("dependency `{name}`'s `features` must all be strings")
+                            })
+                        })
+                        .collect::<eyre::Result<_>>()?,
+                    Some(_) => {
+                        eyre::bail!("dependency `{name}`'s `features` must be an array of strings")
+                    }
+                };
+                let default_features = match table.get("default-features") {
+                    None => None,
+                    Some(toml::Value::Boolean(default_features)) => Some(*default_features),
+                    Some(_) => {
+                        eyre::bail!("dependency `{name}`'s `default-features` must be true or false")
+                    }
+                };
+
+                Ok(UserDependency {
+                    name: name.to_string(),
+                    version,
+                    features,
+                    default_features,
+                })
+            }
+            _ => Err(eyre!(
+                "dependency {name} with values {value:?} is malformatted.  Only version strings and tables are supported"
+            )),
+        }
+    }
+}
+
+/// Refuse a crate name `cargo` wouldn't accept, which is ASCII letters, digits, `-`, and `_`, at most
+/// 64 of them.  Names are used in paths, so this also keeps them from escaping the directory they're
+/// looked for in
+pub(crate) fn check_crate_name(name: &str) -> eyre::Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_CRATE_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        eyre::bail!(
+            "`{name}` is not a valid crate name.  Crate names are at most {MAX_CRATE_NAME_LEN} ASCII letters, digits, `-`, and `_`"
+        );
+    }
+    Ok(())
+}
+
+/// Parse each of the user's `[dependencies]`
+pub(crate) fn parse_user_dependencies(
+    dependencies: &toml::value::Table,
+) -> eyre::Result<Vec<UserDependency>> {
+    dependencies
+        .iter()
+        .map(|(name, value)| UserDependency::parse(name, value))
+        .collect()
+}
+
+/// One allowed version of a crate in the allow-list.  An entry is either `foo = "1.0.0"`, a table
+/// like `foo = { version = "1.0.0", features = ["bar"], forbidden-features = ["std"] }`, whose
+/// `version` may also be an array of versions, or an array of either of those.
+pub(crate) struct AllowedDependency<'a> {
+    pub(crate) version: semver::Version,
+    /// features users may request
+    pub(crate) features: Vec<&'a str>,
+    /// features that are always enabled, whether requested or not
+    pub(crate) forced_features: Vec<&'a str>,
+    /// features that may never be requested
+    pub(crate) forbidden_features: Vec<&'a str>,
+    /// `Some(false)` if the crate's default features are always disabled
+    pub(crate) default_features: Option<bool>,
+    /// the crate's build script has been audited and may run
+    pub(crate) build_script_ok: bool,
+    /// the crate is a proc-macro that has been audited and may run
+    pub(crate) proc_macro_ok: bool,
+    /// the crate has been audited, and needn't be compiled with the `plrust_lints` group
+    pub(crate) audited: bool,
+}
+
+impl<'a> AllowedDependency<'a> {
+    /// The keys of a table-form allow-list entry
+    const KEYS: &'static [&'static str] = &[
+        "version",
+        "features",
+        "forced-features",
+        "forbidden-features",
+        "default-features",
+        "build_script_ok",
+        "proc_macro_ok",
+        "audited",
+    ];
+
+    /// Every allowed version of the crate `name`
+    fn parse(name: &str, value: &'a toml::Value) -> eyre::Result<Vec<Self>> {
+        match value {
+            toml::Value::String(version) => Ok(vec![AllowedDependency {
+                version: parse_allowed_version(name, version)?,
+                features: vec![],
+                forced_features: vec![],
+                forbidden_features: vec![],
+                default_features: None,
+                build_script_ok: false,
+                proc_macro_ok: false,
+                audited: false,
+            }]),
+            toml::Value::Array(entries) => {
+                let mut allowed = Vec::new();
+                for entry in entries {
+                    if entry.is_array() {
+                        eyre::bail!("allowed dependency `{name}` has nested arrays");
+                    }
+                    allowed.extend(Self::parse(name, entry)?);
+                }
+                Ok(allowed)
+            }
+            toml::Value::Table(entry) => {
+                if let Some(key) = entry.keys().find(|key| !Self::KEYS.contains(&key.as_str())) {
+                    eyre::bail!(
+                        "allowed dependency `{name}` has unsupported key `{key}`.  Only {:?} are supported",
+                        Self::KEYS
+                    );
+                }
+
+                let strings = |key: &str| -> eyre::Result<Vec<&'a str>> {
+                    match entry.get(key) {
+                        None => Ok(vec![]),
+                        Some(toml::Value::String(value)) if key == "version" => Ok(vec![value]),
+                        Some(toml::Value::Array(values)) => values
+                            .iter()
+                            .map(|value| {
+                                value.as_str().ok_or_else(|| {
+                                    eyre!(
+                                        "allowed dependency `{name}`'s `{key}` must all be strings"
+                                    )
+                                })
+                            })
+                            .collect(),
+                        Some(_) => Err(eyre!(
+                            "allowed dependency `{name}`'s `{key}` must be an array of strings"
+                        )),
+                    }
+                };
+
+                let versions = strings("version")?;
+                if versions.is_empty() {
+                    eyre::bail!("allowed dependency `{name}` has no `version`");
+                }
+                let default_features = match entry.get("default-features") {
+                    None => None,
+                    Some(toml::Value::Boolean(default_features)) => Some(*default_features),
+                    Some(_) => eyre::bail!(
+                        "allowed dependency `{name}`'s `default-features` must be true or false"
+                    ),
+                };
+                let flag = |key: &str| match entry.get(key) {
+                    None => Ok(false),
+                    Some(toml::Value::Boolean(flag)) => Ok(*flag),
+                    Some(_) => Err(eyre!(
+                        "allowed dependency `{name}`'s `{key}` must be true or false"
+                    )),
+                };
+                let build_script_ok = flag("build_script_ok")?;
+                let proc_macro_ok = flag("proc_macro_ok")?;
+                let audited = flag("audited")?;
+                let forced_features = strings("forced-features")?;
+                let forbidden_features = strings("forbidden-features")?;
+                if let Some(feature) = forced_features
+                    .iter()
+                    .find(|feature| forbidden_features.contains(feature))
+                {
+                    eyre::bail!(
+                        "allowed dependency `{name}` both forces and forbids feature `{feature}`"
+                    );
+                }
+
+                versions
+                    .into_iter()
+                    .map(|version| {
+                        Ok(AllowedDependency {
+                            version: parse_allowed_version(name, version)?,
+                            features: strings("features")?,
+                            forced_features: forced_features.clone(),
+                            forbidden_features: forbidden_features.clone(),
+                            default_features,
+                            build_script_ok,
+                            proc_macro_ok,
+                            audited,
+                        })
+                    })
+                    .collect()
+            }
+            _ => Err(eyre!(
+                "allowed dependency `{name}` has an unsupported toml format"
+            )),
+        }
+    }
+
+    /// Combine the entries for the same version, as when a crate is allowed both to everyone and to a
+    /// role, so the version may be used in any of the ways each entry allows
+    fn merge_versions(versions: Vec<Self>) -> Vec<Self> {
+        let mut merged: Vec<Self> = Vec::with_capacity(versions.len());
+        for allowed in versions {
+            match merged
+                .iter_mut()
+                .find(|existing| existing.version == allowed.version)
+            {
+                Some(existing) => existing.merge(allowed),
+                None => merged.push(allowed),
+            }
+        }
+        merged
+    }
+
+    /// Allow whatever `other` allows of the same version: any feature either allows or forces, and
+    /// forbid only what both forbid
+    fn merge(&mut self, other: Self) {
+        let union = |features: &mut Vec<&'a str>, other: Vec<&'a str>| {
+            for feature in other {
+                if !features.contains(&feature) {
+                    features.push(feature);
+                }
+            }
+        };
+        union(&mut self.features, other.features);
+        union(&mut self.forced_features, other.forced_features);
+        self.forbidden_features
+            .retain(|feature| other.forbidden_features.contains(feature));
+        self.default_features = match (self.default_features, other.default_features) {
+            (Some(false), Some(false)) => Some(false),
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            _ => None,
+        };
+        self.build_script_ok |= other.build_script_ok;
+        self.proc_macro_ok |= other.proc_macro_ok;
+        self.audited |= other.audited;
+    }
+}
+
+/// Who the crates in a part of the allow-list are allowed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scope<'a> {
+    Everyone,
+    /// Functions owned by a role with the privileges of this one
+    Role(&'a str),
+    /// Functions in this schema
+    Schema(&'a str),
+}
+
+impl Display for Scope<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Everyone => write!(f, "everyone"),
+            Scope::Role(role) => write!(f, "role `{role}`"),
+            Scope::Schema(schema) => write!(f, "schema `{schema}`"),
+        }
+    }
+}
+
+/// Parse every entry of the `allowed` allow-list, returning each crate's allowed versions for
+/// everyone, then for each role and schema it has a section for
+pub(crate) fn parse_allow_list(
+    allowed: &toml::value::Table,
+) -> eyre::Result<Vec<(Scope<'_>, BTreeMap<&str, Vec<AllowedDependency<'_>>>)>> {
+    let mut scopes = vec![(Scope::Everyone, parse_allowed_crates(allowed)?)];
+    let roles =
+        sections(allowed, ROLES_SECTION)?.map(|(role, entries)| (Scope::Role(role), entries));
+    let schemas = sections(allowed, SCHEMAS_SECTION)?
+        .map(|(schema, entries)| (Scope::Schema(schema), entries));
+    for (scope, entries) in roles.chain(schemas) {
+        let entries = entries
+            .as_table()
+            .ok_or_else(|| eyre!("the allow-list for {scope} must be a table"))?;
+        let crates = parse_allowed_crates(entries)
+            .wrap_err_with(|| format!("in the allow-list for {scope}"))?;
+        scopes.push((scope, crates));
+    }
+    Ok(scopes)
+}
+
+/// The crates of a single scope of the allow-list, with the entries for the same version of a crate
+/// merged
+pub(crate) fn parse_allowed_crates(
+    allowed: &toml::value::Table,
+) -> eyre::Result<BTreeMap<&str, Vec<AllowedDependency<'_>>>> {
+    allowed
+        .iter()
+        .filter(|(name, _)| !is_section(name))
+        .map(|(name, entry)| {
+            let versions = AllowedDependency::parse(name, entry)?;
+            Ok((name.as_str(), AllowedDependency::merge_versions(versions)))
+        })
+        .collect()
+}
+
+fn is_section(name: &str) -> bool {
+    name == ROLES_SECTION || name == SCHEMAS_SECTION
+}
+
+/// The `[roles]` or `[schemas]` section of the allow-list, by role or schema name
+fn sections<'a>(
+    allowed: &'a toml::value::Table,
+    section: &str,
+) -> eyre::Result<impl Iterator<Item = (&'a str, &'a toml::Value)>> {
+    let sections = match allowed.get(section) {
+        None => None,
+        Some(toml::Value::Table(sections)) => Some(sections),
+        Some(_) => eyre::bail!("the allow-list's `{section}` must be a table"),
+    };
+    Ok(sections
+        .into_iter()
+        .flatten()
+        .map(|(name, entries)| (name.as_str(), entries)))
+}
+
+/// The allow-list that applies to a single function: the crates allowed to everyone, to each role
+/// `has_privs_of_role` says the function's owner has the privileges of, and to the function's
+/// `schema`.  A crate allowed by more than one of these may be used in any of the ways each allows.
+pub(crate) fn effective_allow_list(
+    allowed: &toml::value::Table,
+    mut has_privs_of_role: impl FnMut(&str) -> bool,
+    schema: Option<&str>,
+) -> eyre::Result<toml::value::Table> {
+    let mut effective = allowed
+        .iter()
+        .filter(|(name, _)| !is_section(name))
+        .map(|(name, entry)| (name.clone(), entry.clone()))
+        .collect::<toml::value::Table>();
+
+    let mut add = |entries: &toml::Value| {
+        for (name, entry) in entries.as_table().into_iter().flatten() {
+            let combined = match effective.remove(name) {
+                None => entry.clone(),
+                Some(existing) => {
+                    let as_array = |value: toml::Value| match value {
+                        toml::Value::Array(values) => values,
+                        value => vec![value],
+                    };
+                    let mut combined = as_array(existing);
+                    combined.extend(as_array(entry.clone()));
+                    toml::Value::Array(combined)
+                }
+            };
+            effective.insert(name.clone(), combined);
+        }
+    };
+    for (role, entries) in sections(allowed, ROLES_SECTION)? {
+        if has_privs_of_role(role) {
+            add(entries);
+        }
+    }
+    if let Some(entries) = schema.and_then(|schema| allowed.get(SCHEMAS_SECTION)?.get(schema)) {
+        add(entries);
+    }
+    Ok(effective)
+}
+
+/// SHA-256 of an allow-list, as [`effective_allow_list`] made it for a function.  It's recorded in
+/// the signed build inputs of the function's artifacts, so an artifact compiled elsewhere is only
+/// trusted if its dependencies were checked against the same allow-list
+pub(crate) fn allow_list_sha256(allow_list: &toml::value::Table) -> String {
+    super::artifact_format::sha256_hex(
+        &serde_json::to_vec(allow_list).expect("unable to serialize the allow-list to json"),
+    )
+}
+
+/// Parse an allow-listed version, which is an exact version, although we also accept `1` and
+/// `1.2` as `1.0.0` and `1.2.0`
+fn parse_allowed_version(name: &str, version: &str) -> eyre::Result<semver::Version> {
+    let version = version.trim();
+    let exact = version.strip_prefix('=').unwrap_or(version);
+    let padded = match exact.split('.').count() {
+        1 => format!("{exact}.0.0"),
+        2 => format!("{exact}.0"),
+        _ => exact.to_string(),
+    };
+    semver::Version::parse(&padded)
+        .map_err(|e| eyre!("allowed dependency `{name}` has an invalid version `{version}`: {e}"))
+}
+
+/// Check `dependencies` against the `allowed` allow-list, as [`effective_allow_list`] made it for
+/// the function, and return them as they should be built:
+/// pinned to the newest allowed version that satisfies the user's version requirement, with whatever
+/// features the allow-list forces on or off.  The error describes every dependency that isn't
+/// allowed, and why.
+pub(crate) fn apply_allow_list(
+    dependencies: &[UserDependency],
+    allowed: &toml::value::Table,
+) -> eyre::Result<Vec<UserDependency>> {
+    let allowed = parse_allowed_crates(allowed)?;
+    let mut disallowed = Vec::new();
+    let mut applied = Vec::with_capacity(dependencies.len());
+    for dependency in dependencies {
+        let name = &dependency.name;
+        let Some(allowed_versions) = allowed.get(name.as_str()) else {
+            disallowed.push(format!("`{name}` is not an allowed dependency"));
+            continue;
+        };
+
+        let req = match semver::VersionReq::parse(&dependency.version) {
+            Ok(req) => req,
+            Err(e) => {
+                disallowed.push(format!(
+                    "`{name}` has an invalid version requirement `{}`: {e}",
+                    dependency.version
+                ));
+                continue;
+            }
+        };
+        let Some(allowed_entry) = allowed_versions
+            .iter()
+            .filter(|allowed| req.matches(&allowed.version))
+            .max_by(|a, b| a.version.cmp(&b.version))
+        else {
+            let versions = allowed_versions
+                .iter()
+                .map(|allowed| allowed.version.to_string())
+                .collect::<Vec<_>>();
+            disallowed.push(format!(
+                "`{dependency}` does not match any allowed version.  Allowed versions are {versions:?}"
+            ));
+            continue;
+        };
+
+        let mut rejected = false;
+        for feature in &dependency.features {
+            if allowed_entry.forbidden_features.contains(&feature.as_str()) {
+                disallowed.push(format!("`{name}` feature `{feature}` is forbidden"));
+                rejected = true;
+            } else if !allowed_entry.features.contains(&feature.as_str())
+                && !allowed_entry.forced_features.contains(&feature.as_str())
+            {
+                disallowed.push(format!(
+                    "`{name}` feature `{feature}` is not allowed.  Allowed features are {:?}",
+                    allowed_entry.features
+                ));
+                rejected = true;
+            }
+        }
+        if allowed_entry.default_features == Some(false)
+            && dependency.default_features == Some(true)
+        {
+            disallowed.push(format!("`{name}`'s default features are not allowed"));
+            rejected = true;
+        }
+        if rejected {
+            continue;
+        }
+
+        let mut features = dependency.features.clone();
+        for forced in &allowed_entry.forced_features {
+            if !features.iter().any(|feature| feature == forced) {
+                features.push(forced.to_string());
+            }
+        }
+        applied.push(UserDependency {
+            name: name.clone(),
+            // build exactly the version that was allowed, not whatever newer one also satisfies the
+            // user's requirement
+            version: format!("={}", allowed_entry.version),
+            features,
+            default_features: match allowed_entry.default_features {
+                Some(false) => Some(false),
+                _ => dependency.default_features,
+            },
+        });
+    }
+
+    if !disallowed.is_empty() {
+        eyre::bail!(
+            "The following dependencies are not allowed:\n    {}",
+            disallowed.join("\n    ")
+        );
+    }
+    Ok(applied)
+}
+
+/// The parts of `cargo metadata --format-version 1` we need
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    packages: Vec<MetadataPackage>,
+    resolve: Resolve,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataPackage {
+    id: String,
+    name: String,
+    version: String,
+    targets: Vec<MetadataTarget>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataTarget {
+    /// such as `lib`, `proc-macro`, or `custom-build` for a build script
+    kind: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Resolve {
+    root: Option<String>,
+    nodes: Vec<ResolveNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResolveNode {
+    id: String,
+    /// the package ids of the node's dependencies, of every kind
+    dependencies: Vec<String>,
+    /// the node's dependencies, with what kinds of dependency they are
+    #[serde(default)]
+    deps: Vec<NodeDep>,
+    /// the features the package is built with, however they came to be enabled
+    #[serde(default)]
+    features: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NodeDep {
+    pkg: String,
+    dep_kinds: Vec<DepKind>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DepKind {
+    /// `None` for a normal dependency, or `build` or `dev`
+    kind: Option<String>,
+}
+
+impl Metadata {
+    fn parse(metadata: &str) -> eyre::Result<Self> {
+        serde_json::from_str(metadata).wrap_err("Parsing `cargo metadata` output")
+    }
+
+    fn package(&self, id: &str) -> Option<&MetadataPackage> {
+        self.packages.iter().find(|package| package.id == id)
+    }
+
+    fn node(&self, id: &str) -> Option<&ResolveNode> {
+        self.resolve.nodes.iter().find(|node| node.id == id)
+    }
+
+    /// The ids of every package reachable from `from`, which are included
+    fn reachable<'a>(&'a self, from: impl IntoIterator<Item = &'a str>) -> BTreeSet<&'a str> {
+        let mut reached = BTreeSet::new();
+        let mut pending = from.into_iter().collect::<Vec<_>>();
+        while let Some(id) = pending.pop() {
+            if !reached.insert(id) {
+                continue;
+            }
+            if let Some(node) = self.node(id) {
+                pending.extend(node.dependencies.iter().map(String::as_str));
+            }
+        }
+        reached
+    }
+
+    /// The ids of the packages reachable from `from` by normal dependencies, which are included,
+    /// other than through a proc-macro.  These are what's compiled for the target, whereas build
+    /// scripts, proc-macros, and what they need are compiled for the host, without our `RUSTFLAGS`
+    fn built_for_target<'a>(
+        &'a self,
+        from: impl IntoIterator<Item = &'a str>,
+    ) -> BTreeSet<&'a str> {
+        let mut reached = BTreeSet::new();
+        let mut pending = from.into_iter().collect::<Vec<_>>();
+        while let Some(id) = pending.pop() {
+            if !reached.insert(id) {
+                continue;
+            }
+            if self
+                .package(id)
+                .map_or(false, |package| package.has_target("proc-macro"))
+            {
+                continue;
+            }
+            if let Some(node) = self.node(id) {
+                pending.extend(
+                    node.deps
+                        .iter()
+                        .filter(|dep| dep.dep_kinds.iter().any(|kind| kind.kind.is_none()))
+                        .map(|dep| dep.pkg.as_str()),
+                );
+            }
+        }
+        reached
+    }
+
+    fn root(&self) -> eyre::Result<&ResolveNode> {
+        match self
+            .resolve
+            .root
+            .as_deref()
+            .and_then(|root| self.node(root))
+        {
+            Some(root) => Ok(root),
+            None => eyre::bail!("`cargo metadata` didn't resolve the user crate"),
+        }
+    }
+
+    /// Every package the user crate's dependencies need, other than those `plrust-trusted-pgx`
+    /// needs, whoever else needs them
+    fn user_packages(&self) -> eyre::Result<Vec<&MetadataPackage>> {
+        let root = self.root()?;
+        let is_trusted_pgx = |id: &&str| {
+            self.package(id)
+                .map_or(false, |package| package.name == TRUSTED_PGX_PACKAGE)
+        };
+        let pgx = self.reachable(
+            root.dependencies
+                .iter()
+                .map(String::as_str)
+                .filter(is_trusted_pgx),
+        );
+        let user = self.reachable(
+            root.dependencies
+                .iter()
+                .map(String::as_str)
+                .filter(|id| !is_trusted_pgx(id)),
+        );
+        Ok(user
+            .difference(&pgx)
+            .filter_map(|id| self.package(id))
+            .collect())
+    }
+}
+
+impl MetadataPackage {
+    fn has_target(&self, kind: &str) -> bool {
+        self.targets
+            .iter()
+            .any(|target| target.kind.iter().any(|k| k == kind))
+    }
+
+    /// Does the package have a library, rather than only a proc-macro or binaries?
+    fn has_lib(&self) -> bool {
+        ["lib", "rlib", "dylib"]
+            .iter()
+            .any(|kind| self.has_target(kind))
+    }
+
+    /// The allow-list entry for the package's version
+    fn allowed<'a, 'b>(
+        &self,
+        allowed: &'b BTreeMap<&str, Vec<AllowedDependency<'a>>>,
+    ) -> Option<&'b AllowedDependency<'a>> {
+        allowed.get(self.name.as_str()).and_then(|versions| {
+            versions
+                .iter()
+                .find(|allowed| allowed.version.to_string() == self.version)
+        })
+    }
+}
+
+/// Check the crates the function's dependencies need to build, as `cargo metadata` describes the
+/// user crate in `metadata`, against the `allowed` allow-list made by [`effective_allow_list`].
+///
+/// [`apply_allow_list`] only sees the features the user asked for, but a crate's default features,
+/// or another crate depending on it, may enable more, so none of the features each crate resolved to
+/// may be forbidden by the allow-list entry for its version.  Any crate with a build script or that's
+/// a proc-macro must be marked audited in that entry.  Crates `plrust-trusted-pgx` needs are exempt,
+/// whoever else needs them.
+pub(crate) fn check_resolved_dependencies(
+    metadata: &str,
+    allowed: &toml::value::Table,
+) -> eyre::Result<()> {
+    let metadata = Metadata::parse(metadata)?;
+    let allowed = parse_allowed_crates(allowed)?;
+
+    let mut forbidden = Vec::new();
+    let mut unaudited = Vec::new();
+    for package in metadata.user_packages()? {
+        let entry = package.allowed(&allowed);
+        let (name, version) = (&package.name, &package.version);
+        let features = metadata
+            .node(&package.id)
+            .map_or(&[][..], |node| node.features.as_slice());
+        for feature in features {
+            if entry.map_or(false, |entry| {
+                entry.forbidden_features.contains(&feature.as_str())
+            }) {
+                forbidden.push(format!(
+                    "`{name} {version}` feature `{feature}` is forbidden"
+                ));
+            }
+        }
+        if package.has_target("custom-build") && !entry.map_or(false, |entry| entry.build_script_ok)
+        {
+            unaudited.push(format!(
+                "`{name} {version}` has a build script, which is not marked `build_script_ok`"
+            ));
+        }
+        if package.has_target("proc-macro") && !entry.map_or(false, |entry| entry.proc_macro_ok) {
+            unaudited.push(format!(
+                "`{name} {version}` is a proc-macro, which is not marked `proc_macro_ok`"
+            ));
+        }
+    }
+
+    if !forbidden.is_empty() {
+        eyre::bail!(
+            "The following dependencies resolved to features that are not allowed:\n    {}",
+            forbidden.join("\n    ")
+        );
+    }
+    if !unaudited.is_empty() {
+        eyre::bail!(
+            "The following dependencies run code when they're built and have not been audited:\n    {}",
+            unaudited.join("\n    ")
+        );
+    }
+    Ok(())
+}
+
+/// Which of a function's dependencies were compiled with the `plrust_lints` group, as recorded with
+/// its compiled artifacts.  Each is named as `name version`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DependencyLints {
+    pub(crate) linted: Vec<String>,
+    /// not linted, as they're marked `audited` in the allow-list
+    pub(crate) audited: Vec<String>,
+    /// each of the `linted` crates as `name@version`, which is how `plrustc` recognizes them
+    #[serde(skip)]
+    packages: Vec<String>,
+}
+
+impl DependencyLints {
+    /// Decide which of the crates the function's dependencies need, as `cargo metadata` describes
+    /// the user crate in `metadata`, to lint.  That's all the libraries compiled for the target,
+    /// other than those marked `audited` in the `allowed` allow-list, if there is one, and those
+    /// `plrust-trusted-pgx` needs.  What's only needed by build scripts and proc-macros is compiled
+    /// for the host, which our `RUSTFLAGS` don't apply to, so it can't be linted
+    pub(crate) fn new(metadata: &str, allowed: Option<&toml::value::Table>) -> eyre::Result<Self> {
+        let metadata = Metadata::parse(metadata)?;
+        let allowed = allowed.map(parse_allowed_crates).transpose()?;
+
+        let for_target =
+            metadata.built_for_target(metadata.root()?.dependencies.iter().map(String::as_str));
+
+        let mut lints = DependencyLints::default();
+        for package in metadata.user_packages()? {
+            if !package.has_lib() || !for_target.contains(package.id.as_str()) {
+                continue;
+            }
+            let described = format!("{} {}", package.name, package.version);
+            let audited = allowed
+                .as_ref()
+                .and_then(|allowed| package.allowed(allowed))
+                .map_or(false, |entry| entry.audited);
+            if audited {
+                lints.audited.push(described);
+            } else {
+                lints.linted.push(described);
+                lints
+                    .packages
+                    .push(format!("{}@{}", package.name, package.version));
+            }
+        }
+        lints.linted.sort();
+        lints.audited.sort();
+        lints.packages.sort();
+        Ok(lints)
+    }
+
+    /// The `RUSTFLAGS` argument that has `plrustc` lint the crates
+    pub(crate) fn rustflag(&self) -> Option<String> {
+        (!self.packages.is_empty())
+            .then(|| format!("{LINT_DEPENDENCIES_ARG}={}", self.packages.join(",")))
+    }
+}
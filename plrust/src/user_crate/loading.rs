@@ -8,9 +8,21 @@ Use of this source code is governed by the PostgreSQL license that can be found
 
 use pgx::pg_sys;
 
+use crate::signing::Claims;
 use crate::target::CompilationTarget;
 use crate::user_crate::lint::LintSet;
-use crate::user_crate::{CrateState, FnValidate};
+use crate::user_crate::{BuildInputs, CrateState, FnValidate};
+
+/// Everything produced by compiling a user function for a single [`CompilationTarget`]
+pub(crate) struct CompiledArtifact {
+    pub(crate) target: CompilationTarget,
+    /// the name of the function it exports
+    pub(crate) symbol: Option<String>,
+    pub(crate) shared_object: Vec<u8>,
+    pub(crate) lints: LintSet,
+    pub(crate) signature: Option<String>,
+    pub(crate) build_inputs: Option<BuildInputs>,
+}
 
 /// Available and ready-to-load PL/Rust function
 ///
@@ -25,6 +37,9 @@ pub(crate) struct FnLoad {
     symbol: Option<String>,
     shared_object: Vec<u8>,
     lints: LintSet,
+    claims: Claims,
+    signature: Option<String>,
+    build_inputs: Option<BuildInputs>,
 }
 
 impl CrateState for FnLoad {}
@@ -39,6 +54,9 @@ impl FnLoad {
         symbol: Option<String>,
         shared_object: Vec<u8>,
         lints: LintSet,
+        claims: Claims,
+        signature: Option<String>,
+        build_inputs: Option<BuildInputs>,
     ) -> Self {
         Self {
             generation_number,
@@ -48,11 +66,21 @@ impl FnLoad {
             symbol,
             shared_object,
             lints,
+            claims,
+            signature,
+            build_inputs,
         }
     }
 
-    pub(crate) fn into_inner(self) -> (CompilationTarget, Vec<u8>, LintSet) {
-        (self.target, self.shared_object, self.lints)
+    pub(crate) fn into_inner(self) -> CompiledArtifact {
+        CompiledArtifact {
+            target: self.target,
+            symbol: self.symbol,
+            shared_object: self.shared_object,
+            lints: self.lints,
+            signature: self.signature,
+            build_inputs: self.build_inputs,
+        }
     }
 
     #[tracing::instrument(level = "debug", skip_all, fields(db_oid = % self.db_oid, fn_oid = % self.fn_oid))]
@@ -61,9 +89,13 @@ impl FnLoad {
             self.generation_number,
             self.db_oid,
             self.fn_oid,
+            self.target,
             self.symbol,
             self.shared_object,
             self.lints,
+            self.claims,
+            self.build_inputs,
+            self.signature,
         )
     }
 }
@@ -0,0 +1,269 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! The `Cargo.lock` a user crate's dependencies were resolved to.
+//!
+//! A function's lockfile is stored alongside its compiled artifacts and written back out the next
+//! time it's compiled, so recompiling it builds exactly the crates it was built with before, rather
+//! than whatever crates.io has since published.  With `plrust.dependency_pinning = transitive`,
+//! every crate the function's dependencies depend on must also be resolved to a version in
+//! `plrust.allowed_dependencies`.
+//!
+//! Nothing in here may depend on pgx, as `plrust-compile` includes this file as-is.
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use eyre::{eyre, WrapErr};
+use serde::Deserialize;
+
+use super::dependencies::AllowedDependency;
+
+/// Every user crate's package name starts with this, whichever function and generation it's for
+const USER_CRATE_PREFIX: &str = "plrust_fn_";
+
+/// The package of the `pgx` dependency every user crate has.  Its dependencies are PL/Rust's
+/// concern, not the user's
+const TRUSTED_PGX_PACKAGE: &str = "plrust-trusted-pgx";
+
+/// Which of a function's dependencies must be pinned to a version in the allow-list
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum DependencyPinning {
+    /// only the dependencies the function declares
+    Direct,
+    /// those, and every crate they depend on
+    Transitive,
+}
+
+impl FromStr for DependencyPinning {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "direct" => Ok(DependencyPinning::Direct),
+            "transitive" => Ok(DependencyPinning::Transitive),
+            other => eyre::bail!(
+                "unrecognized `plrust.dependency_pinning` value `{other}`.  Expected `direct` or `transitive`"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    /// `None` for the user crate itself, or anything else built from a path
+    #[serde(default)]
+    source: Option<String>,
+    /// SHA-256 of the `.crate` file, for crates from a registry
+    #[serde(default)]
+    checksum: Option<String>,
+    /// as `name`, or `name version` when more than one version of `name` is locked
+    #[serde(default)]
+    dependencies: Vec<String>,
+}
+
+impl LockedPackage {
+    fn is_from_registry(&self) -> bool {
+        self.source.as_deref().map_or(false, |source| {
+            source.starts_with("registry+") || source.starts_with("sparse+")
+        })
+    }
+}
+
+/// A crate the function's dependencies depend on that isn't locked to an allowed version
+pub(crate) struct Unpinned<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) version: &'a str,
+    /// The newest allowed version compatible with `version`, which it can be updated to
+    pub(crate) pin_to: Option<semver::Version>,
+}
+
+/// A parsed `Cargo.lock`
+#[derive(Debug, Deserialize)]
+pub(crate) struct Lockfile {
+    #[serde(default, rename = "package")]
+    packages: Vec<LockedPackage>,
+}
+
+impl Lockfile {
+    pub(crate) fn parse(contents: &str) -> eyre::Result<Self> {
+        toml::from_str(contents).wrap_err("Parsing `Cargo.lock`")
+    }
+
+    /// The lockfile from a previous compilation of a function, for building it as the crate
+    /// `crate_name`.  Its own package is renamed, so `cargo` doesn't throw it away along with the
+    /// versions it locked for it
+    pub(crate) fn for_crate(contents: &str, crate_name: &str) -> eyre::Result<String> {
+        let mut lockfile: toml::value::Table =
+            toml::from_str(contents).wrap_err("Parsing the stored `Cargo.lock`")?;
+        if let Some(toml::Value::Array(packages)) = lockfile.get_mut("package") {
+            for package in packages.iter_mut().filter_map(toml::Value::as_table_mut) {
+                if let Some(toml::Value::String(name)) = package.get_mut("name") {
+                    if name.starts_with(USER_CRATE_PREFIX) {
+                        *name = crate_name.to_string();
+                    }
+                }
+            }
+        }
+        toml::to_string(&lockfile).wrap_err("Stringifying the stored `Cargo.lock`")
+    }
+
+    /// Make sure every crate from a registry has a checksum, so `cargo` verifies what it downloads,
+    /// and that no checksum has changed since the `previous` lockfile
+    pub(crate) fn verify_checksums(&self, previous: Option<&Lockfile>) -> eyre::Result<()> {
+        let mut problems = Vec::new();
+        for package in self
+            .packages
+            .iter()
+            .filter(|package| package.is_from_registry())
+        {
+            let (name, version) = (&package.name, &package.version);
+            let Some(checksum) = &package.checksum else {
+                problems.push(format!("`{name} {version}` has no checksum"));
+                continue;
+            };
+
+            let previous_checksum = previous
+                .into_iter()
+                .flat_map(|previous| &previous.packages)
+                .find(|p| p.name == *name && p.version == *version && p.source == package.source)
+                .and_then(|previous| previous.checksum.as_ref());
+            if let Some(previous_checksum) = previous_checksum {
+                if previous_checksum != checksum {
+                    problems.push(format!(
+                        "`{name} {version}`'s checksum changed from `{previous_checksum}` to `{checksum}`"
+                    ));
+                }
+            }
+        }
+
+        if !problems.is_empty() {
+            eyre::bail!(
+                "The following locked crates could not be verified:\n    {}",
+                problems.join("\n    ")
+            );
+        }
+        Ok(())
+    }
+
+    /// The crates the user's dependencies need, including those dependencies themselves, that
+    /// aren't locked to one of the `allowed` versions
+    pub(crate) fn unpinned<'a>(
+        &'a self,
+        allowed: &BTreeMap<&str, Vec<AllowedDependency<'_>>>,
+    ) -> Vec<Unpinned<'a>> {
+        self.user_dependencies()
+            .into_iter()
+            .filter(|package| package.is_from_registry())
+            .filter_map(|package| {
+                let locked = semver::Version::parse(&package.version).ok();
+                let versions = allowed
+                    .get(package.name.as_str())
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                if versions
+                    .iter()
+                    .any(|allowed| Some(&allowed.version) == locked.as_ref())
+                {
+                    return None;
+                }
+
+                let pin_to = locked.and_then(|locked| {
+                    versions
+                        .iter()
+                        .map(|allowed| &allowed.version)
+                        .filter(|allowed| is_compatible(&locked, allowed))
+                        .max()
+                        .cloned()
+                });
+                Some(Unpinned {
+                    name: &package.name,
+                    version: &package.version,
+                    pin_to,
+                })
+            })
+            .collect()
+    }
+
+    /// The name and version of every crate from a registry the user's dependencies need, including
+    /// those dependencies themselves
+    pub(crate) fn locked_dependencies(&self) -> Vec<(&str, &str)> {
+        self.user_dependencies()
+            .into_iter()
+            .filter(|package| package.is_from_registry())
+            .map(|package| (package.name.as_str(), package.version.as_str()))
+            .collect()
+    }
+
+    /// Every package reachable from the user crate's dependencies, other than `plrust-trusted-pgx`
+    fn user_dependencies(&self) -> Vec<&LockedPackage> {
+        let Some(root) = self
+            .packages
+            .iter()
+            .find(|package| package.name.starts_with(USER_CRATE_PREFIX))
+        else {
+            return vec![];
+        };
+
+        let mut reached: Vec<&LockedPackage> = Vec::new();
+        let mut pending = root
+            .dependencies
+            .iter()
+            .filter_map(|dependency| self.find(dependency))
+            .filter(|package| package.name != TRUSTED_PGX_PACKAGE)
+            .collect::<Vec<_>>();
+        while let Some(package) = pending.pop() {
+            if reached
+                .iter()
+                .any(|reached| std::ptr::eq(*reached, package))
+            {
+                continue;
+            }
+            reached.push(package);
+            pending.extend(
+                package
+                    .dependencies
+                    .iter()
+                    .filter_map(|dependency| self.find(dependency)),
+            );
+        }
+        reached
+    }
+
+    /// The package a `dependencies` entry refers to
+    fn find(&self, dependency: &str) -> Option<&LockedPackage> {
+        let mut parts = dependency.split_whitespace();
+        let name = parts.next()?;
+        let version = parts.next();
+        self.packages.iter().find(|package| {
+            package.name == name && version.map_or(true, |version| package.version == version)
+        })
+    }
+}
+
+/// Could `cargo` have picked `allowed` instead of `locked`, without breaking whatever required it?
+fn is_compatible(locked: &semver::Version, allowed: &semver::Version) -> bool {
+    match (locked.major, locked.minor) {
+        (0, 0) => allowed.major == 0 && allowed.minor == 0 && allowed.patch == locked.patch,
+        (0, minor) => allowed.major == 0 && allowed.minor == minor,
+        (major, _) => allowed.major == major,
+    }
+}
+
+/// Describe each crate in `unpinned`, for an error
+pub(crate) fn describe_unpinned(unpinned: &[Unpinned<'_>]) -> eyre::Report {
+    eyre!(
+        "The following crates are not pinned to a version in `plrust.allowed_dependencies`:\n    {}",
+        unpinned
+            .iter()
+            .map(|unpinned| format!("`{} {}`", unpinned.name, unpinned.version))
+            .collect::<Vec<_>>()
+            .join("\n    ")
+    )
+}
@@ -16,33 +16,50 @@ Consider opening the documentation like so:
 cargo doc --no-deps --document-private-items --open
 ```
 */
-use std::{path::Path, process::Output};
+use std::{
+    path::{Path, PathBuf},
+    process::Output,
+};
 
+use eyre::WrapErr;
+use pgx::pg_sys::AsPgCStr;
 use pgx::{pg_sys, PgBuiltInOids, PgOid};
 use proc_macro2::TokenStream;
 use quote::quote;
-use semver;
 
-pub(crate) use build::FnBuild;
+pub(crate) use artifact_format::{sha256_hex, BuildInputs, Encoding, BASE64_ENGINE};
+pub(crate) use build::{Diagnostic, FnBuild};
 use crate_variant::CrateVariant;
 pub(crate) use crating::FnCrating;
-pub(crate) use loading::FnLoad;
+pub(crate) use dependencies::{parse_allow_list, DependencyLints, Scope};
+pub(crate) use loading::{CompiledArtifact, FnLoad};
+pub(crate) use lockfile::DependencyPinning;
 pub(crate) use ready::FnReady;
+pub(crate) use source::CrateSource;
 pub(crate) use validate::FnValidate;
 pub(crate) use verify::FnVerify;
 
+use crate::pgproc::PgProc;
 use crate::prosrc::maybe_extract_source_from_json;
+use crate::signing::Claims;
 use crate::target::CompilationTarget;
 use crate::user_crate::lint::LintSet;
 use crate::PlRustError;
 
+pub(crate) mod advisories;
+pub(crate) mod advisory_db;
+mod artifact_format;
 mod build;
 mod cargo;
+mod codegen;
 mod crate_variant;
 mod crating;
+mod dependencies;
 pub(crate) mod lint;
 mod loading;
+mod lockfile;
 mod ready;
+mod source;
 mod validate;
 mod verify;
 
@@ -76,6 +93,22 @@ premature abstraction would be unwise.
 */
 pub(crate) trait CrateState {}
 
+/// Removes a generated crate's directory when dropped, so that nothing is left behind under
+/// `plrust.work_dir` however building or checking the crate ends
+#[must_use]
+pub(crate) struct CrateDirGuard(PathBuf);
+
+impl Drop for CrateDirGuard {
+    fn drop(&mut self) {
+        if self.0.exists() {
+            tracing::trace!("removing {}", self.0.display());
+            if let Err(e) = std::fs::remove_dir_all(&self.0) {
+                pgx::log!("Problem during removing crate directory: {e}")
+            }
+        }
+    }
+}
+
 impl UserCrate<FnCrating> {
     #[cfg(any(test, feature = "pg_test"))]
     #[tracing::instrument(level = "debug", skip_all)]
@@ -101,6 +134,27 @@ impl UserCrate<FnCrating> {
         unsafe { FnCrating::try_from_fn_oid(db_oid, fn_oid).map(Self) }
     }
     #[tracing::instrument(level = "debug", skip_all)]
+    pub(crate) fn try_from_source(
+        db_oid: pg_sys::Oid,
+        generation_number: u64,
+        source: &str,
+        argument_oids_and_names: Vec<(PgOid, syn::Ident)>,
+        return_oid: PgOid,
+        return_set: bool,
+        is_strict: bool,
+    ) -> eyre::Result<Self> {
+        FnCrating::try_from_source(
+            db_oid,
+            generation_number,
+            source,
+            argument_oids_and_names,
+            return_oid,
+            return_set,
+            is_strict,
+        )
+        .map(Self)
+    }
+    #[tracing::instrument(level = "debug", skip_all)]
     #[allow(unused)] // used in tests
     pub fn lib_rs(&self) -> eyre::Result<(syn::File, LintSet)> {
         self.0.lib_rs()
@@ -110,6 +164,25 @@ impl UserCrate<FnCrating> {
     pub fn cargo_toml(&self) -> eyre::Result<toml::value::Table> {
         self.0.cargo_toml()
     }
+    pub(crate) fn crate_name(&self) -> String {
+        self.0.crate_name()
+    }
+    /// Remove the directory [`UserCrate::provision`] generates the crate in under `parent_dir` once
+    /// the returned guard is dropped
+    pub(crate) fn remove_crate_dir_on_drop(&self, parent_dir: &Path) -> CrateDirGuard {
+        CrateDirGuard(self.0.crate_dir(parent_dir))
+    }
+    #[allow(unused)] // used in tests
+    pub(crate) fn symbol_name(&self) -> &str {
+        self.0.symbol_name()
+    }
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub(crate) fn export(
+        &self,
+        trusted_pgx_path: Option<&str>,
+    ) -> eyre::Result<Vec<(String, String)>> {
+        self.0.export(trusted_pgx_path)
+    }
     /// Provision into a given folder and return the crate directory.
     #[tracing::instrument(level = "debug", skip_all)]
     pub fn provision(&self, parent_dir: &Path) -> eyre::Result<UserCrate<FnVerify>> {
@@ -156,6 +229,19 @@ impl UserCrate<FnBuild> {
             .map(|(state, output)| (UserCrate(state), output))
             .collect())
     }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            db_oid = %self.0.db_oid(),
+            fn_oid = %self.0.fn_oid(),
+            crate_dir = %self.0.crate_dir().display(),
+            target_dir = tracing::field::display(target_dir.display()),
+        ))]
+    pub(crate) fn check(self, target_dir: &Path) -> eyre::Result<Vec<Diagnostic>> {
+        self.0.check(target_dir)
+    }
 }
 
 impl UserCrate<FnLoad> {
@@ -168,6 +254,9 @@ impl UserCrate<FnLoad> {
         symbol: Option<String>,
         shared_object: Vec<u8>,
         lints: LintSet,
+        claims: Claims,
+        signature: Option<String>,
+        build_inputs: Option<BuildInputs>,
     ) -> Self {
         UserCrate(FnLoad::new(
             generation_number,
@@ -177,11 +266,14 @@ impl UserCrate<FnLoad> {
             symbol,
             shared_object,
             lints,
+            claims,
+            signature,
+            build_inputs,
         ))
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
-    pub fn into_inner(self) -> (CompilationTarget, Vec<u8>, LintSet) {
+    pub fn into_inner(self) -> CompiledArtifact {
         self.0.into_inner()
     }
 
@@ -218,6 +310,28 @@ impl UserCrate<FnReady> {
     }
 }
 
+/// The Rust signature of the function generated for the function `pg_proc` currently describes,
+/// see [`codegen::signature`]
+#[tracing::instrument(level = "debug", skip_all)]
+pub(crate) fn function_signature(pg_proc: &PgProc) -> eyre::Result<String> {
+    Ok(CrateVariant::from_pg_proc(pg_proc)?.signature())
+}
+
+/// What the signature of an artifact compiled from `src`, against `trusted_pgx_version`, must vouch
+/// for to be loaded as the function `pg_proc` currently describes
+#[tracing::instrument(level = "debug", skip_all)]
+pub(crate) fn claims(
+    pg_proc: &PgProc,
+    src: &str,
+    trusted_pgx_version: String,
+) -> eyre::Result<Claims> {
+    Ok(Claims::new(
+        function_signature(pg_proc)?,
+        src,
+        trusted_pgx_version,
+    ))
+}
+
 #[tracing::instrument(level = "debug", skip_all, fields(type_oid = %type_oid.value()))]
 pub(crate) fn oid_to_syn_type(type_oid: &PgOid, owned: bool) -> Result<syn::Type, PlRustError> {
     let array_type = unsafe { pg_sys::get_element_type(type_oid.value()) };
@@ -280,13 +394,34 @@ pub(crate) fn oid_to_syn_type(type_oid: &PgOid, owned: bool) -> Result<syn::Type
         .map_err(|e| PlRustError::ParsingRustMapping(type_oid.value(), rust_type.to_string(), e))
 }
 
+/// Split the user's function source into its code and its `[dependencies]`, checking those against
+/// the allow-list for a function owned by `owner` in `schema`.  Also returns the parts of the
+/// allow-list that applied, if there is one
 #[tracing::instrument(level = "debug", skip_all)]
-fn parse_source_and_deps(code_and_deps: &str) -> eyre::Result<(syn::Block, toml::value::Table)> {
-    enum Parse {
-        Code,
-        Deps,
-    }
+fn parse_source_and_deps(
+    code_and_deps: &str,
+    owner: pg_sys::Oid,
+    schema: Option<&str>,
+) -> eyre::Result<(syn::Block, toml::value::Table, Option<toml::value::Table>)> {
+    let (code_block, deps_block) = split_source_and_deps(code_and_deps);
+
+    let (user_dependencies, allow_list) = check_user_dependencies(deps_block, owner, schema)?;
+
+    let user_code: syn::Block =
+        syn::parse_str(&code_block).map_err(PlRustError::ParsingCodeBlock)?;
 
+    Ok((user_code, user_dependencies, allow_list))
+}
+
+/// The `[dependencies]` the user declared in the specified function source, without checking them
+/// against `plrust.allowed_dependencies`
+pub(crate) fn declared_dependencies(code_and_deps: &str) -> eyre::Result<toml::value::Table> {
+    let (_, deps_block) = split_source_and_deps(code_and_deps);
+    Ok(toml::from_str(&deps_block)?)
+}
+
+/// Split the user's function source into its code, as a block, and its `[dependencies]` section
+fn split_source_and_deps(code_and_deps: &str) -> (String, String) {
     // it's possible, especially via a `pg_restore` operation, that "code_and_deps" is actually
     // our JSON structure stored in `pg_proc.prosrc`.  We'll pass it to [`maybe_extract_source_from_json`]
     // and let it figure out what to do.
@@ -299,117 +434,91 @@ fn parse_source_and_deps(code_and_deps: &str) -> eyre::Result<(syn::Block, toml:
     // It's also possible "code_and_deps" is exactly that, given to us via a user-written
     // "CREATE OR REPLACE FUNCTION" statement.
     let code_and_deps = maybe_extract_source_from_json(code_and_deps);
-
-    let mut deps_block = String::new();
-    let mut code_block = String::from("{ ");
-    let mut parse = Parse::Code;
-
-    for line in code_and_deps.trim().split_inclusive('\n') {
-        match line.trim() {
-            "[dependencies]" => parse = Parse::Deps,
-            "[code]" => parse = Parse::Code,
-            _ => match parse {
-                Parse::Code => code_block.push_str(line),
-                Parse::Deps => deps_block.push_str(line),
-            },
-        }
-    }
-
-    code_block.push_str("\n}");
-
-    let user_dependencies = check_user_dependencies(deps_block)?;
-
-    let user_code: syn::Block =
-        syn::parse_str(&code_block).map_err(PlRustError::ParsingCodeBlock)?;
-
-    Ok((user_code, user_dependencies))
+    codegen::split_source_and_deps(&code_and_deps)
 }
 
 #[tracing::instrument(level = "debug", skip_all)]
-fn check_user_dependencies(user_deps: String) -> eyre::Result<toml::value::Table> {
+fn check_user_dependencies(
+    user_deps: String,
+    owner: pg_sys::Oid,
+    schema: Option<&str>,
+) -> eyre::Result<(toml::value::Table, Option<toml::value::Table>)> {
     let user_dependencies: toml::value::Table = toml::from_str(&user_deps)?;
+    let parsed = dependencies::parse_user_dependencies(&user_dependencies)?;
 
-    for (dependency, val) in &user_dependencies {
-        match val {
-            toml::Value::String(_) => {
-                // No-op, we currently only support dependencies in the format
-                // foo = "1.0.0"
-            }
-            _ => {
-                return Err(eyre::eyre!(
-                    "dependency {} with values {:?} is malformatted. Only strings are supported",
-                    dependency,
-                    val
-                ));
-            }
-        }
-    }
+    let (user_dependencies, allow_list) = match apply_allowed_dependencies(&parsed, owner, schema)?
+    {
+        Some((applied, allow_list)) => (
+            applied
+                .into_iter()
+                .map(|dependency| (dependency.name.clone(), dependency.to_toml()))
+                .collect(),
+            Some(allow_list),
+        ),
+        None => (user_dependencies, None),
+    };
 
-    check_dependencies_against_allowed(&user_dependencies)?;
-    Ok(user_dependencies)
+    // the rest of what they need is checked once it's resolved
+    let pinned = advisories::pinned_dependencies(&user_dependencies);
+    advisories::check_dependencies(
+        pinned
+            .iter()
+            .map(|(name, version)| (name.as_str(), version)),
+    )?;
+    Ok((user_dependencies, allow_list))
 }
 
-#[tracing::instrument(level = "debug", skip_all)]
-fn check_dependencies_against_allowed(dependencies: &toml::value::Table) -> eyre::Result<()> {
-    if matches!(crate::gucs::PLRUST_ALLOWED_DEPENDENCIES.get(), None) {
-        return Ok(());
-    }
-
-    let allowed_deps = &*crate::gucs::PLRUST_ALLOWED_DEPENDENCIES_CONTENTS;
-    let mut unsupported_deps = std::vec::Vec::new();
-
-    for (dep, val) in dependencies {
-        if !allowed_deps.contains_key(dep) {
-            unsupported_deps.push(format!("{} = {}", dep, val.to_string()));
-            continue;
-        }
+/// Check `dependencies` against the parts of `plrust.allowed_dependencies` that apply to a function
+/// owned by `owner` in `schema`, and return them with whatever features it forces on or off, along
+/// with those parts of the allow-list, or `None` if there's no allow-list
+#[tracing::instrument(level = "debug", skip(dependencies))]
+fn apply_allowed_dependencies(
+    dependencies: &[dependencies::UserDependency],
+    owner: pg_sys::Oid,
+    schema: Option<&str>,
+) -> eyre::Result<Option<(Vec<dependencies::UserDependency>, toml::value::Table)>> {
+    let Some(effective) = effective_allow_list(owner, schema)? else {
+        return Ok(None);
+    };
+    let applied = dependencies::apply_allow_list(dependencies, &effective).wrap_err_with(|| {
+        format!(
+            "checking dependencies against `{}`",
+            crate::gucs::allowed_dependencies_path().unwrap_or_default()
+        )
+    })?;
+    Ok(Some((applied, effective)))
+}
 
-        match val {
-            toml::Value::String(ver) => {
-                let req = semver::VersionReq::parse(ver.as_str()).unwrap();
-
-                // Check if the allowed dependency is of format String or toml::Table
-                // foo = "1.0.0" vs foo = { version = "1.0.0", features = ["full", "boo"], test = ["single"]}
-                match allowed_deps.get(dep).unwrap() {
-                    toml::Value::String(allowed_deps_ver) => {
-                        if !req.matches(&semver::Version::parse(allowed_deps_ver)?) {
-                            unsupported_deps.push(format!("{} = {}", dep, val.to_string()));
-                        }
-                    }
-                    toml::Value::Table(allowed_deps_vals) => {
-                        if !req.matches(&semver::Version::parse(
-                            &allowed_deps_vals.get("version").unwrap().as_str().unwrap(),
-                        )?) {
-                            unsupported_deps.push(format!("{} = {}", dep, val.to_string()));
-                        }
-                    }
-                    _ => {
-                        return Err(eyre::eyre!(
-                            "{} contains an unsupported toml format",
-                            crate::gucs::PLRUST_ALLOWED_DEPENDENCIES.get().unwrap()
-                        ));
-                    }
-                }
-            }
-            _ => {
-                return Err(eyre::eyre!(
-                    "dependency {} with values {:?} is malformatted. Only strings are supported",
-                    dep,
-                    val
-                ));
-            }
-        }
-    }
+/// The parts of `plrust.allowed_dependencies` that apply to a function owned by `owner` in
+/// `schema`, or `None` if there's no allow-list
+fn effective_allow_list(
+    owner: pg_sys::Oid,
+    schema: Option<&str>,
+) -> eyre::Result<Option<toml::value::Table>> {
+    let Some(allowed_deps) = crate::gucs::allowed_dependencies()? else {
+        return Ok(None);
+    };
 
-    if !unsupported_deps.is_empty() {
-        return Err(eyre::eyre!(
-            "The following dependencies are unsupported {:?}. The configured PL/Rust only supports {:?}",
-            unsupported_deps,
-            allowed_deps
-        ));
-    }
+    let has_privs_of_role = |role: &str| unsafe {
+        // SAFETY:  `get_role_oid` returns InvalidOid rather than raising an ERROR when asked to,
+        // and `has_privs_of_role` is fine with any pair of role oids
+        let role = pg_sys::get_role_oid(role.as_pg_cstr(), true);
+        role != pg_sys::InvalidOid && pg_sys::has_privs_of_role(owner, role)
+    };
+    Ok(Some(dependencies::effective_allow_list(
+        &allowed_deps,
+        has_privs_of_role,
+        schema,
+    )?))
+}
 
-    Ok(())
+/// SHA-256 of the `plrust.allowed_dependencies` that apply to the function `pg_proc` describes, or
+/// `None` if there's no allow-list.  See [`BuildInputs::allow_list_sha256`]
+pub(crate) fn allow_list_sha256(pg_proc: &PgProc) -> eyre::Result<Option<String>> {
+    let schema = pg_proc.namespace_name();
+    Ok(effective_allow_list(pg_proc.proowner(), schema.as_deref())?
+        .as_ref()
+        .map(dependencies::allow_list_sha256))
 }
 
 #[cfg(any(test, feature = "pg_test"))]
@@ -453,9 +562,8 @@ mod tests {
                 user_code,
                 variant,
             );
-            let symbol_name = crate::plrust::symbol_name(db_oid, fn_oid);
             let symbol_ident =
-                proc_macro2::Ident::new(&symbol_name, proc_macro2::Span::call_site());
+                proc_macro2::Ident::new(generated.symbol_name(), proc_macro2::Span::call_site());
 
             let (generated_lib_rs, lints) = generated.lib_rs()?;
             let imports = crate::user_crate::crating::shared_imports();
@@ -491,8 +599,7 @@ mod tests {
 
             let generated_cargo_toml = generated.cargo_toml()?;
             let version_feature = format!("pgx/pg{}", pgx::pg_sys::get_pg_major_version_num());
-            let crate_name = crate::plrust::crate_name(db_oid, fn_oid, generation_number);
-            let fixture_cargo_toml = cargo_toml_template(&crate_name, &version_feature);
+            let fixture_cargo_toml = cargo_toml_template(generated.symbol_name(), &version_feature);
 
             assert_eq!(
                 toml::to_string(&generated_cargo_toml)?,
@@ -514,4 +621,539 @@ mod tests {
         }
         wrapped().unwrap()
     }
+
+    #[pg_test]
+    fn allow_list_features() {
+        fn wrapped() -> eyre::Result<()> {
+            let allowed: toml::value::Table = toml::from_str(
+                r#"
+                serde = { version = "1.0.160", features = ["derive"], forced-features = ["std"], forbidden-features = ["rc"], default-features = false }
+                "#,
+            )?;
+            let requested = |deps: &str| -> eyre::Result<_> {
+                let deps: toml::value::Table = toml::from_str(deps)?;
+                dependencies::apply_allow_list(
+                    &dependencies::parse_user_dependencies(&deps)?,
+                    &allowed,
+                )
+            };
+
+            let applied = requested(r#"serde = { version = "1", features = ["derive"] }"#)?;
+            assert_eq!(
+                applied[0].to_toml(),
+                toml::Value::from(toml::toml! {
+                    version = "=1.0.160"
+                    features = ["derive", "std"]
+                    default-features = false
+                })
+            );
+
+            let rejected = requested(r#"serde = { version = "1", features = ["rc", "alloc"] }"#)
+                .unwrap_err()
+                .to_string();
+            assert!(
+                rejected.contains("`serde` feature `rc` is forbidden"),
+                "{rejected}"
+            );
+            assert!(
+                rejected.contains("`serde` feature `alloc` is not allowed"),
+                "{rejected}"
+            );
+            assert!(requested(r#"serde = { version = "1", default-features = true }"#).is_err());
+
+            // names are checked as `cargo` would, before they're looked for anywhere
+            let too_long = "a".repeat(65);
+            for name in ["../serde", "serde json", "", too_long.as_str()] {
+                let mut deps = toml::value::Table::new();
+                deps.insert(name.to_string(), "1".into());
+                let err = dependencies::parse_user_dependencies(&deps)
+                    .err()
+                    .unwrap()
+                    .to_string();
+                assert!(err.contains("is not a valid crate name"), "{err}");
+            }
+
+            let contradictory: toml::value::Table = toml::from_str(
+                r#"serde = { version = "1.0.160", forced-features = ["std"], forbidden-features = ["std"] }"#,
+            )?;
+            let err = dependencies::parse_allowed_crates(&contradictory)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(
+                err.contains("both forces and forbids feature `std`"),
+                "{err}"
+            );
+
+            let allowed: toml::value::Table = toml::from_str(
+                r#"
+                rand = ["0.7.3", { version = ["0.8.4", "0.8.5"], features = ["small_rng"] }]
+                itoa = "1.0"
+                "#,
+            )?;
+            let requested = |deps: &str| -> eyre::Result<_> {
+                let deps: toml::value::Table = toml::from_str(deps)?;
+                dependencies::apply_allow_list(
+                    &dependencies::parse_user_dependencies(&deps)?,
+                    &allowed,
+                )
+            };
+            assert_eq!("=0.8.5", requested(r#"rand = "0.8""#)?[0].version);
+            assert_eq!(
+                "=0.8.4",
+                requested(r#"rand = "~0.8.4, <0.8.5""#)?[0].version
+            );
+            assert_eq!("=0.7.3", requested(r#"rand = "0.7""#)?[0].version);
+            assert!(requested(r#"rand = { version = "0.7", features = ["small_rng"] }"#).is_err());
+            assert!(requested(r#"rand = "0.9""#).is_err());
+            assert_eq!("=1.0.0", requested(r#"itoa = "1.0""#)?[0].version);
+
+            let allowed: toml::value::Table = toml::from_str(
+                r#"
+                serde_json = "1.0.96"
+                [roles.analytics]
+                regex = "1.8.1"
+                serde_json = { version = "1.0.96", features = ["float_roundtrip"] }
+                [schemas.reporting]
+                chrono = "0.4.24"
+                "#,
+            )?;
+            assert_eq!(3, dependencies::parse_allow_list(&allowed)?.len());
+            let requested = |deps: &str, role: &str, schema: &str| -> eyre::Result<_> {
+                let deps: toml::value::Table = toml::from_str(deps)?;
+                let effective =
+                    dependencies::effective_allow_list(&allowed, |r| r == role, Some(schema))?;
+                dependencies::apply_allow_list(
+                    &dependencies::parse_user_dependencies(&deps)?,
+                    &effective,
+                )
+            };
+            assert!(requested(r#"serde_json = "1""#, "app", "public").is_ok());
+            assert!(requested(r#"regex = "1""#, "app", "public").is_err());
+            assert!(requested(r#"regex = "1""#, "analytics", "public").is_ok());
+            assert!(requested(r#"chrono = "0.4""#, "analytics", "public").is_err());
+            assert!(requested(r#"chrono = "0.4""#, "app", "reporting").is_ok());
+            let float_roundtrip =
+                r#"serde_json = { version = "1", features = ["float_roundtrip"] }"#;
+            assert!(requested(float_roundtrip, "app", "public").is_err());
+            assert!(requested(float_roundtrip, "analytics", "public").is_ok());
+
+            // entries for the same version allow whatever any of them allows
+            let allowed: toml::value::Table = toml::from_str(
+                r#"
+                foo = { version = "1.0.0", features = ["a"] }
+                [roles.analytics]
+                foo = { version = "1.0.0", features = ["b"], build_script_ok = true }
+                "#,
+            )?;
+            let effective =
+                dependencies::effective_allow_list(&allowed, |r| r == "analytics", None)?;
+            let merged = dependencies::parse_allowed_crates(&effective)?;
+            assert_eq!(1, merged["foo"].len());
+            assert_eq!(vec!["a", "b"], merged["foo"][0].features);
+            assert!(merged["foo"][0].build_script_ok);
+            let requested = |deps: &str, role: &str| -> eyre::Result<_> {
+                let deps: toml::value::Table = toml::from_str(deps)?;
+                let effective = dependencies::effective_allow_list(&allowed, |r| r == role, None)?;
+                dependencies::apply_allow_list(
+                    &dependencies::parse_user_dependencies(&deps)?,
+                    &effective,
+                )
+            };
+            let both = r#"foo = { version = "1", features = ["a", "b"] }"#;
+            assert!(requested(both, "analytics").is_ok());
+            assert!(requested(both, "app").is_err());
+            Ok(())
+        }
+        wrapped().unwrap()
+    }
+
+    #[pg_test]
+    fn lockfile_pinning() {
+        fn wrapped() -> eyre::Result<()> {
+            let lock = |regex_syntax: &str, checksum: &str| {
+                format!(
+                    r#"
+                    version = 3
+
+                    [[package]]
+                    name = "plrust_fn_oid_1_2_3"
+                    version = "0.0.0"
+                    dependencies = ["plrust-trusted-pgx", "regex"]
+
+                    [[package]]
+                    name = "plrust-trusted-pgx"
+                    version = "1.0.0"
+                    source = "registry+https://github.com/rust-lang/crates.io-index"
+                    checksum = "aa"
+                    dependencies = ["libc"]
+
+                    [[package]]
+                    name = "libc"
+                    version = "0.2.140"
+                    source = "registry+https://github.com/rust-lang/crates.io-index"
+                    checksum = "bb"
+
+                    [[package]]
+                    name = "regex"
+                    version = "1.8.1"
+                    source = "registry+https://github.com/rust-lang/crates.io-index"
+                    checksum = "cc"
+                    dependencies = ["regex-syntax"]
+
+                    [[package]]
+                    name = "regex-syntax"
+                    version = "{regex_syntax}"
+                    source = "registry+https://github.com/rust-lang/crates.io-index"
+                    checksum = "{checksum}"
+                    "#
+                )
+            };
+            let allowed: toml::value::Table = toml::from_str(
+                r#"
+                regex = "1.8.1"
+                regex-syntax = ["0.6.29", "0.7.1"]
+                "#,
+            )?;
+            let allowed = dependencies::parse_allowed_crates(&allowed)?;
+
+            let pinned = lockfile::Lockfile::parse(&lock("0.7.1", "dd"))?;
+            assert!(pinned.unpinned(&allowed).is_empty());
+
+            // `libc` is only needed by `plrust-trusted-pgx`, so it needn't be allowed
+            let newer = lockfile::Lockfile::parse(&lock("0.7.2", "ee"))?;
+            let unpinned = newer.unpinned(&allowed);
+            assert_eq!(1, unpinned.len());
+            assert_eq!("regex-syntax", unpinned[0].name);
+            assert_eq!(Some(semver::Version::new(0, 7, 1)), unpinned[0].pin_to);
+
+            assert!(newer.verify_checksums(Some(&pinned)).is_ok());
+            let tampered = lockfile::Lockfile::parse(&lock("0.7.1", "ff"))?;
+            let err = tampered
+                .verify_checksums(Some(&pinned))
+                .unwrap_err()
+                .to_string();
+            assert!(
+                err.contains("`regex-syntax 0.7.1`'s checksum changed"),
+                "{err}"
+            );
+
+            let renamed =
+                lockfile::Lockfile::for_crate(&lock("0.7.1", "dd"), "plrust_fn_oid_1_2_4")?;
+            assert!(renamed.contains("plrust_fn_oid_1_2_4"));
+            assert!(!renamed.contains("plrust_fn_oid_1_2_3"));
+            Ok(())
+        }
+        wrapped().unwrap()
+    }
+
+    #[pg_test]
+    fn dependency_build_time_code_and_lints() {
+        fn wrapped() -> eyre::Result<()> {
+            // what `cargo metadata` says of a function depending on `serde` with its `derive`
+            // feature, with `proc-macro2` also needed by `plrust-trusted-pgx`, `quote` only needed by
+            // the proc-macro, and `autocfg` only by the build script
+            let metadata = serde_json::json!({
+                "packages": [
+                    { "id": "root", "name": "plrust_fn_oid_1_2_3", "version": "0.0.0", "targets": [{ "name": "plrust_fn_oid_1_2_3", "kind": ["cdylib"] }] },
+                    { "id": "pgx", "name": "plrust-trusted-pgx", "version": "1.0.0", "targets": [{ "name": "pgx", "kind": ["lib"] }] },
+                    { "id": "pm2", "name": "proc-macro2", "version": "1.0.56", "targets": [{ "name": "proc_macro2", "kind": ["lib"] }, { "name": "build-script-build", "kind": ["custom-build"] }] },
+                    { "id": "serde", "name": "serde", "version": "1.0.160", "targets": [{ "name": "serde", "kind": ["lib"] }, { "name": "build-script-build", "kind": ["custom-build"] }] },
+                    { "id": "derive", "name": "serde_derive", "version": "1.0.160", "targets": [{ "name": "serde_derive", "kind": ["proc-macro"] }] },
+                    { "id": "quote", "name": "quote", "version": "1.0.26", "targets": [{ "name": "quote", "kind": ["lib"] }] },
+                    { "id": "autocfg", "name": "autocfg", "version": "1.1.0", "targets": [{ "name": "autocfg", "kind": ["lib"] }] }
+                ],
+                "resolve": {
+                    "root": "root",
+                    "nodes": [
+                        { "id": "root", "dependencies": ["pgx", "serde"], "deps": [{ "pkg": "pgx", "dep_kinds": [{ "kind": null }] }, { "pkg": "serde", "dep_kinds": [{ "kind": null }] }] },
+                        { "id": "pgx", "dependencies": ["pm2"], "deps": [{ "pkg": "pm2", "dep_kinds": [{ "kind": null }] }] },
+                        { "id": "pm2", "dependencies": [] },
+                        { "id": "serde", "dependencies": ["autocfg", "derive"], "deps": [{ "pkg": "autocfg", "dep_kinds": [{ "kind": "build" }] }, { "pkg": "derive", "dep_kinds": [{ "kind": null }] }], "features": ["default", "derive", "serde_derive", "std"] },
+                        { "id": "derive", "dependencies": ["pm2", "quote"], "deps": [{ "pkg": "pm2", "dep_kinds": [{ "kind": null }] }, { "pkg": "quote", "dep_kinds": [{ "kind": null }] }] },
+                        { "id": "quote", "dependencies": [] },
+                        { "id": "autocfg", "dependencies": [] }
+                    ]
+                }
+            })
+            .to_string();
+
+            let check = |allowed: &str| -> eyre::Result<()> {
+                dependencies::check_resolved_dependencies(&metadata, &toml::from_str(allowed)?)
+            };
+
+            let err = check(
+                r#"
+                serde = "1.0.160"
+                serde_derive = "1.0.160"
+                "#,
+            )
+            .unwrap_err()
+            .to_string();
+            assert!(err.contains("`serde 1.0.160` has a build script"), "{err}");
+            assert!(
+                err.contains("`serde_derive 1.0.160` is a proc-macro"),
+                "{err}"
+            );
+            assert!(!err.contains("proc-macro2"), "{err}");
+
+            check(
+                r#"
+                serde = { version = "1.0.160", build_script_ok = true }
+                serde_derive = { version = "1.0.160", proc_macro_ok = true }
+                "#,
+            )?;
+
+            // the flag is for the version that was audited
+            let err = check(
+                r#"
+                serde = [{ version = "1.0.159", build_script_ok = true }, "1.0.160"]
+                serde_derive = { version = "1.0.160", proc_macro_ok = true }
+                "#,
+            )
+            .unwrap_err()
+            .to_string();
+            assert!(err.contains("`serde 1.0.160` has a build script"), "{err}");
+
+            // `std` is one of `serde`'s default features, which the user didn't have to ask for
+            let err = check(
+                r#"
+                serde = { version = "1.0.160", forbidden-features = ["std"], build_script_ok = true }
+                serde_derive = { version = "1.0.160", proc_macro_ok = true }
+                "#,
+            )
+            .unwrap_err()
+            .to_string();
+            assert!(
+                err.contains("`serde 1.0.160` feature `std` is forbidden"),
+                "{err}"
+            );
+
+            // only `serde` is a library compiled for the target that `pgx` doesn't need
+            let lints = dependencies::DependencyLints::new(&metadata, None)?;
+            assert_eq!(vec!["serde 1.0.160".to_string()], lints.linted);
+            assert_eq!(
+                Some("--plrustc-lint-dependencies=serde@1.0.160".to_string()),
+                lints.rustflag()
+            );
+            let allowed = toml::from_str(r#"serde = { version = "1.0.160", audited = true }"#)?;
+            let lints = dependencies::DependencyLints::new(&metadata, Some(&allowed))?;
+            assert_eq!(vec!["serde 1.0.160".to_string()], lints.audited);
+            assert_eq!(None, lints.rustflag());
+            Ok(())
+        }
+        wrapped().unwrap()
+    }
+
+    #[pg_test]
+    fn advisory_db_findings() {
+        fn wrapped() -> eyre::Result<()> {
+            let db_dir = tempdir::TempDir::new("plrust-advisory-db")?;
+            let crate_dir = db_dir.path().join("crates").join("smallvec");
+            std::fs::create_dir_all(&crate_dir)?;
+            std::fs::write(
+                crate_dir.join("RUSTSEC-2021-0003.md"),
+                r#"```toml
+[advisory]
+id = "RUSTSEC-2021-0003"
+package = "smallvec"
+date = 2021-01-08
+
+[versions]
+patched = [">= 0.6.14, < 1.0.0", ">= 1.6.1"]
+unaffected = ["< 0.6.3"]
+```
+
+# Buffer overflow in SmallVec::insert_many
+"#,
+            )?;
+            std::fs::write(
+                crate_dir.join("RUSTSEC-2099-0001.md"),
+                r#"```toml
+[advisory]
+id = "RUSTSEC-2099-0001"
+package = "smallvec"
+date = 2099-01-01
+withdrawn = 2099-01-02
+
+[versions]
+patched = []
+```
+
+# Not actually a problem
+"#,
+            )?;
+            // skipped with a warning, rather than keeping the others from being checked
+            std::fs::write(
+                crate_dir.join("RUSTSEC-2099-0002.md"),
+                "```toml\n[advisory\n```\n",
+            )?;
+            let db = advisories::open(db_dir.path().to_str().unwrap());
+
+            let findings = db.findings("smallvec", &semver::Version::new(1, 6, 0))?;
+            assert_eq!(1, findings.len());
+            assert_eq!(
+                "`smallvec 1.6.0` has advisory RUSTSEC-2021-0003: Buffer overflow in SmallVec::insert_many",
+                findings[0].to_string()
+            );
+            assert!(db
+                .findings("smallvec", &semver::Version::new(1, 6, 1))?
+                .is_empty());
+            assert!(db
+                .findings("smallvec", &semver::Version::new(0, 6, 2))?
+                .is_empty());
+            assert!(db
+                .findings("serde", &semver::Version::new(1, 0, 0))?
+                .is_empty());
+
+            let dependencies: toml::value::Table = toml::from_str(
+                r#"
+                smallvec = "=1.6.0"
+                serde = { version = "=1.0.160", features = ["derive"] }
+                rand = "0.8"
+                "#,
+            )?;
+            let mut pinned = advisories::pinned_dependencies(&dependencies);
+            pinned.sort();
+            assert_eq!(
+                vec![
+                    ("serde".to_string(), semver::Version::new(1, 0, 160)),
+                    ("smallvec".to_string(), semver::Version::new(1, 6, 0)),
+                ],
+                pinned
+            );
+            Ok(())
+        }
+        wrapped().unwrap()
+    }
+
+    #[pg_test]
+    fn crate_sources() {
+        fn wrapped() -> eyre::Result<()> {
+            let manifest = |dependencies: &str| -> eyre::Result<toml::value::Table> {
+                Ok(toml::from_str(&format!("[dependencies]\n{dependencies}"))?)
+            };
+            let wanted = manifest(
+                r#"
+                serde = "=1.0.150"
+                pgx = { path = "/src/plrust-trusted-pgx", package = "plrust-trusted-pgx" }
+                "#,
+            )?;
+            let unavailable = manifest(
+                r#"
+                serde = "=1.0.155"
+                serde-value = "0.6"
+                rand = "0.8"
+                "#,
+            )?;
+            let invalid = manifest(r#""ñañ" = "1""#)?;
+
+            // `cargo vendor` suffixes a crate's directory with its version when it has several
+            let vendor_dir = tempdir::TempDir::new("plrust-vendor")?;
+            for (dir, name, version) in [
+                ("serde", "serde", "1.0.160"),
+                ("serde-1.0.150", "serde", "1.0.150"),
+                ("serde-value", "serde-value", "0.7.0"),
+            ] {
+                let crate_dir = vendor_dir.path().join(dir);
+                std::fs::create_dir_all(&crate_dir)?;
+                std::fs::write(
+                    crate_dir.join("Cargo.toml"),
+                    format!("[package]\nname = \"{name}\"\nversion = \"{version}\"\n"),
+                )?;
+            }
+            let vendored = vendor_dir.path().to_str().unwrap().parse::<CrateSource>()?;
+            assert_eq!(
+                CrateSource::Vendored(vendor_dir.path().to_path_buf()),
+                vendored
+            );
+            vendored.check_available(&wanted)?;
+            let err = vendored
+                .check_available(&unavailable)
+                .unwrap_err()
+                .to_string();
+            assert!(
+                err.contains(r#"`serde = "=1.0.155"` is not available"#),
+                "{err}"
+            );
+            assert!(
+                err.contains(r#"`serde-value = "0.6"` is not available"#),
+                "{err}"
+            );
+            assert!(err.contains("`rand` is not available"), "{err}");
+            let err = vendored.check_available(&invalid).unwrap_err().to_string();
+            assert!(err.contains("`ñañ` is not a valid crate name"), "{err}");
+
+            let config = vendored.cargo_config();
+            assert_eq!(
+                Some("plrust-crate-source"),
+                config["source"]["crates-io"]["replace-with"].as_str()
+            );
+            assert_eq!(
+                vendor_dir.path().to_str(),
+                config["source"]["plrust-crate-source"]["directory"].as_str()
+            );
+            assert_eq!(Some(true), config["net"]["offline"].as_bool());
+
+            // a local registry's index is laid out like crates.io's, and yanked versions don't count
+            let registry_dir = tempdir::TempDir::new("plrust-local-registry")?;
+            let index = registry_dir.path().join("index");
+            std::fs::create_dir_all(index.join("se").join("rd"))?;
+            std::fs::write(
+                index.join("se").join("rd").join("serde"),
+                concat!(
+                    r#"{"name":"serde","vers":"1.0.150","yanked":false}"#,
+                    "\n",
+                    r#"{"name":"serde","vers":"1.0.155","yanked":true}"#,
+                    "\n",
+                ),
+            )?;
+            std::fs::create_dir_all(index.join("3").join("r"))?;
+            std::fs::write(
+                index.join("3").join("r").join("rsa"),
+                r#"{"name":"rsa","vers":"0.9.2","yanked":false}"#,
+            )?;
+            let local = registry_dir
+                .path()
+                .to_str()
+                .unwrap()
+                .parse::<CrateSource>()?;
+            assert_eq!(
+                CrateSource::LocalRegistry(registry_dir.path().to_path_buf()),
+                local
+            );
+            local.check_available(&wanted)?;
+            local.check_available(&manifest(r#"rsa = "0.9""#)?)?;
+            let err = local.check_available(&unavailable).unwrap_err().to_string();
+            assert!(
+                err.contains(r#"`serde = "=1.0.155"` is not available"#),
+                "{err}"
+            );
+            let err = local.check_available(&invalid).unwrap_err().to_string();
+            assert!(err.contains("`ñañ` is not a valid crate name"), "{err}");
+            assert_eq!(
+                registry_dir.path().to_str(),
+                local.cargo_config()["source"]["plrust-crate-source"]["local-registry"].as_str()
+            );
+
+            // we can't know what a registry has without asking it
+            let index = "sparse+https://mirror.internal/index/";
+            let registry = index.parse::<CrateSource>()?;
+            assert_eq!(CrateSource::Registry(index.to_string()), registry);
+            registry.check_available(&unavailable)?;
+            let config = registry.cargo_config();
+            assert_eq!(
+                Some(index),
+                config["source"]["plrust-crate-source"]["registry"].as_str()
+            );
+            assert!(!config.contains_key("net"));
+
+            assert!("vendor".parse::<CrateSource>().is_err());
+            assert!("/no/such/plrust/crate/source"
+                .parse::<CrateSource>()
+                .is_err());
+            Ok(())
+        }
+        wrapped().unwrap()
+    }
 }
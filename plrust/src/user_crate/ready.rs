@@ -102,7 +102,8 @@ impl FnReady {
             ((), library)
         };
 
-        let symbol_name = symbol.unwrap_or_else(|| crate::plrust::symbol_name(db_oid, fn_oid));
+        let symbol_name =
+            symbol.unwrap_or_else(|| crate::plrust::legacy_symbol_name(db_oid, fn_oid));
         let symbol_name = symbol_name + "_wrapper"; // + "_wrapper" b/c pgx' `#[pg_extern]` adds that
 
         tracing::trace!("Getting symbol `{symbol_name}`");
@@ -0,0 +1,233 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Where user crates get their dependencies, when it isn't crates.io.
+//!
+//! `plrust.crate_source` replaces crates.io with a `cargo vendor` directory, a `cargo local-registry`
+//! directory, or another registry's index, by way of a `.cargo/config.toml` in each generated
+//! crate's directory.  Builds from a directory can't reach the network at all.
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use eyre::{eyre, WrapErr};
+
+use crate::user_crate::dependencies::check_crate_name;
+
+/// The name of the source that replaces crates.io in the generated cargo config
+const REPLACEMENT_NAME: &str = "plrust-crate-source";
+
+/// A replacement for crates.io
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CrateSource {
+    /// a directory made by `cargo vendor`
+    Vendored(PathBuf),
+    /// a directory made by `cargo local-registry`, which has an `index` and the `.crate` files
+    LocalRegistry(PathBuf),
+    /// the index of a registry mirror, such as `sparse+https://mirror.internal/index/`
+    Registry(String),
+}
+
+impl FromStr for CrateSource {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.contains("://") {
+            return Ok(CrateSource::Registry(s.to_string()));
+        }
+
+        let path = Path::new(s);
+        if !path.is_absolute() {
+            eyre::bail!(
+                "`plrust.crate_source` must be an absolute path or a registry index url, not `{s}`"
+            );
+        }
+        if !path.is_dir() {
+            eyre::bail!("`plrust.crate_source` directory `{s}` does not exist");
+        }
+        match path.join("index").is_dir() {
+            true => Ok(CrateSource::LocalRegistry(path.to_path_buf())),
+            false => Ok(CrateSource::Vendored(path.to_path_buf())),
+        }
+    }
+}
+
+impl std::fmt::Display for CrateSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CrateSource::Vendored(path) => write!(f, "vendored crates at `{}`", path.display()),
+            CrateSource::LocalRegistry(path) => {
+                write!(f, "local registry at `{}`", path.display())
+            }
+            CrateSource::Registry(index) => write!(f, "registry at `{index}`"),
+        }
+    }
+}
+
+impl CrateSource {
+    /// The `.cargo/config.toml` that has `cargo` use this instead of crates.io
+    pub(crate) fn cargo_config(&self) -> toml::value::Table {
+        let (kind, location) = match self {
+            CrateSource::Vendored(path) => ("directory", path.display().to_string()),
+            CrateSource::LocalRegistry(path) => ("local-registry", path.display().to_string()),
+            CrateSource::Registry(index) => ("registry", index.clone()),
+        };
+
+        let mut crates_io = toml::value::Table::new();
+        crates_io.insert("replace-with".into(), REPLACEMENT_NAME.into());
+        let mut replacement = toml::value::Table::new();
+        replacement.insert(kind.into(), location.into());
+
+        let mut source = toml::value::Table::new();
+        source.insert("crates-io".into(), crates_io.into());
+        source.insert(REPLACEMENT_NAME.into(), replacement.into());
+
+        let mut config = toml::value::Table::new();
+        config.insert("source".into(), source.into());
+        if !matches!(self, CrateSource::Registry(_)) {
+            let mut net = toml::value::Table::new();
+            net.insert("offline".into(), true.into());
+            config.insert("net".into(), net.into());
+        }
+        config
+    }
+
+    /// Write the cargo config into the crate directory `crate_dir`
+    pub(crate) fn configure(&self, crate_dir: &Path) -> eyre::Result<()> {
+        let config_dir = crate_dir.join(".cargo");
+        std::fs::create_dir_all(&config_dir).wrap_err("Creating the crate's `.cargo` directory")?;
+        std::fs::write(
+            config_dir.join("config.toml"),
+            toml::to_string(&self.cargo_config()).wrap_err("Stringifying the cargo config")?,
+        )
+        .wrap_err("Writing the crate's `.cargo/config.toml`")
+    }
+
+    /// Make sure each crates.io dependency in the generated `Cargo.toml`, `manifest`, is available
+    /// from a directory, so a missing crate is reported by name rather than by a failed `cargo` run.
+    /// We can't know what a registry has without asking it, so that's left to `cargo`
+    pub(crate) fn check_available(&self, manifest: &toml::value::Table) -> eyre::Result<()> {
+        let dependencies = manifest
+            .get("dependencies")
+            .and_then(toml::Value::as_table)
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, dependency)| match dependency {
+                toml::Value::String(version) => Some((name.as_str(), version.as_str())),
+                toml::Value::Table(table) if !table.contains_key("path") => Some((
+                    table
+                        .get("package")
+                        .and_then(toml::Value::as_str)
+                        .unwrap_or(name),
+                    table.get("version").and_then(toml::Value::as_str)?,
+                )),
+                _ => None,
+            });
+
+        let mut missing = Vec::new();
+        for (name, version) in dependencies {
+            let available = match self {
+                CrateSource::Vendored(path) => vendored_versions(path, name)?,
+                CrateSource::LocalRegistry(path) => local_registry_versions(path, name)?,
+                CrateSource::Registry(_) => return Ok(()),
+            };
+            let req = semver::VersionReq::parse(version).map_err(|e| {
+                eyre!("`{name}` has an invalid version requirement `{version}`: {e}")
+            })?;
+            if !available.iter().any(|available| req.matches(available)) {
+                let available = available
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+                missing.push(match available.is_empty() {
+                    true => format!("`{name}` is not available"),
+                    false => format!(
+                        "`{name} = \"{version}\"` is not available.  Available versions are {available:?}"
+                    ),
+                });
+            }
+        }
+
+        if !missing.is_empty() {
+            eyre::bail!(
+                "The following dependencies are not in the {self}:\n    {}",
+                missing.join("\n    ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The versions of `name` in a `cargo vendor` directory, which puts each crate in a directory named
+/// for it, suffixed with its version when there's more than one
+fn vendored_versions(vendor_dir: &Path, name: &str) -> eyre::Result<Vec<semver::Version>> {
+    check_crate_name(name)?;
+    let mut versions = Vec::new();
+    let entries = std::fs::read_dir(vendor_dir)
+        .wrap_err_with(|| format!("Reading vendored crates from `{}`", vendor_dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if file_name != name && !file_name.starts_with(&format!("{name}-")) {
+            continue;
+        }
+
+        let Ok(manifest) = std::fs::read_to_string(entry.path().join("Cargo.toml")) else {
+            continue;
+        };
+        let Ok(manifest) = toml::from_str::<toml::value::Table>(&manifest) else {
+            continue;
+        };
+        let package = manifest.get("package").and_then(toml::Value::as_table);
+        if package
+            .and_then(|p| p.get("name"))
+            .and_then(toml::Value::as_str)
+            != Some(name)
+        {
+            // `foo-bar` when looking for `foo`
+            continue;
+        }
+        if let Some(version) = package
+            .and_then(|p| p.get("version"))
+            .and_then(toml::Value::as_str)
+            .and_then(|version| semver::Version::parse(version).ok())
+        {
+            versions.push(version);
+        }
+    }
+    Ok(versions)
+}
+
+/// The versions of `name` in a `cargo local-registry` directory, whose index is laid out like
+/// crates.io's
+fn local_registry_versions(registry_dir: &Path, name: &str) -> eyre::Result<Vec<semver::Version>> {
+    // which also makes it ASCII, so we can slice it up by bytes below
+    check_crate_name(name)?;
+    let lower = name.to_ascii_lowercase();
+    let index_path = match lower.len() {
+        1 => PathBuf::from("1").join(&lower),
+        2 => PathBuf::from("2").join(&lower),
+        3 => PathBuf::from("3").join(&lower[..1]).join(&lower),
+        _ => PathBuf::from(&lower[..2]).join(&lower[2..4]).join(&lower),
+    };
+    let Ok(index) = std::fs::read_to_string(registry_dir.join("index").join(index_path)) else {
+        return Ok(vec![]);
+    };
+
+    Ok(index
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|entry| entry["yanked"] != true)
+        .filter_map(|entry| {
+            entry["vers"]
+                .as_str()
+                .and_then(|v| semver::Version::parse(v).ok())
+        })
+        .collect())
+}
@@ -1,8 +1,10 @@
 use pgx::pg_sys;
 
 use crate::error::PlRustError;
+use crate::signing::{self, Claims};
+use crate::target::CompilationTarget;
 use crate::user_crate::lint::{required_lints, LintSet};
-use crate::user_crate::{CrateState, FnReady};
+use crate::user_crate::{BuildInputs, CrateState, FnReady};
 
 pub(crate) struct FnValidate {
     generation_number: u64,
@@ -20,10 +22,26 @@ impl FnValidate {
         generation_number: u64,
         db_oid: pg_sys::Oid,
         fn_oid: pg_sys::Oid,
+        target: CompilationTarget,
         symbol: Option<String>,
         shared_object: Vec<u8>,
         lints: LintSet,
+        claims: Claims,
+        build_inputs: Option<BuildInputs>,
+        signature: Option<String>,
     ) -> eyre::Result<Self> {
+        // the lints, and everything else, we're about to trust are only as good as the artifact's
+        // signature, so check that first
+        signing::verify(
+            &target,
+            symbol.as_deref(),
+            &lints,
+            &claims,
+            build_inputs.as_ref(),
+            &shared_object,
+            signature.as_deref(),
+        )?;
+
         // if the set of lints we're validating don't include every required lint, we raise an error
         // with the missing lints
         let missing_lints = required_lints()
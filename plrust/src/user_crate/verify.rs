@@ -29,11 +29,17 @@ use std::{
     process::Output,
 };
 
+use color_eyre::{Section, SectionExt};
 use eyre::{eyre, WrapErr};
 use pgx::pg_sys;
 
+use crate::gucs;
+use crate::signing::Claims;
+use crate::user_crate::advisories;
 use crate::user_crate::cargo::cargo;
+use crate::user_crate::dependencies::{self, DependencyLints};
 use crate::user_crate::lint::LintSet;
+use crate::user_crate::lockfile::{self, DependencyPinning, Lockfile};
 use crate::user_crate::{CrateState, FnBuild, PlRustError};
 
 /// Available and ready-to-validate PL/Rust crate
@@ -48,6 +54,12 @@ pub(crate) struct FnVerify {
     crate_name: String,
     crate_dir: PathBuf,
     lints: LintSet,
+    claims: Claims,
+    /// the `Cargo.lock` from the function's previous compilation, if we have it
+    previous_lockfile: Option<String>,
+    /// the parts of `plrust.allowed_dependencies` that apply to the function, if there's an allow-list
+    allow_list: Option<toml::value::Table>,
+    pinning: DependencyPinning,
 }
 
 impl CrateState for FnVerify {}
@@ -61,6 +73,10 @@ impl FnVerify {
         crate_name: String,
         crate_dir: PathBuf,
         lints: LintSet,
+        claims: Claims,
+        previous_lockfile: Option<String>,
+        allow_list: Option<toml::value::Table>,
+        pinning: DependencyPinning,
     ) -> Self {
         Self {
             generation_number,
@@ -69,6 +85,10 @@ impl FnVerify {
             crate_name,
             crate_dir,
             lints,
+            claims,
+            previous_lockfile,
+            allow_list,
+            pinning,
         }
     }
 
@@ -93,6 +113,24 @@ impl FnVerify {
         let output = command.output().wrap_err("verification failure")?;
 
         if output.status.success() {
+            self.lock_dependencies(cargo_target_dir)?;
+            let lint_dependencies = gucs::dependency_lints();
+            let mut dependency_lints = None;
+            if self.allow_list.is_some() || lint_dependencies {
+                let metadata = self.cargo(
+                    cargo_target_dir,
+                    &["metadata", "--format-version", "1", "--locked", "--offline"],
+                )?;
+                let metadata = String::from_utf8_lossy(&metadata);
+                if let Some(allow_list) = &self.allow_list {
+                    dependencies::check_resolved_dependencies(&metadata, allow_list)?;
+                }
+                if lint_dependencies {
+                    dependency_lints =
+                        Some(DependencyLints::new(&metadata, self.allow_list.as_ref())?);
+                }
+            }
+
             Ok((
                 FnBuild::new(
                     self.generation_number,
@@ -101,6 +139,11 @@ impl FnVerify {
                     self.crate_name,
                     self.crate_dir,
                     self.lints,
+                    self.claims,
+                    dependency_lints,
+                    self.allow_list
+                        .as_ref()
+                        .map(dependencies::allow_list_sha256),
                 ),
                 output,
             ))
@@ -109,6 +152,104 @@ impl FnVerify {
         }
     }
 
+    /// Resolve the crate's dependencies into its `Cargo.lock`, starting from the previous
+    /// compilation's, and download them, which has `cargo` check them against their checksums.
+    /// With `plrust.dependency_pinning = transitive`, crates that aren't locked to an allowed version
+    /// are updated to one
+    #[tracing::instrument(level = "debug", skip_all)]
+    fn lock_dependencies(&self, cargo_target_dir: &Path) -> eyre::Result<()> {
+        let previous = self
+            .previous_lockfile
+            .as_deref()
+            .map(Lockfile::parse)
+            .transpose()?;
+        let allowed = match self.pinning {
+            DependencyPinning::Direct => None,
+            DependencyPinning::Transitive => self
+                .allow_list
+                .as_ref()
+                .map(dependencies::parse_allowed_crates)
+                .transpose()?,
+        };
+
+        self.cargo(cargo_target_dir, &["fetch"])?;
+        let mut lockfile = self.read_lockfile()?;
+        if let Some(allowed) = &allowed {
+            let unpinned = lockfile.unpinned(allowed);
+            if unpinned.iter().any(|unpinned| unpinned.pin_to.is_none()) {
+                return Err(lockfile::describe_unpinned(&unpinned));
+            }
+            for unpinned in &unpinned {
+                let spec = format!("{}@{}", unpinned.name, unpinned.version);
+                let precise = unpinned.pin_to.as_ref().unwrap().to_string(); // checked above
+                self.cargo(
+                    cargo_target_dir,
+                    &["update", "-p", &spec, "--precise", &precise],
+                )?;
+            }
+            if !unpinned.is_empty() {
+                self.cargo(cargo_target_dir, &["fetch"])?;
+                lockfile = self.read_lockfile()?;
+                let unpinned = lockfile.unpinned(allowed);
+                if !unpinned.is_empty() {
+                    return Err(lockfile::describe_unpinned(&unpinned));
+                }
+            }
+        }
+
+        lockfile.verify_checksums(previous.as_ref())?;
+        self.check_advisories(&lockfile)
+    }
+
+    /// Check the crates the user's dependencies were resolved to against `plrust.advisory_db`,
+    /// other than the dependencies already checked because they're pinned to an exact version
+    fn check_advisories(&self, lockfile: &Lockfile) -> eyre::Result<()> {
+        let cargo_toml: toml::value::Table = toml::from_str(
+            &std::fs::read_to_string(self.crate_dir.join("Cargo.toml"))
+                .wrap_err("Reading generated `Cargo.toml`")?,
+        )
+        .wrap_err("Parsing generated `Cargo.toml`")?;
+        let pinned = cargo_toml
+            .get("dependencies")
+            .and_then(toml::Value::as_table)
+            .map(advisories::pinned_dependencies)
+            .unwrap_or_default();
+
+        let locked = lockfile
+            .locked_dependencies()
+            .into_iter()
+            .filter_map(|(name, version)| Some((name, semver::Version::parse(version).ok()?)))
+            .filter(|(name, version)| {
+                !pinned.iter().any(|(pinned_name, pinned_version)| {
+                    pinned_name == name && pinned_version == version
+                })
+            })
+            .collect::<Vec<_>>();
+        advisories::check_dependencies(locked.iter().map(|(name, version)| (*name, version)))
+    }
+
+    fn read_lockfile(&self) -> eyre::Result<Lockfile> {
+        let contents = std::fs::read_to_string(self.crate_dir.join("Cargo.lock"))
+            .wrap_err("Reading generated `Cargo.lock`")?;
+        Lockfile::parse(&contents)
+    }
+
+    /// Run `cargo` with `args` in the crate directory, returning what it wrote to stdout
+    fn cargo(&self, cargo_target_dir: &Path, args: &[&str]) -> eyre::Result<Vec<u8>> {
+        let mut command = cargo(cargo_target_dir, None)?;
+        command.current_dir(&self.crate_dir);
+        command.args(args);
+
+        let output = command.output().wrap_err("`cargo` execution failure")?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+            return Err(eyre!(PlRustError::CargoBuildFail)
+                .wrap_err(format!("`cargo {}` failed", args.join(" ")))
+                .section(stderr.header("`cargo` stderr:")));
+        }
+        Ok(output.stdout)
+    }
+
     // for #[tracing] purposes
     pub(crate) fn fn_oid(&self) -> pg_sys::Oid {
         self.fn_oid
@@ -0,0 +1,270 @@
+/*
+Copyright 2021-2023 Technology Concepts & Design, Inc. <support@tcdi.com>
+
+All rights reserved.
+
+Use of this source code is governed by the PostgreSQL license that can be found in the LICENSE.md file.
+*/
+
+//! Background workers PL/Rust runs at most one of, of each kind, per database, coordinated through
+//! shared memory.
+//!
+//! A backend that will have work for a worker once its transaction commits [`hold`]s it, which
+//! launches the worker unless it's already running, and keeps it from exiting until the transaction
+//! ends.  When the transaction commits the worker is told there's new work.  A worker only exits
+//! once it has nothing left to do, nothing holds it, and no new work has been announced, which it
+//! decides under the same lock the others are made under, so work is never left behind.
+//!
+//! Work that's ready right away, rather than once a transaction commits, is announced with
+//! [`notify`] instead.
+use std::ffi::CStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use pgx::bgworkers::BackgroundWorkerBuilder;
+use pgx::prelude::*;
+use pgx::{pg_shmem_init, PGXSharedMemory, PgLwLock};
+
+/// How many workers, of every kind across every database, we can coordinate
+const MAX_WORKERS: usize = 64;
+
+/// A worker that hasn't attached this long after it was launched is assumed to have never started,
+/// for example because `max_worker_processes` was exhausted, and is launched again
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+static WORKERS: PgLwLock<heapless::FnvIndexMap<WorkerKey, WorkerSlot, MAX_WORKERS>> =
+    PgLwLock::new();
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum WorkerKind {
+    /// compiles functions restored with `plrust.restore_policy = background`
+    Restore = 0,
+    /// moves spooled failed compilations into `plrust.compile_history`
+    History = 1,
+}
+
+impl WorkerKind {
+    fn name(self) -> &'static str {
+        match self {
+            WorkerKind::Restore => "plrust restore compiler",
+            WorkerKind::History => "plrust compile history writer",
+        }
+    }
+
+    fn function(self) -> &'static str {
+        match self {
+            WorkerKind::Restore => "plrust_restore_worker_main",
+            WorkerKind::History => "plrust_history_worker_main",
+        }
+    }
+
+    /// The kind passed to [`detach`], see [`attach`]
+    fn from_datum(datum: pg_sys::Datum) -> Self {
+        match datum.value() {
+            0 => WorkerKind::Restore,
+            1 => WorkerKind::History,
+            other => panic!("unrecognized PL/Rust background worker kind {other}"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct WorkerKey {
+    kind: WorkerKind,
+    db_oid: u32,
+}
+
+impl WorkerKey {
+    fn new(kind: WorkerKind) -> Self {
+        // SAFETY: Postgres globally sets this to `const InvalidOid`, so is always read-safe,
+        // then writes it only during initialization, so we should not be racing anyone.
+        let db_oid = unsafe { pg_sys::MyDatabaseId };
+        WorkerKey {
+            kind,
+            db_oid: db_oid.as_u32(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct WorkerSlot {
+    /// a worker has been launched and hasn't exited
+    running: bool,
+    /// the worker's process id, once it has attached
+    pid: i32,
+    /// seconds since the epoch that the worker was launched
+    launched_at: u64,
+    /// transactions that will have work for the worker once they commit
+    holds: u32,
+    /// work was announced that the worker hasn't yet looked for
+    pending: bool,
+}
+
+unsafe impl PGXSharedMemory for WorkerSlot {}
+
+pub(crate) fn init() {
+    pg_shmem_init!(WORKERS);
+}
+
+/// Keep this database's worker of the specified `kind` running until [`release`] is called, most
+/// likely when the current transaction ends, launching it if it isn't already running
+pub(crate) fn hold(kind: WorkerKind) -> eyre::Result<()> {
+    let key = WorkerKey::new(kind);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let launch = {
+        let mut workers = WORKERS.exclusive();
+        if !workers.contains_key(&key) {
+            // make room by forgetting workers that have nothing to do
+            let idle = workers
+                .iter()
+                .filter(|(_, slot)| !slot.running && slot.holds == 0 && !slot.pending)
+                .map(|(key, _)| *key)
+                .collect::<Vec<_>>();
+            for key in idle {
+                workers.remove(&key);
+            }
+        }
+
+        match workers.get_mut(&key) {
+            Some(slot) => {
+                slot.holds += 1;
+                let never_started = slot.running
+                    && slot.pid == 0
+                    && now.saturating_sub(slot.launched_at) > STARTUP_TIMEOUT.as_secs();
+                if slot.running && !never_started {
+                    false
+                } else {
+                    slot.running = true;
+                    slot.pid = 0;
+                    slot.launched_at = now;
+                    true
+                }
+            }
+            None => {
+                let slot = WorkerSlot {
+                    running: true,
+                    launched_at: now,
+                    holds: 1,
+                    ..Default::default()
+                };
+                // if every slot is taken we launch the worker anyway, and it may run alongside
+                // another one for the same database
+                let _ = workers.insert(key, slot);
+                true
+            }
+        }
+    };
+
+    if launch {
+        let dbname = unsafe {
+            // SAFETY:  `get_database_name` returns a palloc'd copy of our database's name, and our
+            // database certainly exists
+            CStr::from_ptr(pg_sys::get_database_name(pg_sys::MyDatabaseId))
+                .to_str()?
+                .to_string()
+        };
+
+        BackgroundWorkerBuilder::new(kind.name())
+            .set_function(kind.function())
+            .set_library("plrust")
+            .set_extra(&dbname)
+            .enable_spi_access()
+            .load_dynamic();
+    }
+    Ok(())
+}
+
+/// Undo a [`hold`] on this database's worker of the specified `kind`.  If the work the hold was
+/// for was `committed`, the worker is told to look for it.
+///
+/// This is called from transaction callbacks, after the transaction has committed, so it must not
+/// raise an ERROR or access the catalogs.
+pub(crate) fn release(kind: WorkerKind, committed: bool) {
+    let key = WorkerKey::new(kind);
+    let mut workers = WORKERS.exclusive();
+    if let Some(slot) = workers.get_mut(&key) {
+        slot.holds = slot.holds.saturating_sub(1);
+        slot.pending |= committed;
+    }
+}
+
+/// Tell this database's worker of the specified `kind` there's new work, launching it if it isn't
+/// already running
+pub(crate) fn notify(kind: WorkerKind) -> eyre::Result<()> {
+    let held = hold(kind);
+    release(kind, true);
+    held
+}
+
+/// Called by a worker of the specified `kind` once it has connected to its database
+pub(crate) fn attach(kind: WorkerKind) {
+    let key = WorkerKey::new(kind);
+    // SAFETY:  Postgres sets `MyProcPid` before starting any process
+    let pid = unsafe { pg_sys::MyProcPid };
+    {
+        let mut workers = WORKERS.exclusive();
+        match workers.get_mut(&key) {
+            Some(slot) => {
+                slot.running = true;
+                slot.pid = pid;
+            }
+            None => {
+                let slot = WorkerSlot {
+                    running: true,
+                    pid,
+                    ..Default::default()
+                };
+                let _ = workers.insert(key, slot);
+            }
+        }
+    }
+
+    unsafe {
+        // SAFETY:  `detach` is a `#[pg_guard]`ed function that lives as long as the process
+        pg_sys::on_shmem_exit(Some(detach), pg_sys::Datum::from(kind as usize));
+    }
+}
+
+/// Has new work been announced to this database's worker of the specified `kind` since it last
+/// asked?
+pub(crate) fn take_pending(kind: WorkerKind) -> bool {
+    let key = WorkerKey::new(kind);
+    let mut workers = WORKERS.exclusive();
+    workers
+        .get_mut(&key)
+        .map_or(false, |slot| std::mem::take(&mut slot.pending))
+}
+
+/// Called by a worker of the specified `kind` that has nothing left to do.  Returns `true` if it
+/// may exit, or `false` if it must keep going because it's held or new work was announced
+pub(crate) fn finish(kind: WorkerKind) -> bool {
+    let key = WorkerKey::new(kind);
+    let mut workers = WORKERS.exclusive();
+    match workers.get_mut(&key) {
+        Some(slot) if slot.holds > 0 || slot.pending => false,
+        Some(slot) => {
+            slot.running = false;
+            slot.pid = 0;
+            true
+        }
+        None => true,
+    }
+}
+
+/// Forgets an exiting worker, however it's exiting, so the next [`hold`] launches another
+#[pg_guard]
+unsafe extern "C" fn detach(_code: i32, arg: pg_sys::Datum) {
+    let key = WorkerKey::new(WorkerKind::from_datum(arg));
+    // SAFETY:  Postgres sets `MyProcPid` before starting any process
+    let pid = unsafe { pg_sys::MyProcPid };
+    let mut workers = WORKERS.exclusive();
+    if let Some(slot) = workers.get_mut(&key) {
+        // a worker that has already finished may have been replaced by another
+        if slot.pid == pid {
+            slot.running = false;
+            slot.pid = 0;
+        }
+    }
+}
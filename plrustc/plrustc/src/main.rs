@@ -23,6 +23,10 @@ use std::path::{Path, PathBuf};
 
 const PLRUSTC_USER_CRATE_NAME: &str = "PLRUSTC_USER_CRATE_NAME";
 const PLRUSTC_USER_CRATE_ALLOWED_SOURCE_PATHS: &str = "PLRUSTC_USER_CRATE_ALLOWED_SOURCE_PATHS";
+// Comma-separated `name@version`s of dependency packages to forbid `plrust_lints` in the libraries
+// of.  Given in `RUSTFLAGS`, rather than the environment, so `cargo` rebuilds dependencies when it
+// changes
+const PLRUSTC_LINT_DEPENDENCIES_ARG: &str = "--plrustc-lint-dependencies";
 
 const PLRUSTC_VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -137,6 +141,17 @@ fn main() {
             args.remove(1);
         }
 
+        if let Some(lint_dependencies) = take_arg(&mut args, PLRUSTC_LINT_DEPENDENCIES_ARG) {
+            if lint_dependencies
+                .split(',')
+                .any(|package| compiling_library_of(&args, package))
+            {
+                // `cargo` caps lints for crates from a registry, which would allow everything
+                take_arg(&mut args, "--cap-lints");
+                args.push("-Fplrust_lints".to_string());
+            }
+        }
+
         run_compiler(
             args,
             &mut PlrustcCallbacks {
@@ -259,6 +274,58 @@ fn arg_value<'a, T: AsRef<str>>(args: &'a [T], find_arg: &str) -> Option<&'a str
     None
 }
 
+/// Is `rustc` compiling the library of `package`, given as `name@version`?  Crates are matched by
+/// the package `cargo` says it's compiling rather than by `--crate-name`, as two versions of the same
+/// crate have libraries of the same name, and only one of them may be audited
+fn compiling_library_of<T: AsRef<str>>(args: &[T], package: &str) -> bool {
+    let compiling = std::env::var("CARGO_PKG_NAME")
+        .ok()
+        .zip(std::env::var("CARGO_PKG_VERSION").ok())
+        .map(|(name, version)| format!("{name}@{version}"));
+    let library = arg_value(args, "--crate-type").map_or(false, |crate_type| {
+        matches!(crate_type, "lib" | "rlib" | "dylib")
+    });
+    library && compiling.as_deref() == Some(package)
+}
+
+/// Remove `find_arg` and its value from `args`, returning the value
+fn take_arg(args: &mut Vec<String>, find_arg: &str) -> Option<String> {
+    let position = args
+        .iter()
+        .position(|arg| arg == find_arg || arg.starts_with(&format!("{find_arg}=")))?;
+    let arg = args.remove(position);
+    match arg.split_once('=') {
+        Some((_, value)) => Some(value.to_string()),
+        None if position < args.len() => Some(args.remove(position)),
+        None => None,
+    }
+}
+
+#[test]
+fn take_arg_forms() {
+    let mut args = ["rustc", "--cap-lints", "allow", "--crate-name=foo", "-O"]
+        .map(String::from)
+        .to_vec();
+    assert_eq!(
+        Some("allow".to_string()),
+        take_arg(&mut args, "--cap-lints")
+    );
+    assert_eq!(Some("foo".to_string()), take_arg(&mut args, "--crate-name"));
+    assert_eq!(None, take_arg(&mut args, "--crate-name"));
+    assert_eq!(vec!["rustc", "-O"], args);
+}
+
+#[test]
+fn compiling_library_of_package() {
+    std::env::set_var("CARGO_PKG_NAME", "rand");
+    std::env::set_var("CARGO_PKG_VERSION", "0.8.5");
+    let library = ["--crate-name", "rand", "--crate-type", "lib"];
+    assert!(compiling_library_of(&library, "rand@0.8.5"));
+    assert!(!compiling_library_of(&library, "rand@0.7.3"));
+    let build_script = ["--crate-name", "build_script_build", "--crate-type", "bin"];
+    assert!(!compiling_library_of(&build_script, "rand@0.8.5"));
+}
+
 struct ErrorHidingFileLoader;
 
 fn replacement_error() -> std::io::Error {